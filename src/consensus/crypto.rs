@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use rand::RngCore;
+use crate::network::recovery::RecoverySchedule;
+
+// Domain separation tag for BLS signatures on votes, of the proof-of-possession ciphersuite.
+// Aggregates are verified with `fast_aggregate_verify`, which is only safe against rogue keys
+// if every BLS key has come with a proof of possession: from the configuration, or in the `NewKeyMsg` announcing it.
+const BLS_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+// Domain separation tag for proofs of possession, a signature of a key over itself
+const BLS_POP_DST: &[u8] = b"BLS_POP_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+// How replicas authenticate their prepare and commit votes.
+// Votes are always sent and verified one by one, each replica counts them as they arrive.
+// Only the commit certificates attached to replies differ: the aggregate is for the client.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AuthScheme {
+    Ed25519, // one signature per vote, certificates carry 2f+1 signatures
+    Bls,     // BLS12-381 signatures, certificates carry a single aggregate signature and a bitmap of the signers
+}
+
+impl std::str::FromStr for AuthScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(AuthScheme::Ed25519),
+            "bls" => Ok(AuthScheme::Bls),
+            _ => Err(format!("unknown authentication scheme '{}', expected 'ed25519' or 'bls'", s)),
+        }
+    }
+}
+
+// Signing key of a replica. Prepare and commit votes are signed with it,
+// so that a set of commit votes can be shown to a client as proof of ordering.
+#[derive(Clone)]
+pub(crate) enum KeyPair {
+    Ed25519(ed25519_dalek::SigningKey),
+    Bls(blst::min_pk::SecretKey),
+}
+
+#[derive(Clone)]
+pub(crate) enum PublicKey {
+    Ed25519(ed25519_dalek::VerifyingKey),
+    Bls(blst::min_pk::PublicKey),
+}
+
+impl PublicKey {
+    pub(crate) fn to_hex(&self) -> String {
+        match self {
+            PublicKey::Ed25519(verifying_key) => hex::encode(verifying_key.to_bytes()),
+            PublicKey::Bls(public_key) => hex::encode(public_key.compress()),
+        }
+    }
+
+    pub(crate) fn verify(&self, bytes: &[u8], signature: &str) -> bool {
+        verify_signature(self, bytes, signature)
+    }
+
+    // Whether `proof` is a proof of possession of this key, see `KeyPair::prove_possession`.
+    // Ed25519 keys are never aggregated and need none.
+    pub(crate) fn verify_possession(&self, proof: &str) -> bool {
+        let PublicKey::Bls(public_key) = self else {
+            return true;
+        };
+        let Ok(proof) = hex::decode(proof) else {
+            return false;
+        };
+        let Ok(proof) = blst::min_pk::Signature::sig_validate(&proof, true) else {
+            return false;
+        };
+        proof.verify(false, &public_key.compress(), BLS_POP_DST, &[], public_key, false) == blst::BLST_ERROR::BLST_SUCCESS
+    }
+
+    // The scheme is told apart by the key length: 32 bytes for ed25519, 48 bytes for BLS.
+    pub(crate) fn from_hex(public_key: &str) -> Option<Self> {
+        let bytes = hex::decode(public_key).ok()?;
+        match bytes.len() {
+            32 => ed25519_dalek::VerifyingKey::from_bytes(&bytes.try_into().ok()?).ok().map(PublicKey::Ed25519),
+            48 => blst::min_pk::PublicKey::key_validate(&bytes).ok().map(PublicKey::Bls),
+            _ => None,
+        }
+    }
+}
+
+impl KeyPair {
+    pub(crate) fn generate(scheme: AuthScheme) -> Self {
+        match scheme {
+            AuthScheme::Ed25519 => KeyPair::Ed25519(ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng)),
+            AuthScheme::Bls => {
+                let mut ikm = [0u8; 32];
+                rand::rngs::OsRng.fill_bytes(&mut ikm);
+                KeyPair::Bls(blst::min_pk::SecretKey::key_gen(&ikm, &[]).expect("32 bytes of key material"))
+            }
+        }
+    }
+
+    // Both schemes use 32-byte secret keys, so the scheme has to be known to read one back.
+    pub(crate) fn from_secret_hex(scheme: AuthScheme, secret_key: &str) -> Option<Self> {
+        let bytes = hex::decode(secret_key.trim()).ok()?;
+        match scheme {
+            AuthScheme::Ed25519 => Some(KeyPair::Ed25519(ed25519_dalek::SigningKey::from_bytes(&bytes.try_into().ok()?))),
+            AuthScheme::Bls => blst::min_pk::SecretKey::from_bytes(&bytes).ok().map(KeyPair::Bls),
+        }
+    }
+
+    pub(crate) fn to_secret_hex(&self) -> String {
+        match self {
+            KeyPair::Ed25519(signing_key) => hex::encode(signing_key.to_bytes()),
+            KeyPair::Bls(secret_key) => hex::encode(secret_key.to_bytes()),
+        }
+    }
+
+    pub(crate) fn scheme(&self) -> AuthScheme {
+        match self {
+            KeyPair::Ed25519(_) => AuthScheme::Ed25519,
+            KeyPair::Bls(_) => AuthScheme::Bls,
+        }
+    }
+
+    pub(crate) fn public_key(&self) -> PublicKey {
+        match self {
+            KeyPair::Ed25519(signing_key) => PublicKey::Ed25519(signing_key.verifying_key()),
+            KeyPair::Bls(secret_key) => PublicKey::Bls(secret_key.sk_to_pk()),
+        }
+    }
+
+    // Hex encoded proof that the holder of the public key also holds its secret key: the signature of the key over itself.
+    // Empty for ed25519 keys, see `PublicKey::verify_possession`.
+    pub(crate) fn prove_possession(&self) -> String {
+        match self {
+            KeyPair::Ed25519(_) => String::new(),
+            KeyPair::Bls(secret_key) => hex::encode(secret_key.sign(&secret_key.sk_to_pk().compress(), BLS_POP_DST, &[]).to_bytes()),
+        }
+    }
+
+    // hex encoded signature over `bytes`
+    pub(crate) fn sign(&self, bytes: &[u8]) -> String {
+        match self {
+            KeyPair::Ed25519(signing_key) => {
+                use ed25519_dalek::Signer;
+                hex::encode(signing_key.sign(bytes).to_bytes())
+            }
+            KeyPair::Bls(secret_key) => hex::encode(secret_key.sign(bytes, BLS_DST, &[]).to_bytes()),
+        }
+    }
+}
+
+// Public keys of all replicas, indexed by node id.
+#[derive(Clone, Default)]
+pub(crate) struct PublicKeyTable {
+    keys: HashMap<u32, PublicKey>,
+    epochs: HashMap<u32, u64>, // key refresh epoch of the current key of each replica
+    // Long-term keys that sign the key refreshes of proactive recovery, they are never refreshed themselves.
+    // BFT-PR keeps them in a secure coprocessor, so that a replica whose current key leaks can still recover.
+    recovery_keys: HashMap<u32, PublicKey>,
+    schedule: Option<RecoverySchedule>, // keys are never refreshed if None
+}
+
+impl PublicKeyTable {
+    pub(crate) fn insert(&mut self, node_id: u32, public_key: PublicKey) {
+        self.keys.insert(node_id, public_key);
+    }
+
+    pub(crate) fn insert_recovery_key(&mut self, node_id: u32, public_key: PublicKey) {
+        self.recovery_keys.insert(node_id, public_key);
+    }
+
+    pub(crate) fn set_recovery_schedule(&mut self, schedule: RecoverySchedule) {
+        self.schedule = Some(schedule);
+    }
+
+    // Replaces the key of `node_id` after a key refresh. Keys of older or equal epochs are ignored,
+    // so a replayed key announcement cannot roll a replica back to a compromised key,
+    // and so are keys of epochs that are not recovery slots of the replica that have started,
+    // so the epoch of a key cannot be raised in advance to shut out the keys of the coming recoveries.
+    pub(crate) fn refresh(&mut self, node_id: u32, epoch: u64, public_key: PublicKey) -> bool {
+        if !self.schedule.is_some_and(|schedule| schedule.is_valid_epoch(node_id, epoch)) {
+            return false;
+        }
+        if self.epochs.get(&node_id).is_some_and(|current| *current >= epoch) {
+            return false;
+        }
+        self.epochs.insert(node_id, epoch);
+        self.keys.insert(node_id, public_key);
+        true
+    }
+
+    // Installs a refreshed key saved before a restart, see `config::KeyStore`
+    pub(crate) fn restore(&mut self, node_id: u32, epoch: u64, public_key: PublicKey) {
+        self.epochs.insert(node_id, epoch);
+        self.keys.insert(node_id, public_key);
+    }
+
+    // (node id, epoch, key) of the keys installed by a key refresh
+    pub(crate) fn refreshed(&self) -> impl Iterator<Item = (u32, u64, &PublicKey)> {
+        self.epochs.iter().map(|(node_id, epoch)| (*node_id, *epoch, &self.keys[node_id]))
+    }
+
+    pub(crate) fn verify(&self, node_id: u32, bytes: &[u8], signature: &str) -> bool {
+        match self.keys.get(&node_id) {
+            Some(public_key) => verify_signature(public_key, bytes, signature),
+            None => false, // unknown replica
+        }
+    }
+
+    // Checks a signature with the long-term recovery key of `node_id`
+    pub(crate) fn verify_recovery(&self, node_id: u32, bytes: &[u8], signature: &str) -> bool {
+        match self.recovery_keys.get(&node_id) {
+            Some(public_key) => verify_signature(public_key, bytes, signature),
+            None => false, // unknown replica, or recovery is disabled
+        }
+    }
+
+    // Checks a BLS aggregate signature of `signers` over the same `bytes`.
+    pub(crate) fn verify_aggregate(&self, signers: &[u32], bytes: &[u8], signature: &str) -> bool {
+        let mut public_keys = Vec::with_capacity(signers.len());
+        for node_id in signers {
+            match self.keys.get(node_id) {
+                Some(PublicKey::Bls(public_key)) => public_keys.push(public_key),
+                _ => return false, // unknown replica, or not a BLS key
+            }
+        }
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        let Ok(signature) = blst::min_pk::Signature::sig_validate(&signature, true) else {
+            return false;
+        };
+        signature.fast_aggregate_verify(false, bytes, BLS_DST, &public_keys) == blst::BLST_ERROR::BLST_SUCCESS
+    }
+}
+
+fn verify_signature(public_key: &PublicKey, bytes: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    match public_key {
+        PublicKey::Ed25519(verifying_key) => {
+            use ed25519_dalek::Verifier;
+            let Ok(signature) = ed25519_dalek::Signature::from_slice(&signature) else {
+                return false;
+            };
+            verifying_key.verify(bytes, &signature).is_ok()
+        }
+        PublicKey::Bls(public_key) => {
+            let Ok(signature) = blst::min_pk::Signature::sig_validate(&signature, true) else {
+                return false;
+            };
+            signature.verify(false, bytes, BLS_DST, &[], public_key, false) == blst::BLST_ERROR::BLST_SUCCESS
+        }
+    }
+}
+
+// Aggregates hex encoded BLS signatures into a single hex encoded signature.
+pub(crate) fn aggregate_signatures(signatures: &[&str]) -> Option<String> {
+    let mut parsed = Vec::with_capacity(signatures.len());
+    for signature in signatures {
+        let bytes = hex::decode(signature).ok()?;
+        parsed.push(blst::min_pk::Signature::from_bytes(&bytes).ok()?);
+    }
+    let refs: Vec<_> = parsed.iter().collect();
+    let aggregate = blst::min_pk::AggregateSignature::aggregate(&refs, true).ok()?;
+    Some(hex::encode(aggregate.to_signature().to_bytes()))
+}
@@ -12,28 +12,203 @@ pub struct RequestMsg { //<REQUEST, o, t, c>
     pub(crate) sequence_id: u32, // 'n', the sequence number
     #[serde(skip)]
     pub(crate) digest: String, // compute&save digest when receiving the request message for performance consideration
+
+    // Where the client receives its replies, for clients that are not in the configuration of the replicas.
+    // Chosen by the client and covered by the digest, so the primary cannot redirect the replies of a request.
+    #[serde(default)]
+    pub(crate) reply_address: Option<String>,
+    // Key and signature of such a client over `canonical_bytes()`, so that only the client can register its reply address,
+    // and only with the key it registered it with can move it (see `ClientAddresses`). Not covered by the digest.
+    #[serde(default)]
+    pub(crate) client_key: Option<String>, // hex encoded ed25519 public key
+    #[serde(default)]
+    pub(crate) client_signature: Option<String>, // hex encoded
 }
 
+// Tag prepended to the canonical encoding of a request, so that the bytes of a
+// request can never be confused with the bytes of another message type.
+const REQUEST_ENCODING_TAG: &[u8] = b"PBFT-REQ";
+const REQUEST_ENCODING_VERSION: u8 = 1;
+const REQUEST_ENCODING_VERSION_WITH_REPLY_ADDRESS: u8 = 2;
+
+impl RequestMsg {
+    // Canonical, deterministic binary encoding of <REQUEST, o, t, c>.
+    // This is what digests (and signatures) are computed over, so it must never depend on
+    // serde field ordering or on fields that are not chosen by the client.
+    // Layout, all integers big-endian:
+    //   "PBFT-REQ" | version: u8 | len(o): u32 | o: utf-8 bytes | t: u64 | c: u32
+    // followed, for requests with a reply address (version 2), by
+    //   len(a): u32 | a: utf-8 bytes
+    // so that the digests of requests without one are the same as before.
+    // `sequence_id` and `digest` are assigned by the replicas and are therefore not covered.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let operation = self.operation.as_bytes();
+        let reply_address = self.reply_address.as_deref().map(str::as_bytes);
+        let mut bytes = Vec::with_capacity(
+            REQUEST_ENCODING_TAG.len() + 1 + 4 + operation.len() + 8 + 4 + reply_address.map_or(0, |a| 4 + a.len()),
+        );
+        bytes.extend_from_slice(REQUEST_ENCODING_TAG);
+        bytes.push(if reply_address.is_some() { REQUEST_ENCODING_VERSION_WITH_REPLY_ADDRESS } else { REQUEST_ENCODING_VERSION });
+        bytes.extend_from_slice(&(operation.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(operation);
+        bytes.extend_from_slice(&self.time_stamp.to_be_bytes());
+        bytes.extend_from_slice(&self.client_id.to_be_bytes());
+        if let Some(reply_address) = reply_address {
+            bytes.extend_from_slice(&(reply_address.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(reply_address);
+        }
+        bytes
+    }
+}
+
+// Paper 4.2: the pre-prepare is signed by the primary, so that backups relaying it (see `overlay`)
+// cannot assign a request to a sequence number in its name.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PrePrepareMsg { //< <PRE-PREPARE, v, n, d>, m >
     pub(crate) view_id: u32, // 'v', the view number
     pub(crate) sequence_id: u32, // 'n', the sequence number
     pub(crate) digest: String, // 'd', the digest of the request message
     pub(crate) request_msg: RequestMsg, // 'm', the request message
+    #[serde(default)]
+    pub(crate) signature: String, // hex encoded signature of the primary over `signing_bytes()`
+}
+
+const PRE_PREPARE_ENCODING_TAG: &[u8] = b"PBFT-PREPREPARE";
+const PRE_PREPARE_ENCODING_VERSION: u8 = 1;
+
+impl PrePrepareMsg {
+    // The digest covers the request, so the signature covers the request as well.
+    // Layout, all integers big-endian:
+    //   "PBFT-PREPREPARE" | version: u8 | v: u32 | n: u32 | len(d): u32 | d: utf-8 bytes
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let digest = self.digest.as_bytes();
+        let mut bytes = Vec::with_capacity(PRE_PREPARE_ENCODING_TAG.len() + 1 + 4 + 4 + 4 + digest.len());
+        bytes.extend_from_slice(PRE_PREPARE_ENCODING_TAG);
+        bytes.push(PRE_PREPARE_ENCODING_VERSION);
+        bytes.extend_from_slice(&self.view_id.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence_id.to_be_bytes());
+        bytes.extend_from_slice(&(digest.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(digest);
+        bytes
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub(crate) struct ReplyMsg {
+pub struct ReplyMsg {
     pub(crate) time_stamp: u64,
     pub(crate) view_id: u32,
     pub(crate) node_id: u32, // 'r', the node(replica) id
     pub(crate) client_id: u32,
     pub(crate) result: String,
+    // Optional proof that the request was committed, see `CommitCertificate`.
+    // It proves the ordering of the request only: `result` is not covered by the votes,
+    // so the client still waits for f+1 matching replies before trusting it.
+    #[serde(default)]
+    pub(crate) certificate: Option<CommitCertificate>,
+    // Requests waiting at the primary for a slot in its pipeline, only set in replies of the primary.
+    #[serde(default)]
+    pub(crate) queue_depth: Option<u32>,
 }
 
+// Sent by the primary instead of ordering a request when its queue of pending requests is full.
+// The client should send the request again after `retry_after_ms`.
+// It is signed, so that no other node can hold back the requests of the client in the name of the primary.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BusyMsg {
+    pub(crate) view_id: u32,
+    pub(crate) node_id: u32, // the primary
+    pub(crate) request_msg: RequestMsg, // the rejected request
+    pub(crate) retry_after_ms: u64,
+    pub(crate) queue_depth: u32,
+    #[serde(default)]
+    pub(crate) signature: String, // hex encoded signature of `node_id` over `signing_bytes()`
+}
 
+const BUSY_ENCODING_TAG: &[u8] = b"PBFT-BUSY";
+const BUSY_ENCODING_VERSION: u8 = 1;
 
+impl BusyMsg {
+    // Layout, all integers big-endian:
+    //   "PBFT-BUSY" | version: u8 | v: u32 | i: u32 | len(m): u32 | m: canonical bytes of the request | retry: u64 | depth: u32
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let request = self.request_msg.canonical_bytes();
+        let mut bytes = Vec::with_capacity(BUSY_ENCODING_TAG.len() + 1 + 4 + 4 + 4 + request.len() + 8 + 4);
+        bytes.extend_from_slice(BUSY_ENCODING_TAG);
+        bytes.push(BUSY_ENCODING_VERSION);
+        bytes.extend_from_slice(&self.view_id.to_be_bytes());
+        bytes.extend_from_slice(&self.node_id.to_be_bytes());
+        bytes.extend_from_slice(&(request.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&request);
+        bytes.extend_from_slice(&self.retry_after_ms.to_be_bytes());
+        bytes.extend_from_slice(&self.queue_depth.to_be_bytes());
+        bytes
+    }
+}
+
+// The 2f+1 signed commit votes of distinct replicas for the same (v, n, d).
+// With BLS authentication the votes are compressed into one aggregate signature
+// and `commits` stays empty.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommitCertificate {
+    pub(crate) view_id: u32,
+    pub(crate) sequence_id: u32,
+    pub(crate) digest: String,
+    #[serde(default)]
+    pub(crate) commits: Vec<VoteMsg>,
+    #[serde(default)]
+    pub(crate) aggregate: Option<AggregateSignature>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AggregateSignature {
+    pub(crate) signers: SignerBitmap, // node ids whose commit votes are aggregated
+    pub(crate) signature: String, // hex encoded BLS aggregate signature over the commit's `signing_bytes()`
+}
+
+// Set of node ids as a bitmap, node i is in the set if bit i % 8 of byte i / 8 is set.
+// It takes n / 8 bytes whatever the number of signers, and no node can be in it twice.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct SignerBitmap(Vec<u8>);
+
+impl SignerBitmap {
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub(crate) fn insert(&mut self, node_id: u32) {
+        let byte = node_id as usize / 8;
+        if self.0.len() <= byte {
+            self.0.resize(byte + 1, 0);
+        }
+        self.0[byte] |= 1 << (node_id % 8);
+    }
+
+    // Node ids in the set, in ascending order
+    pub(crate) fn node_ids(&self) -> Vec<u32> {
+        (0..self.0.len() as u32 * 8)
+            .filter(|node_id| self.0[*node_id as usize / 8] & (1 << (node_id % 8)) != 0)
+            .collect()
+    }
+}
+
+impl FromIterator<u32> for SignerBitmap {
+    fn from_iter<I: IntoIterator<Item = u32>>(node_ids: I) -> Self {
+        let mut signers = Self::default();
+        for node_id in node_ids {
+            signers.insert(node_id);
+        }
+        signers
+    }
+}
+
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MsgType {
     PrepareMsg,
     CommitMsg,
@@ -46,4 +221,217 @@ pub struct VoteMsg {
     pub(crate) digest: String,
     pub(crate) node_id: u32,
     pub(crate) msg_type: MsgType,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub(crate) signature: String, // hex encoded signature of `node_id` over `signing_bytes()`
+}
+
+const VOTE_ENCODING_TAG: &[u8] = b"PBFT-VOTE";
+const VOTE_ENCODING_VERSION: u8 = 1;
+
+impl VoteMsg {
+    // Canonical encoding of <PREPARE/COMMIT, v, n, d> that the vote signature covers.
+    // The signer 'i' is bound by its key and deliberately not encoded: every replica signs the same bytes
+    // for the same vote, which is what allows BLS signatures to be aggregated into one.
+    // Layout, all integers big-endian:
+    //   "PBFT-VOTE" | version: u8 | type: u8 (0 = prepare, 1 = commit) | v: u32 | n: u32
+    //   | len(d): u32 | d: utf-8 bytes
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let digest = self.digest.as_bytes();
+        let mut bytes = Vec::with_capacity(VOTE_ENCODING_TAG.len() + 1 + 1 + 4 + 4 + 4 + digest.len());
+        bytes.extend_from_slice(VOTE_ENCODING_TAG);
+        bytes.push(VOTE_ENCODING_VERSION);
+        bytes.push(match self.msg_type {
+            MsgType::PrepareMsg => 0,
+            MsgType::CommitMsg => 1,
+        });
+        bytes.extend_from_slice(&self.view_id.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence_id.to_be_bytes());
+        bytes.extend_from_slice(&(digest.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(digest);
+        bytes
+    }
+}
+const NEW_KEY_ENCODING_TAG: &[u8] = b"PBFT-NEWKEY";
+const NEW_KEY_ENCODING_VERSION: u8 = 1;
+
+// Announces the fresh key of a replica after proactive recovery.
+// It is signed with the long-term recovery key of the replica, which is never refreshed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewKeyMsg {
+    pub(crate) node_id: u32,
+    pub(crate) epoch: u64, // recovery epoch, strictly increasing per replica
+    pub(crate) public_key: String, // hex encoded
+    pub(crate) signature: String, // hex encoded signature with the recovery key over `signing_bytes()`
+    // Hex encoded proof of possession of a BLS key (see `KeyPair::prove_possession`), empty for ed25519 keys.
+    // Self-authenticating, so it is not covered by the signature.
+    #[serde(default)]
+    pub(crate) proof_of_possession: String,
+}
+
+impl NewKeyMsg {
+    // Layout, all integers big-endian:
+    //   "PBFT-NEWKEY" | version: u8 | i: u32 | epoch: u64 | len(key): u32 | key: utf-8 hex bytes
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let public_key = self.public_key.as_bytes();
+        let mut bytes = Vec::with_capacity(NEW_KEY_ENCODING_TAG.len() + 1 + 4 + 8 + 4 + public_key.len());
+        bytes.extend_from_slice(NEW_KEY_ENCODING_TAG);
+        bytes.push(NEW_KEY_ENCODING_VERSION);
+        bytes.extend_from_slice(&self.node_id.to_be_bytes());
+        bytes.extend_from_slice(&self.epoch.to_be_bytes());
+        bytes.extend_from_slice(&(public_key.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(public_key);
+        bytes
+    }
+}
+
+const HEARTBEAT_ENCODING_TAG: &[u8] = b"PBFT-HEARTBEAT";
+const HEARTBEAT_ENCODING_VERSION: u8 = 1;
+
+// Sent periodically by every replica to the others, so that a silent replica can be told from an idle one.
+// It is signed, so a faulty replica cannot keep a silent primary looking alive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeartbeatMsg {
+    pub(crate) node_id: u32,
+    pub(crate) view_id: u32,
+    pub(crate) time_stamp: u64, // milliseconds since the Unix epoch at the sender, older heartbeats are ignored
+    pub(crate) signature: String, // hex encoded signature of `node_id` over `signing_bytes()`
+}
+
+impl HeartbeatMsg {
+    // Layout, all integers big-endian:
+    //   "PBFT-HEARTBEAT" | version: u8 | i: u32 | v: u32 | t: u64
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEARTBEAT_ENCODING_TAG.len() + 1 + 4 + 4 + 8);
+        bytes.extend_from_slice(HEARTBEAT_ENCODING_TAG);
+        bytes.push(HEARTBEAT_ENCODING_VERSION);
+        bytes.extend_from_slice(&self.node_id.to_be_bytes());
+        bytes.extend_from_slice(&self.view_id.to_be_bytes());
+        bytes.extend_from_slice(&self.time_stamp.to_be_bytes());
+        bytes
+    }
+}
+
+// A request that has been committed by the replica.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommittedEntry {
+    pub(crate) view_id: u32,
+    pub(crate) sequence_id: u32,
+    pub(crate) digest: String,
+}
+
+// Snapshot of the committed log of a replica, exchanged during recovery to verify and repair state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateMsg {
+    pub(crate) node_id: u32,
+    pub(crate) state_digest: String, // digest of `log`, see `utils::compute_state_digest`
+    pub(crate) log: Vec<CommittedEntry>, // sorted by (v, n)
+    #[serde(default)]
+    pub(crate) signature: String, // hex encoded signature of `node_id` over `signing_bytes()`
+}
+
+const STATE_ENCODING_TAG: &[u8] = b"PBFT-STATE";
+const STATE_ENCODING_VERSION: u8 = 1;
+
+impl StateMsg {
+    // The state digest covers the log, so the signature covers the log as well.
+    // Layout, all integers big-endian:
+    //   "PBFT-STATE" | version: u8 | i: u32 | len(digest): u32 | digest: utf-8 bytes
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let state_digest = self.state_digest.as_bytes();
+        let mut bytes = Vec::with_capacity(STATE_ENCODING_TAG.len() + 1 + 4 + 4 + state_digest.len());
+        bytes.extend_from_slice(STATE_ENCODING_TAG);
+        bytes.push(STATE_ENCODING_VERSION);
+        bytes.extend_from_slice(&self.node_id.to_be_bytes());
+        bytes.extend_from_slice(&(state_digest.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(state_digest);
+        bytes
+    }
+}
+
+// Asks a replica for its committed log, it answers with a `StateMsg`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateRequestMsg {
+    pub(crate) node_id: u32, // the replica asking
+}
+
+// Version of the messages in this file, announced by every node to its peers (see `transport::Versions`).
+// Two nodes exchange messages in the older of their versions, so a change of a message bumps `PROTOCOL_VERSION`
+// and keeps encoding and decoding it the old way at the older versions (see `tcp::encode_frame`).
+// Every version so far encodes the messages the same way. The version in `RequestMsg::canonical_bytes` is not this one:
+// it tells the layouts of the bytes a digest covers apart, and has nothing to negotiate.
+// Nodes of both versions then interoperate, so a cluster can be upgraded one replica at a time.
+// Dropping the encodings of older versions raises `MIN_PROTOCOL_VERSION`:
+// nodes of those versions are then rejected instead of misreading the messages of newer ones.
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+pub(crate) const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// Every message exchanged between nodes, as carried by a `Transport`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Request(RequestMsg),
+    PrePrepare(PrePrepareMsg),
+    Prepare(VoteMsg),
+    Commit(VoteMsg),
+    Reply(ReplyMsg),
+    Busy(BusyMsg),
+    NewKey(NewKeyMsg),
+    StateRequest(StateRequestMsg),
+    State(StateMsg),
+    Heartbeat(HeartbeatMsg),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::utils::compute_digest;
+
+    fn request(reply_address: Option<&str>) -> RequestMsg {
+        RequestMsg {
+            operation: "put x 1".to_string(),
+            time_stamp: 1,
+            client_id: 7,
+            sequence_id: 42, // assigned by the replicas, not covered
+            digest: "ignored".to_string(),
+            reply_address: reply_address.map(str::to_string),
+            client_key: Some("not covered".to_string()),
+            client_signature: Some("not covered".to_string()),
+        }
+    }
+
+    // Known answers, so that a change of the encoding (and of every digest) cannot go unnoticed
+    #[test]
+    fn canonical_bytes_known_answer() {
+        let request_msg = request(None);
+        assert_eq!(
+            hex::encode(request_msg.canonical_bytes()),
+            "504246542d524551010000000770757420782031000000000000000100000007",
+        );
+        assert_eq!(compute_digest(&request_msg), "27fcb3453fa00f2f4a6a8f94ae02ccf24dfb236a2929fb1601c5c1f2281ddd03");
+    }
+
+    #[test]
+    fn canonical_bytes_with_reply_address_known_answer() {
+        let request_msg = request(Some("127.0.0.1:9007"));
+        assert_eq!(
+            hex::encode(request_msg.canonical_bytes()),
+            "504246542d5245510200000007707574207820310000000000000001000000070000000e3132372e302e302e313a39303037",
+        );
+        assert_eq!(compute_digest(&request_msg), "f98a0884ad05d138d7bd4204bc4fb2a5dcab6fcc444c4d743a24905ddba78502");
+    }
+
+    #[test]
+    fn signer_bitmaps_hold_each_node_once() {
+        let signers: SignerBitmap = [9, 0, 3, 3].into_iter().collect();
+        assert_eq!(signers.as_bytes(), [0b0000_1001, 0b0000_0010]);
+        assert_eq!(signers.node_ids(), vec![0, 3, 9]);
+        assert_eq!(SignerBitmap::from_bytes(signers.as_bytes().to_vec()), signers);
+    }
+
+    #[test]
+    fn canonical_bytes_ignore_replica_fields() {
+        let mut other = request(None);
+        other.sequence_id = 0;
+        other.digest = String::new();
+        assert_eq!(request(None).canonical_bytes(), other.canonical_bytes());
+    }
+}
@@ -1,5 +1,3 @@
-use std::sync::{Arc, Mutex};
-
 #[derive(Clone, PartialEq, Debug)]
 pub(crate) enum Stage {
     Idle,
@@ -9,6 +7,11 @@ pub(crate) enum Stage {
 }
 #[derive(Clone)]
 pub(crate) struct State {
-    pub(crate) view_id: u32,
-    pub(crate) current_stage: Arc<Mutex<Stage>>,
-}
\ No newline at end of file
+    pub(crate) current_stage: Stage,
+}
+
+// Paper 4.1: n replicas tolerate f = ⌊(n-1)/3⌋ faulty ones, so that any two quorums of 2f+1 replicas
+// share a correct one. Every quorum of the replicas and of the client is derived from it.
+pub(crate) fn max_faulty(n: u32) -> u32 {
+    n.saturating_sub(1) / 3
+}
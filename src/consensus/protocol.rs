@@ -0,0 +1,900 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::Instant;
+use tokio::sync::watch;
+use crate::consensus::message::{
+    AggregateSignature, BusyMsg, CommitCertificate, CommittedEntry, Message, MsgType, NewKeyMsg, PrePrepareMsg, ReplyMsg,
+    RequestMsg, StateMsg, StateRequestMsg, VoteMsg,
+};
+use crate::consensus::crypto::{aggregate_signatures, AuthScheme};
+use crate::consensus::pbft::{max_faulty, Stage};
+use crate::network::node::{Node, Progress};
+use crate::network::{liveness, overlay, recovery};
+use crate::network::transport::{Endpoint, Inbound, Transport};
+use crate::network::utils::{apply_new_key, compute_digest, compute_state_digest, verify_client_signature, verify_msg};
+
+// How long a replica that is shutting down waits for the instances in flight to commit
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a backup waits for the pre-prepare of a request before forwarding the request to the primary
+pub(crate) const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The replica side of the protocol. It only sees messages and a `Transport`,
+// so it runs the same whether messages arrive over HTTP or an in-memory channel.
+
+// Inputs of the event loop of a replica.
+pub(crate) enum Event {
+    Message(Message), // received from another node
+    Recover(u64), // start of the proactive recovery of the given epoch
+    RepairState, // the committed logs of the other replicas have had time to arrive
+    Heartbeat, // time to send the next heartbeat
+    ForwardRequests, // requests have waited `FORWARD_TIMEOUT` for their pre-prepare
+}
+
+// Event loop of a replica: the only owner of its state, handling one event at a time in arrival order.
+// Message handlers never wait for other nodes, outgoing messages are only queued on the transport,
+// and anything that has to happen later (timers) comes back to the loop as an event.
+//
+// Once `shutdown` is set, the replica drains: it drops new requests and recoveries, but keeps taking part
+// in the instances it has pre-prepared until they commit or `DRAIN_TIMEOUT` passes.
+// The state of the replica is returned, so that it can be started again from where it stopped.
+pub(crate) async fn run_replica(
+    mut node: Node,
+    transport: Arc<dyn Transport>,
+    mut inbound: Inbound,
+    recovery_period: Option<Duration>,
+    state_request_timeout: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) -> Node {
+    let (events_tx, mut events) = unbounded_channel();
+    let messages_tx = events_tx.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = inbound.recv().await {
+            if messages_tx.send(Event::Message(msg)).is_err() {
+                return;
+            }
+        }
+    });
+    if let Some(period) = recovery_period.filter(|_| !node.is_faulty) {
+        // faulty nodes do not take part in the protocol at all
+        let schedule = recovery::RecoverySchedule { n: node.node_table.len() as u32, period };
+        tokio::spawn(recovery::run_recovery_timer(node.id, schedule, events_tx.clone()));
+    }
+    let heartbeat_interval = node.liveness.heartbeats.interval;
+    if !node.is_faulty && !heartbeat_interval.is_zero() {
+        tokio::spawn(liveness::run_heartbeat_timer(heartbeat_interval, events_tx.clone()));
+    }
+    let mut drain_deadline = None;
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            // an error means the handle is gone, nobody can stop the replica any more than now
+            _ = shutdown.wait_for(|shutdown| *shutdown), if drain_deadline.is_none() => {
+                println!("🛑[{} Node{}] Shutting down, {} instances in flight", emoji(&node), node.id, node.in_flight());
+                drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
+                if node.in_flight() == 0 {
+                    break;
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(Instant::now)), if drain_deadline.is_some() => {
+                eprintln!("🛑[{} Node{}] Gave up on {} instances in flight", emoji(&node), node.id, node.in_flight());
+                break;
+            }
+            _ = tokio::time::sleep_until(next_forward_deadline(&node)), if !node.forward_deadlines.is_empty() => {
+                Event::ForwardRequests
+            }
+        };
+        let draining = drain_deadline.is_some();
+        match event {
+            Event::Message(Message::Request(request_msg)) if draining => {
+                println!("[{} Node{}] Shutting down, dropping request {}", emoji(&node), node.id, request_msg.time_stamp);
+            }
+            Event::Message(msg) => handle_message(&mut node, transport.as_ref(), msg),
+            Event::Recover(_) if draining => {}
+            Event::Recover(epoch) => {
+                recovery::recover(&mut node, transport.as_ref(), epoch);
+                let events_tx = events_tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(state_request_timeout).await;
+                    let _ = events_tx.send(Event::RepairState);
+                });
+            }
+            Event::RepairState => recovery::repair_state(&mut node),
+            Event::Heartbeat => liveness::beat(&mut node, transport.as_ref()),
+            Event::ForwardRequests => forward_requests(&mut node, transport.as_ref()),
+        }
+        if draining && node.in_flight() == 0 {
+            break;
+        }
+    }
+    println!("🛑[{} Node{}] Stopped", emoji(&node), node.id);
+    node
+}
+
+pub(crate) fn handle_message(node: &mut Node, transport: &dyn Transport, msg: Message) {
+    match msg {
+        Message::Request(request_msg) => handle_req(node, transport, request_msg),
+        Message::PrePrepare(pre_prepare_msg) => handle_pre_prepare(node, transport, pre_prepare_msg),
+        Message::Prepare(prepare_msg) => handle_prepare(node, transport, prepare_msg),
+        Message::Commit(commit_msg) => handle_commit(node, transport, commit_msg),
+        Message::NewKey(new_key_msg) => handle_new_key(node, new_key_msg),
+        Message::StateRequest(state_request_msg) => handle_state_request(node, transport, state_request_msg),
+        Message::State(state_msg) => handle_state(node, state_msg),
+        Message::Heartbeat(heartbeat_msg) => liveness::handle_heartbeat(node, heartbeat_msg),
+        Message::Reply(_) | Message::Busy(_) => {
+            eprintln!("[{} Node{}] Ignoring {:?}, only clients receive it", emoji(node), node.id, msg);
+        }
+    }
+}
+
+// Whether a message that could not be delivered yet is no longer worth retransmitting:
+// messages of an older view, and pre-prepares and prepares of a sequence this replica has already committed.
+// Commit votes are retransmitted until acknowledged, the other replicas may still need them to commit.
+// A heartbeat is obsolete once the next one has been sent.
+// Called by the sender tasks of the transport, so it only sees the progress the replica publishes.
+pub(crate) fn is_obsolete(progress: &watch::Receiver<Progress>, msg: &Message) -> bool {
+    let (view_id, sequence_id) = match msg {
+        Message::PrePrepare(msg) => (msg.view_id, msg.sequence_id),
+        Message::Prepare(msg) | Message::Commit(msg) => (msg.view_id, msg.sequence_id),
+        Message::Heartbeat(msg) => return msg.time_stamp < progress.borrow().heartbeat_time_stamp,
+        _ => return false,
+    };
+    let progress = progress.borrow();
+    if view_id < progress.view_id {
+        return true;
+    }
+    matches!(msg, Message::PrePrepare(_) | Message::Prepare(_))
+        && progress.committed.contains(&(view_id, sequence_id))
+}
+
+fn emoji(node: &Node) -> &'static str {
+    if node.is_faulty { "😈" } else { "😃" }
+}
+
+fn handle_req(node: &mut Node, transport: &dyn Transport, request_msg: RequestMsg) {
+    let emoji = emoji(node);
+    println!("[{} Node{}] Received RequestMsg: {:?}", emoji, node.id, request_msg);
+    if node.is_faulty {
+        return;
+    }
+    // Paper 4.3, second paragraph
+    // When the primary receives a client request,
+    // it assigns a sequence number to the request
+    // and multicasts a pre-prepare message for that sequence number.
+    let mut request_msg = request_msg;
+    request_msg.digest = compute_digest(&request_msg);
+    // Paper 4.1: a request that has already been processed is answered with the reply sent for it
+    if let Some((_, reply_msg)) = node.last_replies.get(&request_msg.client_id).filter(|(digest, _)| *digest == request_msg.digest) {
+        println!("[{} Node{}] Sending cached ReplyMsg to client {} again", emoji, node.id, request_msg.client_id);
+        transport.send(Endpoint::Client(request_msg.client_id), Message::Reply(reply_msg.clone()));
+        return;
+    }
+    let is_primary = node.id == node.view.primary_node_id;
+    if node.msg_buffer.request_msgs.iter().any(|msg| msg.digest == request_msg.digest) {
+        // retransmission of a request already seen, the primary may not have received it yet
+        if !is_primary {
+            forward_to_primary(node, transport, request_msg);
+        }
+        return;
+    }
+    // rejected requests are not recorded, so that the retry of the client is accepted.
+    // The queue only has to take the requests that do not fit into the pipeline.
+    if is_primary && node.in_flight() >= node.admission.pipeline_window
+        && node.pending_requests.len() >= node.admission.pending_capacity {
+        println!("🚧[{} Primary Node{}] Queue full", emoji, node.id);
+        let retry_after = node.admission.retry_after;
+        reject_request(node, transport, request_msg, retry_after);
+        return;
+    }
+    if let Err(retry_after) = node.take_token(request_msg.client_id) {
+        println!("🚧[{} Node{}] Client {} is over its rate limit", emoji, node.id, request_msg.client_id);
+        if is_primary {
+            reject_request(node, transport, request_msg, retry_after);
+        }
+        // backups drop it, they get the request with its pre-prepare if the primary orders it
+        return;
+    }
+    learn_reply_address(transport, &request_msg, false);
+    node.msg_buffer.request_msgs.push(request_msg.clone());
+    if node.current_state.current_stage == Stage::Idle {
+        node.current_state.current_stage = Stage::PrePrepare;
+    }
+    println!("🌟[{} Node{}] Transitioned to PrePrepare stage!", emoji, node.id);
+    if !is_primary {
+        // the client multicasts its requests, so the primary most likely has this one too
+        node.forward_deadlines.push_back((std::time::Instant::now() + FORWARD_TIMEOUT, request_msg));
+        return;
+    }
+    // requests go through the queue, so they are ordered in arrival order even when the pipeline is full
+    node.pending_requests.push(request_msg);
+    admit_pending(node, transport);
+    if !node.pending_requests.is_empty() {
+        println!("[{} Primary Node{}] Pipeline full, {} requests pending", emoji, node.id, node.pending_requests.len());
+    }
+}
+
+// Paper 4.1: a backup relays the requests it receives to the primary, so that a client that only reaches
+// the backups still gets its requests ordered. As clients multicast their requests, the primary usually has
+// them already: a backup only relays a request once the client retransmits it, or once it has waited
+// `FORWARD_TIMEOUT` without a pre-prepare. Requests that are already pre-prepared need no relaying.
+pub(crate) fn forward_requests(node: &mut Node, transport: &dyn Transport) {
+    let now = std::time::Instant::now();
+    while node.forward_deadlines.front().is_some_and(|(deadline, _)| *deadline <= now) {
+        let (_, request_msg) = node.forward_deadlines.pop_front().unwrap();
+        forward_to_primary(node, transport, request_msg);
+    }
+}
+
+fn next_forward_deadline(node: &Node) -> Instant {
+    node.forward_deadlines.front().map_or_else(Instant::now, |(deadline, _)| Instant::from_std(*deadline))
+}
+
+fn forward_to_primary(node: &Node, transport: &dyn Transport, request_msg: RequestMsg) {
+    if node.msg_buffer.preprepare_msgs.iter().any(|msg| msg.digest == request_msg.digest) {
+        return;
+    }
+    let primary = node.view.primary_node_id;
+    println!("[{} Node{}] Forwarding request of client {} to primary Node{}", emoji(node), node.id, request_msg.client_id, primary);
+    transport.send(Endpoint::Replica(primary), Message::Request(request_msg));
+}
+
+// A client that is not in the configuration is reached at the address in its requests.
+// Replicas only learn it from requests they accept and the client has signed, and let it replace a known address
+// only once the primary has ordered the request (see `Transport::register_client`).
+fn learn_reply_address(transport: &dyn Transport, request_msg: &RequestMsg, is_ordered: bool) {
+    let Some(reply_address) = &request_msg.reply_address else {
+        return;
+    };
+    match verify_client_signature(request_msg) {
+        Some(client_key) => transport.register_client(request_msg.client_id, reply_address, client_key, is_ordered),
+        None => eprintln!(" -- Ignoring reply address {} of client {}, the request is not signed by the client", reply_address, request_msg.client_id),
+    }
+}
+
+// Assigns the next sequence number to a request and multicasts its pre-prepare, on the primary.
+fn pre_prepare(node: &mut Node, transport: &dyn Transport, mut request_msg: RequestMsg) {
+    let emoji = emoji(node);
+    request_msg.sequence_id = node.next_sequence_id;
+    node.next_sequence_id += 1;
+    let digest = request_msg.digest.clone();
+    let mut pre_prepare_msg = PrePrepareMsg {
+        view_id: node.view.id,
+        sequence_id: request_msg.sequence_id,
+        digest,
+        request_msg,
+        signature: String::new(),
+    };
+    pre_prepare_msg.signature = node.key_pair.sign(&pre_prepare_msg.signing_bytes());
+    learn_reply_address(transport, &pre_prepare_msg.request_msg, true);
+    // the primary logs its own pre-prepare, the prepares of the backups are counted against it
+    node.msg_buffer.preprepare_msgs.push(pre_prepare_msg.clone());
+    if node.current_state.current_stage == Stage::PrePrepare {
+        node.current_state.current_stage = Stage::Prepare;
+    }
+    println!("🌟🌟[{} Primary Node{}] Transitioned to Prepare stage!", emoji, node.id);
+    // send pre-prepare message to all nodes
+    println!("[{} Node{}] Multicasting PrePrepareMsg: {:?}", emoji, node.id, pre_prepare_msg);
+    overlay::multicast(node, transport, Message::PrePrepare(pre_prepare_msg));
+}
+
+// Tells the client that the primary cannot take the request now, instead of letting the work pile up.
+fn reject_request(node: &mut Node, transport: &dyn Transport, request_msg: RequestMsg, retry_after: Duration) {
+    let mut busy_msg = BusyMsg {
+        view_id: node.view.id,
+        node_id: node.id,
+        retry_after_ms: retry_after.as_millis().max(1) as u64,
+        queue_depth: node.pending_requests.len() as u32,
+        request_msg,
+        signature: String::new(),
+    };
+    busy_msg.signature = node.key_pair.sign(&busy_msg.signing_bytes());
+    learn_reply_address(transport, &busy_msg.request_msg, false); // the registry is bounded, see `ClientAddresses`
+    println!("🚧[{} Primary Node{}] Asking client {} to retry in {}ms",
+             emoji(node), node.id, busy_msg.request_msg.client_id, busy_msg.retry_after_ms);
+    transport.send(Endpoint::Client(busy_msg.request_msg.client_id), Message::Busy(busy_msg));
+}
+
+// Fills the pipeline of the primary from its queue, taking the requests of the clients in turns.
+fn admit_pending(node: &mut Node, transport: &dyn Transport) {
+    while node.in_flight() < node.admission.pipeline_window {
+        let Some(request_msg) = node.pending_requests.pop() else {
+            return;
+        };
+        pre_prepare(node, transport, request_msg);
+    }
+}
+
+fn handle_pre_prepare(node: &mut Node, transport: &dyn Transport, pre_prepare_msg: PrePrepareMsg) {
+    let emoji = emoji(node);
+    println!("[{} Node{}] Received PrePrepareMsg: {:?}", emoji, node.id, pre_prepare_msg);
+    if node.is_faulty {
+        return;
+    }
+    // checked before anything is taken from it or relayed, a pre-prepare only counts if it comes from the primary
+    let primary = node.view.primary_node_id;
+    if !node.public_keys.verify(primary, &pre_prepare_msg.signing_bytes(), &pre_prepare_msg.signature) {
+        eprintln!("[{} Node{}] PrePrepareMsg signature verification failed", emoji, node.id);
+        return;
+    }
+    // request message corresponding to the pre-prepare message
+    let request_msg = node.msg_buffer.request_msgs.iter()
+        .find(|msg| msg.digest == pre_prepare_msg.digest)
+        .cloned();
+    let request_msg = match request_msg {
+        Some(request_msg) => request_msg,
+        None => {
+            // Paper 4.2: the pre-prepare carries the request itself, so a backup that has not received it
+            // from the client (it was late, or over the rate limit of the client) takes it from there
+            let mut request_msg = pre_prepare_msg.request_msg.clone();
+            request_msg.digest = compute_digest(&request_msg);
+            if request_msg.digest != pre_prepare_msg.digest {
+                eprintln!("[{} Node{}] Request in PrePrepareMsg does not match its digest", emoji, node.id);
+                return;
+            }
+            node.msg_buffer.request_msgs.push(request_msg.clone());
+            request_msg
+        }
+    };
+    if !verify_msg(&pre_prepare_msg, node.view.id, request_msg.digest.clone()) {
+        eprintln!("[{} Node{}] PrePrepareMsg verification failed", emoji, node.id);
+        return;
+    }
+    let view_id = pre_prepare_msg.view_id;
+    let sequence_id = pre_prepare_msg.sequence_id;
+    let digest = pre_prepare_msg.digest.clone();
+    {
+        // Paper 4.2: a backup accepts at most one pre-prepare for each (v, n)
+        let preprepare_msgs = &mut node.msg_buffer.preprepare_msgs;
+        if let Some(accepted) = preprepare_msgs.iter().find(|msg| msg.view_id == view_id && msg.sequence_id == sequence_id) {
+            if accepted.digest != digest {
+                eprintln!("[{} Node{}] Rejected PrePrepareMsg: ({}, {}) is already assigned to another request", emoji, node.id, view_id, sequence_id);
+            }
+            return; // or a retransmission of the pre-prepare already accepted
+        }
+        preprepare_msgs.push(pre_prepare_msg.clone());
+    }
+    learn_reply_address(transport, &pre_prepare_msg.request_msg, true);
+    overlay::relay(node, transport, primary, &Message::PrePrepare(pre_prepare_msg));
+    // If node i is accepting the pre-prepare message, it transitions to the Prepare stage
+    // by multicasting a prepare message to all other nodes
+    if node.current_state.current_stage == Stage::PrePrepare {
+        node.current_state.current_stage = Stage::Prepare;
+    }
+    println!("🌟🌟[{} Node{}] Transitioned to Prepare stage!", emoji, node.id);
+
+    let mut prepare_msg = VoteMsg {
+        view_id,
+        sequence_id,
+        digest,
+        node_id: node.id,
+        msg_type: MsgType::PrepareMsg,
+        signature: String::new(),
+    };
+    prepare_msg.signature = node.key_pair.sign(&prepare_msg.signing_bytes());
+
+    node.msg_buffer.prepare_msgs.push(prepare_msg.clone()); // save the prepare message
+    println!("[{} Node{}] Multicasting PrepareMsg: {:?}", emoji, node.id, prepare_msg);
+    overlay::multicast(node, transport, Message::Prepare(prepare_msg.clone()));
+    // the prepares of other replicas may have arrived first
+    check_prepared(node, transport, &prepare_msg);
+}
+
+fn handle_prepare(node: &mut Node, transport: &dyn Transport, prepare_msg: VoteMsg) {
+    let emoji = emoji(node);
+    println!("[{} Node{}] Received PrepareMsg: {:?}", emoji, node.id, prepare_msg);
+    if node.is_faulty {
+        return;
+    }
+    if !node.public_keys.verify(prepare_msg.node_id, &prepare_msg.signing_bytes(), &prepare_msg.signature) {
+        eprintln!("[{} Node{}] PrepareMsg signature verification failed", emoji, node.id);
+        return;
+    }
+    node.liveness.seen(prepare_msg.node_id);
+    {
+        let prepare_msgs = &mut node.msg_buffer.prepare_msgs;
+        if prepare_msgs.iter().any(|msg| msg.node_id == prepare_msg.node_id && msg.view_id == prepare_msg.view_id && msg.digest == prepare_msg.digest) {
+            return; // retransmission, every replica is counted once
+        }
+        // kept even without its pre-prepare, which may still be on its way (through other relays of the overlay)
+        prepare_msgs.push(prepare_msg.clone()); // save the prepare message
+    }
+    overlay::relay(node, transport, prepare_msg.node_id, &Message::Prepare(prepare_msg.clone()));
+    let has_pre_prepare = node.msg_buffer.preprepare_msgs.iter()
+        .any(|msg| msg.digest == prepare_msg.digest && msg.view_id == prepare_msg.view_id);
+    if !has_pre_prepare {
+        eprintln!("[{} Node{}] PrePrepareMsg not found for PrepareMsg yet", emoji, node.id);
+        return;
+    }
+    check_prepared(node, transport, &prepare_msg);
+}
+
+// Paper 4.3: a replica is prepared once it has accepted the pre-prepare and 2f matching prepares of other replicas,
+// it then multicasts its commit, once.
+fn check_prepared(node: &mut Node, transport: &dyn Transport, prepare_msg: &VoteMsg) {
+    let emoji = emoji(node);
+    let f = max_faulty(node.node_table.len() as u32) as usize;
+    let cnt = node.msg_buffer.prepare_msgs.iter()
+        .filter(|msg| msg.digest == prepare_msg.digest && msg.view_id == prepare_msg.view_id)
+        .count();
+    let has_committed = node.msg_buffer.commit_msgs.iter().any(|msg| {
+        msg.node_id == node.id && msg.view_id == prepare_msg.view_id && msg.sequence_id == prepare_msg.sequence_id
+    });
+    if cnt < 2 * f || has_committed {
+        return;
+    }
+    if node.current_state.current_stage == Stage::Prepare {
+        node.current_state.current_stage = Stage::Commit;
+    }
+    println!("🌟🌟🌟[{} Node{}] Transitioned to Commit stage!", emoji, node.id);
+    let mut commit_msg = VoteMsg {
+        view_id: prepare_msg.view_id,
+        sequence_id: prepare_msg.sequence_id,
+        digest: prepare_msg.digest.clone(),
+        node_id: node.id,
+        msg_type: MsgType::CommitMsg,
+        signature: String::new(),
+    };
+    commit_msg.signature = node.key_pair.sign(&commit_msg.signing_bytes());
+    node.msg_buffer.commit_msgs.push(commit_msg.clone());
+    println!("[{} Node{}] Multicasting CommitMsg: {:?}", emoji, node.id, commit_msg);
+    overlay::multicast(node, transport, Message::Commit(commit_msg));
+}
+
+fn handle_commit(node: &mut Node, transport: &dyn Transport, commit_msg: VoteMsg) {
+    let emoji = emoji(node);
+    println!("[{} Node{}] Received CommitMsg: {:?}", emoji, node.id, commit_msg);
+    if node.is_faulty {
+        return;
+    }
+    let f = max_faulty(node.node_table.len() as u32) as usize;
+    if !node.public_keys.verify(commit_msg.node_id, &commit_msg.signing_bytes(), &commit_msg.signature) {
+        eprintln!("[{} Node{}] CommitMsg signature verification failed", emoji, node.id);
+        return;
+    }
+    node.liveness.seen(commit_msg.node_id);
+    // collect the matching commit votes of distinct nodes, they form the commit certificate once there are 2f+1 of them
+    let commits = {
+        let commit_msgs = &mut node.msg_buffer.commit_msgs;
+        let is_duplicate = commit_msgs.iter().any(|msg| {
+            msg.node_id == commit_msg.node_id && msg.view_id == commit_msg.view_id
+                && msg.sequence_id == commit_msg.sequence_id && msg.digest == commit_msg.digest
+        });
+        if is_duplicate {
+            return;
+        }
+        commit_msgs.push(commit_msg.clone());
+        commit_msgs.iter()
+            .filter(|msg| {
+                msg.digest == commit_msg.digest && msg.view_id == commit_msg.view_id
+                    && msg.sequence_id == commit_msg.sequence_id
+            })
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    overlay::relay(node, transport, commit_msg.node_id, &Message::Commit(commit_msg.clone()));
+    if commits.len() != 2 * f + 1 {
+        return;
+    }
+    node.commit(CommittedEntry {
+        view_id: commit_msg.view_id,
+        sequence_id: commit_msg.sequence_id,
+        digest: commit_msg.digest.clone(),
+    });
+    let request_msg = node.msg_buffer.request_msgs.iter()
+        .find(|msg| msg.digest == commit_msg.digest)
+        .cloned();
+    let is_primary = node.id == node.view.primary_node_id;
+    if let Some(request_msg) = request_msg {
+        let reply_msg = ReplyMsg {
+            time_stamp: request_msg.time_stamp,
+            view_id: commit_msg.view_id,
+            node_id: node.id,
+            client_id: request_msg.client_id,
+            result: request_msg.operation.clone(),
+            certificate: Some(build_commit_certificate(node, &commit_msg, commits)),
+            queue_depth: is_primary.then_some(node.pending_requests.len() as u32),
+        };
+        println!("[{} Node{}] Sending ReplyMsg to client {}", emoji, node.id, reply_msg.client_id);
+        node.last_replies.insert(reply_msg.client_id, (request_msg.digest.clone(), reply_msg.clone()));
+        transport.send(Endpoint::Client(reply_msg.client_id), Message::Reply(reply_msg));
+    }
+    if is_primary {
+        admit_pending(node, transport);
+    }
+}
+
+fn handle_new_key(node: &mut Node, new_key_msg: NewKeyMsg) {
+    if node.is_faulty {
+        return;
+    }
+    if apply_new_key(&mut node.public_keys, &new_key_msg) {
+        println!("🔑[😃 Node{}] Installed new key of node {} (epoch {})", node.id, new_key_msg.node_id, new_key_msg.epoch);
+        recovery::save_keys(node);
+    } else {
+        eprintln!("[😃 Node{}] Rejected NewKeyMsg of node {}", node.id, new_key_msg.node_id);
+    }
+}
+
+// Sends the committed log of this replica to a recovering replica, to verify and repair its state
+fn handle_state_request(node: &mut Node, transport: &dyn Transport, state_request_msg: StateRequestMsg) {
+    if node.is_faulty {
+        return;
+    }
+    let mut log = node.committed_log.clone();
+    log.sort_by_key(|entry| (entry.view_id, entry.sequence_id));
+    let mut state_msg = StateMsg {
+        node_id: node.id,
+        state_digest: compute_state_digest(&log),
+        log,
+        signature: String::new(),
+    };
+    state_msg.signature = node.key_pair.sign(&state_msg.signing_bytes());
+    transport.send(Endpoint::Replica(state_request_msg.node_id), Message::State(state_msg));
+}
+
+// Keeps the committed log of another replica for `recovery::repair_state`, if it is signed by that replica,
+// so that a faulty replica cannot vote for a log f+1 times under the names of others.
+fn handle_state(node: &mut Node, state_msg: StateMsg) {
+    if !node.public_keys.verify(state_msg.node_id, &state_msg.signing_bytes(), &state_msg.signature) {
+        eprintln!("[{} Node{}] StateMsg signature verification failed", emoji(node), node.id);
+        return;
+    }
+    node.msg_buffer.state_msgs.push(state_msg);
+}
+
+// Builds the commit certificate for the 2f+1 matching `commits`.
+// With BLS keys the commit signatures are aggregated, so the certificate has a constant-size signature.
+fn build_commit_certificate(node: &Node, commit_msg: &VoteMsg, commits: Vec<VoteMsg>) -> CommitCertificate {
+    let mut certificate = CommitCertificate {
+        view_id: commit_msg.view_id,
+        sequence_id: commit_msg.sequence_id,
+        digest: commit_msg.digest.clone(),
+        commits: Vec::new(),
+        aggregate: None,
+    };
+    if node.key_pair.scheme() == AuthScheme::Bls {
+        let signatures: Vec<&str> = commits.iter().map(|msg| msg.signature.as_str()).collect();
+        if let Some(signature) = aggregate_signatures(&signatures) {
+            certificate.aggregate = Some(AggregateSignature {
+                signers: commits.iter().map(|msg| msg.node_id).collect(),
+                signature,
+            });
+            return certificate;
+        }
+    }
+    certificate.commits = commits;
+    certificate
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::consensus::crypto::{KeyPair, PublicKeyTable};
+    use crate::network::node::PRIMARY_NODE_ID as PRIMARY;
+    use crate::network::transport::memory::{MemoryNetwork, MemoryTransport};
+
+    const N: u32 = 4; // f = 1
+    const CLIENT_ID: u32 = 0;
+
+    // Replicas on a `MemoryNetwork`, driven one message at a time by the test instead of by `run_replica`.
+    struct Cluster {
+        replicas: Vec<(Node, MemoryTransport, Inbound)>,
+        key_pairs: Vec<KeyPair>, // to sign votes in the name of the faulty replicas
+        client: Inbound,
+        network: MemoryNetwork,
+    }
+
+    impl Cluster {
+        fn new(faulty: &[u32]) -> Self {
+            let network = MemoryNetwork::default();
+            let key_pairs: Vec<KeyPair> = (0..N).map(|_| KeyPair::generate(AuthScheme::Ed25519)).collect();
+            let mut public_keys = PublicKeyTable::default();
+            for (id, key_pair) in key_pairs.iter().enumerate() {
+                public_keys.insert(id as u32, key_pair.public_key());
+            }
+            let node_table: HashMap<u32, String> = (0..N).map(|id| (id, format!("localhost:{}", 8000 + id))).collect();
+            let replicas = (0..N)
+                .map(|id| {
+                    let node = Node::new(id, node_table.clone(), faulty.contains(&id), key_pairs[id as usize].clone(), public_keys.clone());
+                    let (transport, inbound) = network.join(Endpoint::Replica(id));
+                    (node, transport, inbound)
+                })
+                .collect();
+            let (_, client) = network.join(Endpoint::Client(CLIENT_ID));
+            Self { replicas, key_pairs, client, network }
+        }
+
+        fn node(&self, id: u32) -> &Node {
+            &self.replicas[id as usize].0
+        }
+
+        // Delivers `msg` to replica `to`.
+        fn deliver(&mut self, to: u32, msg: Message) {
+            let (node, transport, _) = &mut self.replicas[to as usize];
+            handle_message(node, transport, msg);
+        }
+
+        fn request_msg(operation: &str) -> RequestMsg {
+            RequestMsg {
+                operation: operation.to_string(),
+                time_stamp: 1,
+                client_id: CLIENT_ID,
+                sequence_id: 0,
+                digest: String::new(),
+                reply_address: None,
+                client_key: None,
+                client_signature: None,
+            }
+        }
+
+        // The client multicasts a request to all replicas.
+        fn request(&mut self, operation: &str) {
+            let request_msg = Self::request_msg(operation);
+            for id in 0..N {
+                self.deliver(id, Message::Request(request_msg.clone()));
+            }
+        }
+
+        // Hands the messages the replicas have sent to their receivers, until there are none left.
+        fn run(&mut self) {
+            let mut delivered = true;
+            while delivered {
+                delivered = false;
+                for (node, transport, inbound) in &mut self.replicas {
+                    while let Ok(msg) = inbound.try_recv() {
+                        handle_message(node, transport, msg);
+                        delivered = true;
+                    }
+                }
+            }
+        }
+
+        fn replies(&mut self) -> Vec<ReplyMsg> {
+            let mut replies = Vec::new();
+            while let Ok(msg) = self.client.try_recv() {
+                if let Message::Reply(reply_msg) = msg {
+                    replies.push(reply_msg);
+                }
+            }
+            replies
+        }
+
+        // A vote of replica `node_id` for the pre-prepare of the primary.
+        fn vote(&self, node_id: u32, msg_type: MsgType) -> VoteMsg {
+            let pre_prepare_msg = &self.node(PRIMARY).msg_buffer.preprepare_msgs[0];
+            let mut vote_msg = VoteMsg {
+                view_id: pre_prepare_msg.view_id,
+                sequence_id: pre_prepare_msg.sequence_id,
+                digest: pre_prepare_msg.digest.clone(),
+                node_id,
+                msg_type,
+                signature: String::new(),
+            };
+            vote_msg.signature = self.key_pairs[node_id as usize].sign(&vote_msg.signing_bytes());
+            vote_msg
+        }
+    }
+
+    #[test]
+    fn requests_commit_on_every_replica() {
+        let mut cluster = Cluster::new(&[]);
+        cluster.request("op");
+        cluster.run();
+        let digest = &cluster.node(PRIMARY).msg_buffer.preprepare_msgs[0].digest;
+        for id in 0..N {
+            let committed_log = &cluster.node(id).committed_log;
+            assert_eq!(committed_log.len(), 1, "replica {} did not commit", id);
+            assert_eq!(committed_log[0].digest, *digest);
+        }
+        let replies = cluster.replies();
+        assert_eq!(replies.len(), N as usize);
+        assert!(replies.iter().all(|reply_msg| reply_msg.result == "op" && reply_msg.certificate.is_some()));
+    }
+
+    #[tokio::test]
+    async fn replicas_run_as_event_loops_and_hand_back_their_state() {
+        let Cluster { replicas, mut client, network, .. } = Cluster::new(&[]);
+        let shutdown = watch::Sender::new(false);
+        let tasks: Vec<_> = replicas.into_iter()
+            .map(|(node, transport, inbound)| {
+                tokio::spawn(run_replica(node, Arc::new(transport), inbound, None, Duration::ZERO, shutdown.subscribe()))
+            })
+            .collect();
+        let (sender, _) = network.join(Endpoint::Client(CLIENT_ID + 1));
+        sender.broadcast(Message::Request(RequestMsg {
+            operation: "op".to_string(),
+            time_stamp: 1,
+            client_id: CLIENT_ID,
+            sequence_id: 0,
+            digest: String::new(),
+            reply_address: None,
+            client_key: None,
+            client_signature: None,
+        }));
+        let mut replied = Vec::new();
+        while replied.len() < N as usize {
+            match tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap() {
+                Some(Message::Reply(reply_msg)) => replied.push(reply_msg.node_id),
+                msg => assert!(msg.is_some()),
+            }
+        }
+        replied.sort();
+        assert_eq!(replied, (0..N).collect::<Vec<u32>>());
+
+        // nothing is in flight any more, so every replica stops at once, with what it has committed
+        shutdown.send_replace(true);
+        let mut digests = Vec::new();
+        for task in tasks {
+            let node = tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+            assert_eq!(node.committed_log.len(), 1);
+            digests.push(node.committed_log[0].digest.clone());
+        }
+        digests.dedup();
+        assert_eq!(digests.len(), 1);
+    }
+
+    #[test]
+    fn the_primary_queues_beyond_its_pipeline_and_is_busy_beyond_its_queue() {
+        let mut cluster = Cluster::new(&[]);
+        cluster.replicas[PRIMARY as usize].0.admission.pipeline_window = 1;
+        cluster.replicas[PRIMARY as usize].0.admission.pending_capacity = 1;
+        for time_stamp in 1..=3 {
+            let request_msg = RequestMsg {
+                operation: format!("op{}", time_stamp),
+                time_stamp,
+                client_id: CLIENT_ID,
+                sequence_id: 0,
+                digest: String::new(),
+                reply_address: None,
+                client_key: None,
+                client_signature: None,
+            };
+            cluster.deliver(PRIMARY, Message::Request(request_msg));
+        }
+        assert_eq!(cluster.node(PRIMARY).msg_buffer.preprepare_msgs.len(), 1);
+        assert_eq!(cluster.node(PRIMARY).pending_requests.len(), 1);
+        let Ok(Message::Busy(busy_msg)) = cluster.client.try_recv() else {
+            panic!("the third request was not refused");
+        };
+        assert_eq!((busy_msg.request_msg.time_stamp, busy_msg.queue_depth), (3, 1));
+        let primary = cluster.node(PRIMARY);
+        assert!(primary.public_keys.verify(PRIMARY, &busy_msg.signing_bytes(), &busy_msg.signature));
+
+        // the queued request is pre-prepared once the first one has committed
+        cluster.run();
+        let operations: Vec<&str> = cluster.node(PRIMARY).msg_buffer.preprepare_msgs.iter()
+            .map(|msg| msg.request_msg.operation.as_str())
+            .collect();
+        assert_eq!(operations, ["op1", "op2"]);
+        assert_eq!(cluster.node(PRIMARY).committed_log.len(), 2);
+        assert!(cluster.node(PRIMARY).pending_requests.is_empty());
+    }
+
+    #[test]
+    fn a_backup_forwards_a_retransmitted_request_the_primary_has_missed() {
+        let mut cluster = Cluster::new(&[]);
+        // only backup 1 receives the request, and waits for the pre-prepare of the primary first
+        cluster.deliver(1, Message::Request(Cluster::request_msg("op")));
+        cluster.run();
+        assert!(cluster.node(PRIMARY).msg_buffer.request_msgs.is_empty());
+        assert_eq!(cluster.node(1).forward_deadlines.len(), 1);
+
+        // the client retransmits it, so the backup relays it at once
+        cluster.deliver(1, Message::Request(Cluster::request_msg("op")));
+        cluster.run();
+        for id in 0..N {
+            assert_eq!(cluster.node(id).committed_log.len(), 1, "replica {} did not commit", id);
+        }
+        assert_eq!(cluster.replies().len(), N as usize);
+    }
+
+    #[test]
+    fn a_backup_forwards_a_request_left_without_pre_prepare() {
+        let mut cluster = Cluster::new(&[]);
+        cluster.deliver(1, Message::Request(Cluster::request_msg("op")));
+        cluster.run();
+        assert!(cluster.node(PRIMARY).msg_buffer.request_msgs.is_empty());
+
+        // `FORWARD_TIMEOUT` has passed
+        let (node, transport, _) = &mut cluster.replicas[1];
+        node.forward_deadlines[0].0 = std::time::Instant::now();
+        forward_requests(node, transport);
+        assert!(node.forward_deadlines.is_empty());
+        cluster.run();
+        assert_eq!(cluster.node(PRIMARY).committed_log.len(), 1);
+        assert_eq!(cluster.replies().len(), N as usize);
+    }
+
+    #[test]
+    fn a_retransmitted_request_is_answered_with_the_cached_reply() {
+        let mut cluster = Cluster::new(&[]);
+        cluster.request("op");
+        cluster.run();
+        let reply_msg = cluster.replies().into_iter().find(|reply_msg| reply_msg.node_id == 2).unwrap();
+
+        // e.g. the reply was lost: replica 2 sends it again, without ordering the request a second time
+        cluster.deliver(2, Message::Request(Cluster::request_msg("op")));
+        cluster.run();
+        let replies = cluster.replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!((replies[0].node_id, replies[0].time_stamp, &replies[0].result), (2, reply_msg.time_stamp, &reply_msg.result));
+        assert!(replies[0].certificate.is_some());
+        for id in 0..N {
+            assert_eq!(cluster.node(id).committed_log.len(), 1);
+        }
+        assert_eq!(cluster.node(PRIMARY).msg_buffer.preprepare_msgs.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_votes_are_counted_once() {
+        // replicas 2 and 3 are silent, so the votes of 0 and 1 alone are short of a quorum
+        let mut cluster = Cluster::new(&[2, 3]);
+        cluster.request("op");
+        cluster.run();
+        // the prepare of replica 2, received three times, makes up the 2f = 2 prepares of replica 1 with its own
+        let prepare_msg = cluster.vote(2, MsgType::PrepareMsg);
+        for _ in 0..3 {
+            cluster.deliver(1, Message::Prepare(prepare_msg.clone()));
+        }
+        let prepares = cluster.node(1).msg_buffer.prepare_msgs.iter().filter(|msg| msg.node_id == 2).count();
+        assert_eq!(prepares, 1);
+        cluster.deliver(PRIMARY, Message::Prepare(prepare_msg));
+        cluster.run();
+        // both multicast their commit once, and count each other's, 2 of the 2f + 1 = 3 needed
+        for id in [0, 1] {
+            let commits = cluster.node(id).msg_buffer.commit_msgs.iter().filter(|msg| msg.node_id == id).count();
+            assert_eq!(commits, 1);
+        }
+        let commit_msg = cluster.node(1).msg_buffer.commit_msgs.iter().find(|msg| msg.node_id == 1).unwrap().clone();
+        for _ in 0..3 {
+            cluster.deliver(PRIMARY, Message::Commit(commit_msg.clone()));
+        }
+        assert!(cluster.node(PRIMARY).committed_log.is_empty());
+        assert!(cluster.replies().is_empty());
+
+        // a third replica completes the quorum
+        cluster.deliver(PRIMARY, Message::Commit(cluster.vote(2, MsgType::CommitMsg)));
+        assert_eq!(cluster.node(PRIMARY).committed_log.len(), 1);
+        assert_eq!(cluster.replies().len(), 1);
+    }
+
+    #[test]
+    fn a_silent_faulty_replica_does_not_stop_the_others() {
+        let mut cluster = Cluster::new(&[3]);
+        cluster.request("op");
+        cluster.run();
+        for id in 0..3 {
+            assert_eq!(cluster.node(id).committed_log.len(), 1, "replica {} did not commit", id);
+        }
+        assert!(cluster.node(3).committed_log.is_empty());
+        assert_eq!(cluster.replies().len(), 3);
+    }
+
+    #[test]
+    fn votes_in_the_name_of_another_replica_are_dropped() {
+        // faulty replica 3 signs votes as replica 2, which would complete the quorums of 0 and 1
+        let mut cluster = Cluster::new(&[2, 3]);
+        cluster.request("op");
+        cluster.run();
+        let mut prepare_msg = cluster.vote(2, MsgType::PrepareMsg);
+        prepare_msg.signature = cluster.key_pairs[3].sign(&prepare_msg.signing_bytes());
+        let mut commit_msg = cluster.vote(2, MsgType::CommitMsg);
+        commit_msg.signature = cluster.key_pairs[3].sign(&commit_msg.signing_bytes());
+        for id in [0, 1] {
+            cluster.deliver(id, Message::Prepare(prepare_msg.clone()));
+            cluster.deliver(id, Message::Commit(commit_msg.clone()));
+        }
+        cluster.run();
+        for id in [0, 1] {
+            let node = cluster.node(id);
+            assert!(node.msg_buffer.prepare_msgs.iter().chain(&node.msg_buffer.commit_msgs).all(|msg| msg.node_id != 2));
+            assert!(node.committed_log.is_empty());
+        }
+        assert!(cluster.replies().is_empty());
+    }
+}
@@ -3,11 +3,19 @@ pub mod network {
     pub mod client;
     pub mod server;
     pub mod launcher;
-    mod utils;
+    pub mod config;
+    mod tls;
+    pub(crate) mod recovery;
+    pub(crate) mod liveness;
+    pub(crate) mod overlay;
+    pub(crate) mod utils;
+    pub(crate) mod transport;
 }
 
 mod consensus {
     pub(crate) mod pbft;
     pub(crate) mod message;
+    pub(crate) mod crypto;
+    pub(crate) mod protocol;
 }
 
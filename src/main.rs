@@ -1,36 +1,193 @@
-use clap::{Arg, Command};
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Arg, ArgMatches, Command};
+use pbft_rust::network::config::{ClientConfig, ClusterConfig};
 use pbft_rust::network::launcher;
 fn main() {
     let matches = Command::new("pbft-rust")
         .version("1.0.0")
         .author("Jeffro")
         .about("A sample implementation of the Practical Byzantine Fault Tolerance (PBFT) consensus algorithm in Rust. 🦀")
-        .arg(
-            Arg::new("f")
-                .short('f')
-                .long("number of faulty nodes")
-                .value_parser(clap::value_parser!(u32))
-                .help("Sets the number of faulty nodes"),
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("local")
+                .about("Runs all replicas and the client in this process")
+                .arg(
+                    Arg::new("f")
+                        .short('f')
+                        .long("number of faulty nodes")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Sets the number of faulty nodes, the last f nodes, which must be (n-1)/3 (the default)"),
+                )
+                .arg(
+                    Arg::new("n")
+                        .short('n')
+                        .long("number of nodes")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Sets the number of nodes"),
+                )
+                .arg(
+                    Arg::new("auth")
+                        .long("auth")
+                        .value_parser(["ed25519", "bls"])
+                        .default_value("ed25519")
+                        .help("Sets how replicas sign their votes, 'bls' produces aggregate commit certificates"),
+                )
+                .arg(
+                    Arg::new("transport")
+                        .long("transport")
+                        .value_parser(["http", "tcp", "unix", "grpc", "quic", "memory"])
+                        .default_value("http")
+                        .help("Sets how nodes exchange messages, 'tcp' uses binary frames, 'unix' the same frames over Unix sockets, 'grpc' the schema in proto/pbft.proto, 'quic' one stream per message type, 'memory' keeps the whole cluster in this process"),
+                )
+                .arg(
+                    Arg::new("tls-dir")
+                        .long("tls-dir")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Enables mutual TLS with the CA, node and client certificates in this directory"),
+                )
+                .arg(
+                    Arg::new("recovery-period")
+                        .long("recovery-period")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Enables proactive recovery, every replica refreshes its key and state once per this many seconds"),
+                )
+                .arg(
+                    Arg::new("tree-fanout")
+                        .long("tree-fanout")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .help("Relays pre-prepares and votes along a tree with this many children per replica, instead of multicasting them directly"),
+                )
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Reads the cluster from this TOML file instead of the options above"),
+                )
+                .arg(
+                    Arg::new("init-config")
+                        .long("init-config")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Writes the cluster given by the options above to this TOML file, with a key file per replica, and exits"),
+                )
+                .arg(
+                    Arg::new("shared-runtime")
+                        .long("shared-runtime")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Runs all replicas as tasks of one multi-threaded runtime instead of a thread each, for large clusters (not with the http transport)"),
+                ),
         )
-        .arg(
-            Arg::new("n")
-                .short('n')
-                .long("number of nodes")
-                .value_parser(clap::value_parser!(u32))
-                .help("Sets the number of nodes"),
+        .subcommand(
+            Command::new("replica")
+                .about("Runs a single replica of the cluster in this process")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Sets the id of the replica in the configuration file"),
+                )
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Reads the cluster from this TOML file"),
+                )
+                .arg(
+                    Arg::new("faulty")
+                        .long("faulty")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Makes the replica faulty, it does not take part in the protocol"),
+                ),
+        )
+        .subcommand(
+            Command::new("client")
+                .about("Runs a client of the cluster in this process")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .default_value("0")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Sets the id of the client, in the configuration file unless --address is given"),
+                )
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Reads the cluster from this TOML file"),
+                )
+                .arg(
+                    Arg::new("address")
+                        .long("address")
+                        .requires("api-address")
+                        .requires("key-path")
+                        .help("Runs a client that is not in the configuration file, receiving its replies at this address"),
+                )
+                .arg(
+                    Arg::new("api-address")
+                        .long("api-address")
+                        .requires("address")
+                        .help("Sets where users send requests to a client that is not in the configuration file"),
+                )
+                .arg(
+                    Arg::new("key-path")
+                        .long("key-path")
+                        .requires("address")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Reads the key a client that is not in the configuration file signs its requests with, created if missing"),
+                ),
         )
         .get_matches();
 
-    let f = *matches.get_one::<u32>("f").unwrap_or(&1);
+    match matches.subcommand() {
+        Some(("replica", matches)) => {
+            let config = ClusterConfig::load(matches.get_one::<PathBuf>("config").unwrap()).unwrap();
+            let id = *matches.get_one::<u32>("id").unwrap();
+            launcher::launch_replica(&config, id, matches.get_flag("faulty")).unwrap();
+        }
+        Some(("client", matches)) => {
+            let config = ClusterConfig::load(matches.get_one::<PathBuf>("config").unwrap()).unwrap();
+            let id = *matches.get_one::<u32>("id").unwrap();
+            match matches.get_one::<String>("address") {
+                Some(address) => {
+                    let client_config = ClientConfig {
+                        id,
+                        address: address.clone(),
+                        api_address: matches.get_one::<String>("api-address").unwrap().clone(),
+                    };
+                    launcher::launch_external_client(&config, client_config, matches.get_one::<PathBuf>("key-path").unwrap()).unwrap();
+                }
+                None => launcher::launch_client(&config, id).unwrap(),
+            }
+        }
+        Some(("local", matches)) => local(matches),
+        _ => unreachable!("a subcommand is required"),
+    }
+}
+
+fn local(matches: &ArgMatches) {
     let n = *matches.get_one::<u32>("n").unwrap_or(&4);
+    let f = *matches.get_one::<u32>("f").unwrap_or(&(n.saturating_sub(1) / 3));
+    let auth = matches.get_one::<String>("auth").unwrap();
+    let transport = matches.get_one::<String>("transport").unwrap();
+    let tls_dir = matches.get_one::<PathBuf>("tls-dir").cloned();
+    let recovery_period = matches.get_one::<u64>("recovery-period").map(|secs| Duration::from_secs(*secs));
 
-    if n < f {
-        panic!("The number of nodes must be greater than the number of faulty nodes.");
+    let mut config = ClusterConfig::local(n, f, auth, transport, tls_dir, recovery_period);
+    config.protocol.tree_fanout = matches.get_one::<u64>("tree-fanout").map(|fanout| *fanout as usize);
+    if let Some(path) = matches.get_one::<PathBuf>("init-config") {
+        config.init(path).unwrap();
+        println!("Wrote cluster configuration to {}", path.display());
+        return;
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("config") {
+        config = ClusterConfig::load(path).unwrap();
     }
 
-    println!("f: {}", f);
-    println!("n: {}", n);
-
-    launcher::launch(n, f).unwrap();
+    println!("f: {}", config.f);
+    println!("n: {}", config.replicas.len());
 
-}
\ No newline at end of file
+    launcher::launch(&config, matches.get_flag("shared-runtime")).unwrap();
+}
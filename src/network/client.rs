@@ -1,33 +1,88 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use std::{io, thread};
 use std::thread::JoinHandle;
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
+use std::time::{Duration, Instant};
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
 use actix_web::web::Data;
 use serde_json::json;
-use crate::consensus::message::{RequestMsg, ReplyMsg};
-use futures::future::join_all;
+use tokio::sync::watch;
+use crate::consensus::crypto::{KeyPair, PublicKeyTable};
+use crate::consensus::pbft::max_faulty;
+use crate::consensus::protocol::DRAIN_TIMEOUT;
+use crate::consensus::message::{BusyMsg, CommitCertificate, Message, NewKeyMsg, RequestMsg, ReplyMsg};
+use crate::network::config::ClientConfig;
+use crate::network::node::PRIMARY_NODE_ID;
+use crate::network::tls::TlsConfig;
+use crate::network::transport::http::{HttpListener, CLIENT_PATHS};
+use crate::network::transport::tcp::MAX_FRAME_LEN;
+use crate::network::transport::unix::prepare_socket;
+use crate::network::transport::{flush, Endpoint, Inbound, Transport};
+use crate::network::utils::{apply_new_key, compute_digest, verify_commit_certificate};
+
+// Requests the replicas have not answered yet are given up once there are more than this many, oldest first
+const MAX_PENDING_REQUESTS: usize = 1024;
+
+// A request sent to the replicas, until f+1 of them have replied with the same result
+struct PendingRequest {
+    digest: String,
+    replies: HashMap<u32, ReplyMsg>, // Node.id -> reply, every replica is counted once
+}
+
+// A client that is not in the configuration of the replicas: its requests carry its reply address,
+// signed with its key so that only this client can register or move the address (see `ClientAddresses`).
+#[derive(Clone)]
+pub(crate) struct ExternalClient {
+    pub(crate) reply_address: String,
+    pub(crate) key_pair: KeyPair, // ed25519, kept across restarts so the client can move to another address
+}
 
 #[derive(Clone)]
 pub(crate) struct Client {
+    id: u32,
     n : u32,
-    server_table: HashMap<u32, String>,
-    pub(crate) reply_msgs: Arc<Mutex<Vec<ReplyMsg>>>,
+    public_keys: Arc<RwLock<PublicKeyTable>>, // to verify the commit certificates attached to replies
+    // (time_stamp, client_id) -> requests sent without an accepted result, oldest first.
+    // Replies to requests that are not in here (accepted, given up or never sent) are ignored.
+    pending_requests: Arc<Mutex<BTreeMap<(u64, u32), PendingRequest>>>,
+    pub(crate) commit_certificates: Arc<Mutex<Vec<CommitCertificate>>>, // verified proofs of ordering, kept for audits
+    busy_until: Arc<Mutex<Option<Instant>>>, // the primary has asked to retry until then, see `BusyMsg`
+    primary_queue_depth: Arc<Mutex<Option<u32>>>, // last reported by the primary
+    transport: Arc<dyn Transport>,
+    inbound: Arc<Mutex<Option<Inbound>>>, // taken over by the client once started
+    listener: Option<HttpListener>, // routes of an HTTP transport, served next to the user API
+    api_address: String, // url:port of the user API
+    external: Option<ExternalClient>,
     handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl Client {
-    pub(crate) fn new(n: u32) -> Self {
-        let mut server_table = HashMap::new();
-        let base_port = 8000;
-        for i in 0..n {
-            server_table.insert(i, "127.0.0.1:".to_string() + &(base_port + i).to_string());
-        }
+    pub(crate) fn new(
+        client_config: &ClientConfig,
+        n: u32,
+        public_keys: PublicKeyTable,
+        transport: Arc<dyn Transport>,
+        inbound: Inbound,
+        listener: Option<HttpListener>,
+        external: Option<ExternalClient>,
+    ) -> Self {
         Self {
+            id: client_config.id,
             n,
-            server_table,
-            reply_msgs: Arc::new(Mutex::new(vec![])),
+            public_keys: Arc::new(RwLock::new(public_keys)),
+            pending_requests: Arc::new(Mutex::new(BTreeMap::new())),
+            commit_certificates: Arc::new(Mutex::new(vec![])),
+            busy_until: Arc::new(Mutex::new(None)),
+            primary_queue_depth: Arc::new(Mutex::new(None)),
+            transport,
+            inbound: Arc::new(Mutex::new(Some(inbound))),
+            listener,
+            api_address: client_config.api_address.clone(),
+            external,
             handle: Arc::new(Mutex::new(None)),
+            shutdown: Arc::new(watch::Sender::new(false)),
         }
     }
 
@@ -36,83 +91,317 @@ impl Client {
         let client_data_clone = client_data.clone();
         let handle = thread::spawn(move || {
             if let Err(e) = start_client(client_data_clone) {
-                eprintln!("Client failed to start on port {}: {}", 9000, e);
+                eprintln!("Client failed to start: {}", e);
             }
         });
         let mut handle_lock = self.handle.lock().unwrap();
         *handle_lock = Some(handle);
     }
+
+    // Asks the client to stop: new requests are refused, and the client waits up to `DRAIN_TIMEOUT`
+    // for the results of the requests it has sent before closing its sockets. `join` waits until it has stopped.
+    pub(crate) fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub(crate) fn join(&self) {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            handle.join().unwrap();
+        }
+    }
+
+    // Requests sent to the replicas without an accepted result yet
+    fn outstanding(&self) -> usize {
+        self.pending_requests.lock().unwrap().len()
+    }
+
+    // Waits for the replies to `request_msg`
+    fn track(&self, request_msg: &RequestMsg) {
+        let mut pending_requests = self.pending_requests.lock().unwrap();
+        pending_requests.insert((request_msg.time_stamp, request_msg.client_id), PendingRequest {
+            digest: compute_digest(request_msg),
+            replies: HashMap::new(),
+        });
+        if pending_requests.len() > MAX_PENDING_REQUESTS {
+            if let Some(((time_stamp, client_id), _)) = pending_requests.pop_first() {
+                eprintln!("[💻 Client] Giving up on request {} of client {}, {} requests are outstanding", time_stamp, client_id, MAX_PENDING_REQUESTS);
+            }
+        }
+    }
 }
 
+// API for users: the request is multicast to all replicas.
+// While the primary is overloaded, new requests are refused with 503 and a Retry-After header.
 #[post("/req")]
 async fn client_handle_req(request_msg: web::Json<RequestMsg>, client_data: Data<Client>) -> impl Responder {
     println!("[💻 Client] Received RequestMsg: {:?}", request_msg);
-    let server_table = &client_data.server_table;
-    let client = reqwest::Client::new();
-
-    // Collect all the requests into a vector of futures
-    let requests: Vec<_> = server_table.iter().map(|(id, server_address)| {
-        let request_msg_clone = request_msg.clone();
-        let client_clone = client.clone();
-        let server_address_clone = server_address.clone();
-        let id_clone = *id;
-
-        // Return a future representing the request
-        async move {
-            println!("[💻 Client] Sending request to server {}: {}", id_clone, server_address_clone);
-            match client_clone.post(&format!("http://{}/req", server_address_clone))
-                .json(&request_msg_clone)
-                .send()
-                .await
-            {
-                Ok(response) => {
-                    // Handle the response here if needed
-                    println!("  -- Response from server {}: {:?}", id_clone, response.status());
-                }
-                Err(e) => {
-                    eprintln!(" -- Error sending request to node {}: {}", id_clone, e);
-                }
-            }
-        }
-    }).collect();
-
-    // Run all requests concurrently
-    join_all(requests).await;
+    if *client_data.shutdown.borrow() {
+        return HttpResponse::ServiceUnavailable().json(json!({"status": "shutting down"}));
+    }
+    let busy_until = *client_data.busy_until.lock().unwrap();
+    if let Some(retry_after) = busy_until.and_then(|busy_until| busy_until.checked_duration_since(Instant::now())) {
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()))
+            .json(json!({"status": "busy", "retry_after_ms": retry_after.as_millis() as u64}));
+    }
+    let mut request_msg = request_msg.into_inner();
+    if let Some(external) = &client_data.external {
+        // the replicas only know this client from its requests, the replies go to its id at this address
+        request_msg.client_id = client_data.id;
+        request_msg.reply_address = Some(external.reply_address.clone());
+        request_msg.client_key = Some(external.key_pair.public_key().to_hex());
+        request_msg.client_signature = Some(external.key_pair.sign(&request_msg.canonical_bytes()));
+    }
+    client_data.track(&request_msg);
+    println!("[💻 Client] Multicasting request to all replicas");
+    client_data.transport.broadcast(Message::Request(request_msg));
 
     HttpResponse::Ok().json(json!({"status": "client ok"}))
 }
 
-#[post("/reply")]
-async fn client_handle_reply(reply_msg: web::Json<ReplyMsg>, client_data: Data<Client>) -> impl Responder {
+// API for users: what the client knows about the load of the cluster
+#[get("/status")]
+async fn client_status(client_data: Data<Client>) -> impl Responder {
+    let busy = client_data.busy_until.lock().unwrap().is_some_and(|busy_until| busy_until > Instant::now());
+    HttpResponse::Ok().json(json!({
+        "busy": busy,
+        "primary_queue_depth": *client_data.primary_queue_depth.lock().unwrap(),
+    }))
+}
+
+// Handles the messages the replicas send to the client
+async fn run_client(client_data: Arc<Client>, mut inbound: Inbound) {
+    while let Some(msg) = inbound.recv().await {
+        match msg {
+            Message::Reply(reply_msg) => handle_reply(&client_data, reply_msg),
+            Message::Busy(busy_msg) => handle_busy(&client_data, busy_msg),
+            Message::NewKey(new_key_msg) => handle_new_key(&client_data, new_key_msg),
+            msg => eprintln!("[💻 Client] Ignoring unexpected message: {:?}", msg),
+        }
+    }
+}
+
+fn handle_reply(client_data: &Client, reply_msg: ReplyMsg) {
     println!("[💻 Client] Received ReplyMsg: {:?}", reply_msg);
     let n = client_data.n;
-    let reply_msg = reply_msg.into_inner();
-    client_data.reply_msgs.lock().unwrap().push(reply_msg.clone());
-    let f = (n - 1) / 3;
-    let mut cnt = 0;
-    for reply in client_data.reply_msgs.lock().unwrap().iter() {
-        if reply.time_stamp == reply_msg.time_stamp && reply.view_id == reply_msg.view_id && reply.result == reply_msg.result {
-            cnt += 1;
+    let request_key = (reply_msg.time_stamp, reply_msg.client_id);
+    if let Some(queue_depth) = reply_msg.queue_depth {
+        *client_data.primary_queue_depth.lock().unwrap() = Some(queue_depth);
+    }
+    let mut pending_requests = client_data.pending_requests.lock().unwrap();
+    let Some(pending_request) = pending_requests.get_mut(&request_key) else {
+        return;
+    };
+    let f = max_faulty(n);
+    // A valid commit certificate proves that the request was ordered, it is kept once per request.
+    // The votes do not cover the result, so a faulty replica could attach a valid certificate to a forged one.
+    if let Some(certificate) = &reply_msg.certificate {
+        if certificate.view_id == reply_msg.view_id
+            && verify_commit_certificate(certificate, &pending_request.digest, &client_data.public_keys.read().unwrap(), f as usize) {
+            let mut commit_certificates = client_data.commit_certificates.lock().unwrap();
+            if !commit_certificates.iter().any(|known| known.digest == certificate.digest) {
+                commit_certificates.push(certificate.clone());
+            }
+        } else {
+            eprintln!("[💻 Client] Invalid commit certificate in reply from node {}", reply_msg.node_id);
         }
     }
-    if cnt == 2 * f + 1 {
+    // Paper 4.1: the result is accepted once f+1 distinct replicas have replied with it,
+    // at least one of them is correct
+    if pending_request.replies.contains_key(&reply_msg.node_id) {
+        return; // every replica is counted once
+    }
+    pending_request.replies.insert(reply_msg.node_id, reply_msg.clone());
+    let cnt = pending_request.replies.values()
+        .filter(|reply| reply.view_id == reply_msg.view_id && reply.result == reply_msg.result)
+        .count();
+    if cnt == f as usize + 1 {
+        pending_requests.remove(&request_key);
         println!("✅  Client received f+1 identical replies, consensus reached: {}", reply_msg.result);
     }
-    HttpResponse::Ok().json(json!({"status": "client ok"}))
+}
+
+// The primary has refused the request, it is sent to the primary again once the retry delay has passed.
+// Only the primary may slow the client down, and only for a request the client waits for.
+fn handle_busy(client_data: &Arc<Client>, busy_msg: BusyMsg) {
+    if busy_msg.node_id != PRIMARY_NODE_ID
+        || !client_data.public_keys.read().unwrap().verify(busy_msg.node_id, &busy_msg.signing_bytes(), &busy_msg.signature) {
+        eprintln!("[💻 Client] Rejected BusyMsg of node {}: not signed by the primary", busy_msg.node_id);
+        return;
+    }
+    let request_key = (busy_msg.request_msg.time_stamp, busy_msg.request_msg.client_id);
+    let digest = compute_digest(&busy_msg.request_msg);
+    let is_pending = client_data.pending_requests.lock().unwrap().get(&request_key)
+        .is_some_and(|pending_request| pending_request.digest == digest);
+    if !is_pending {
+        eprintln!("[💻 Client] Rejected BusyMsg of node {}: the client does not wait for its request", busy_msg.node_id);
+        return;
+    }
+    let retry_after = Duration::from_millis(busy_msg.retry_after_ms);
+    println!("🚧[💻 Client] Primary node {} is busy ({} requests pending), retrying in {:?}",
+             busy_msg.node_id, busy_msg.queue_depth, retry_after);
+    *client_data.primary_queue_depth.lock().unwrap() = Some(busy_msg.queue_depth);
+    {
+        let mut busy_until = client_data.busy_until.lock().unwrap();
+        let retry_at = Instant::now() + retry_after;
+        if busy_until.is_none_or(|busy_until| busy_until < retry_at) {
+            *busy_until = Some(retry_at);
+        }
+    }
+    let client_data = client_data.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(retry_after).await;
+        client_data.transport.send(Endpoint::Replica(busy_msg.node_id), Message::Request(busy_msg.request_msg));
+    });
+}
+
+// Key refresh of a recovering replica, the client needs its new key to verify commit certificates
+fn handle_new_key(client_data: &Client, new_key_msg: NewKeyMsg) {
+    if apply_new_key(&mut client_data.public_keys.write().unwrap(), &new_key_msg) {
+        println!("🔑[💻 Client] Installed new key of node {} (epoch {})", new_key_msg.node_id, new_key_msg.epoch);
+    } else {
+        eprintln!("[💻 Client] Rejected NewKeyMsg of node {}", new_key_msg.node_id);
+    }
 }
 
 fn start_client(client_data: Arc<Client>) -> io::Result<()> {
+    let inbound = client_data.inbound.lock().unwrap().take()
+        .ok_or_else(|| io::Error::other("client already started"))?;
+    // the client accepts replies from the replicas, and requests from users holding the client certificate
+    let tls_config = match client_data.listener.as_ref().and_then(|listener| listener.tls.as_ref()) {
+        Some(tls) => {
+            let mut allowed_peers: Vec<String> = (0..client_data.n).map(TlsConfig::node_identity).collect();
+            allowed_peers.push(TlsConfig::client_identity());
+            Some(tls.server_config(&TlsConfig::client_identity(), allowed_peers)?)
+        }
+        None => None,
+    };
     actix_web::rt::System::new().block_on(async move {
+        client_data.transport.start(Arc::new(|_| false))?; // requests are retransmitted until acknowledged
+        actix_web::rt::spawn(run_client(client_data.clone(), inbound));
+        // replies of an HTTP transport are received next to the API, on their own address if it differs
+        let mut addresses = vec![client_data.api_address.clone()];
+        if let Some(listener) = client_data.listener.as_ref().filter(|listener| listener.address != client_data.api_address) {
+            addresses.push(listener.address.clone());
+        }
+        let api_address = client_data.api_address.clone();
+        let app_data = client_data.clone();
         let client_server = HttpServer::new(move || {
+            let listener = app_data.listener.clone();
             App::new()
-                .app_data(Data::from(client_data.clone()))
+                .app_data(Data::from(app_data.clone()))
+                .app_data(web::JsonConfig::default().limit(MAX_FRAME_LEN as usize)) // operations as large as the transports carry
                 .service(client_handle_req)
-                .service(client_handle_reply)
-        })
-            .bind(("127.0.0.1", 9000))?; // client server runs on port 9000
+                .service(client_status)
+                .configure(move |cfg| {
+                    if let Some(listener) = listener {
+                        listener.transport.configure_inbound(cfg, CLIENT_PATHS);
+                    }
+                })
+        });
+        let mut client_server = client_server;
+        for address in &addresses {
+            client_server = match (address.strip_prefix("unix:"), tls_config.clone()) {
+                (Some(path), _) => {
+                    prepare_socket(Path::new(path))?;
+                    client_server.bind_uds(path)?
+                }
+                (None, Some(tls_config)) => client_server.bind_rustls_0_23(address, tls_config)?,
+                (None, None) => client_server.bind(address)?,
+            };
+        }
 
-        println!("Client started on port {}", 9000);
+        println!("Client started on {}", api_address);
 
-        client_server.run().await
+        let client_server = client_server.run();
+        let server_handle = client_server.handle();
+        actix_web::rt::spawn(client_server);
+        let _ = client_data.shutdown.subscribe().wait_for(|shutdown| *shutdown).await;
+        println!("🛑[💻 Client] Shutting down, {} requests outstanding", client_data.outstanding());
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while client_data.outstanding() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        server_handle.stop(true).await;
+        flush(client_data.transport.as_ref()).await;
+        client_data.transport.stop();
+        for path in addresses.iter().filter_map(|address| address.strip_prefix("unix:")) {
+            let _ = std::fs::remove_file(path);
+        }
+        println!("🛑[💻 Client] Stopped");
+        Ok(())
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transport::memory::MemoryNetwork;
+
+    const N: u32 = 4; // f = 1
+
+    fn client() -> Client {
+        let client_config = ClientConfig { id: 0, address: String::new(), api_address: String::new() };
+        let (transport, inbound) = MemoryNetwork::default().join(Endpoint::Client(0));
+        Client::new(&client_config, N, PublicKeyTable::default(), Arc::new(transport), inbound, None, None)
+    }
+
+    fn request(time_stamp: u64) -> RequestMsg {
+        RequestMsg {
+            operation: "op".to_string(),
+            time_stamp,
+            client_id: 0,
+            sequence_id: 0,
+            digest: String::new(),
+            reply_address: None,
+            client_key: None,
+            client_signature: None,
+        }
+    }
+
+    fn reply(node_id: u32, time_stamp: u64, result: &str) -> ReplyMsg {
+        ReplyMsg {
+            time_stamp,
+            view_id: 9999,
+            node_id,
+            client_id: 0,
+            result: result.to_string(),
+            certificate: None,
+            queue_depth: None,
+        }
+    }
+
+    #[test]
+    fn a_request_is_forgotten_once_f_plus_one_replicas_agree() {
+        let client = client();
+        client.track(&request(1));
+        handle_reply(&client, reply(0, 1, "op"));
+        handle_reply(&client, reply(0, 1, "op")); // counted once
+        handle_reply(&client, reply(1, 1, "forged"));
+        assert_eq!(client.outstanding(), 1);
+        handle_reply(&client, reply(2, 1, "op"));
+        assert_eq!(client.outstanding(), 0);
+        // late replies are not kept
+        handle_reply(&client, reply(3, 1, "op"));
+        assert!(client.pending_requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replies_to_requests_that_were_not_sent_are_ignored() {
+        let client = client();
+        handle_reply(&client, reply(0, 1, "op"));
+        handle_reply(&client, reply(1, 1, "op"));
+        assert_eq!(client.outstanding(), 0);
+    }
+
+    #[test]
+    fn the_oldest_requests_are_given_up_beyond_the_limit() {
+        let client = client();
+        for time_stamp in 0..MAX_PENDING_REQUESTS as u64 + 2 {
+            client.track(&request(time_stamp));
+        }
+        let pending_requests = client.pending_requests.lock().unwrap();
+        assert_eq!(pending_requests.len(), MAX_PENDING_REQUESTS);
+        assert_eq!(pending_requests.keys().next(), Some(&(2, 0)));
+    }
+}
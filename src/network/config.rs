@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+use serde::{Deserialize, Serialize};
+use crate::consensus::crypto::{AuthScheme, KeyPair, PublicKey, PublicKeyTable};
+use crate::consensus::pbft::max_faulty;
+use crate::network::node::{Admission, Dissemination, Heartbeats, RateLimit};
+use crate::network::recovery::RecoverySchedule;
+
+// Cluster configuration, read from a TOML file:
+//
+//   f = 1
+//   auth = "ed25519"             # or "bls"
+//   transport = "http"           # or "tcp", "unix", "grpc", "quic", "memory"
+//   tls_dir = "certs"            # optional, enables mutual TLS (always on with quic)
+//
+//   [protocol]
+//   recovery_period_secs = 60    # optional, enables proactive recovery
+//   state_request_timeout_ms = 1000
+//   pipeline_window = 16         # pre-prepares the primary keeps in flight
+//   pending_queue_capacity = 256 # requests waiting at the primary while the pipeline is full, beyond that clients are asked to retry
+//   busy_retry_after_ms = 100
+//   client_rate_limit = 50.0     # optional, requests per second each client may send to a replica
+//   client_burst = 10            # requests a client may send at once
+//   heartbeat_interval_ms = 1000 # 0 disables heartbeats
+//   peer_timeout_ms = 3000       # a replica not heard from for this long is down
+//   compression_threshold = 4096 # optional, messages larger than this many bytes are sent compressed with zstd
+//   tree_fanout = 8              # optional, pre-prepares and votes are relayed along a tree instead of multicast directly
+//
+//   [[replicas]]
+//   id = 0
+//   address = "127.0.0.1:8000"    # a socket path with the unix transport, e.g. "sockets/node0.sock"
+//   key_path = "keys/node0.key"  # hex encoded secret key
+//   public_key = "..."           # hex encoded public key
+//   proof_of_possession = "..."  # with BLS keys, hex encoded signature of the key over itself
+//   recovery_key_path = "keys/node0.recovery.key"  # long-term ed25519 key signing refreshed keys, with proactive recovery
+//   recovery_public_key = "..."
+//
+//   [[clients]]
+//   id = 0
+//   address = "127.0.0.1:9000"      # where the client receives replies
+//   api_address = "127.0.0.1:9000"  # where users send requests to the client, or "unix:<socket path>"
+//
+// Relative paths are resolved against the directory of the file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterConfig {
+    pub f: u32,
+    #[serde(default = "default_auth")]
+    pub auth: String,
+    #[serde(default = "default_transport")]
+    pub transport: String,
+    #[serde(default)]
+    pub tls_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub protocol: ProtocolConfig,
+    pub replicas: Vec<ReplicaConfig>,
+    pub clients: Vec<ClientConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProtocolConfig {
+    #[serde(default)]
+    pub recovery_period_secs: Option<u64>, // proactive recovery is disabled if None
+    #[serde(default = "default_state_request_timeout_ms")]
+    pub state_request_timeout_ms: u64, // how long a recovering replica waits for the committed logs of the others
+    #[serde(default = "default_pipeline_window")]
+    pub pipeline_window: usize,
+    #[serde(default = "default_pending_queue_capacity")]
+    pub pending_queue_capacity: usize,
+    #[serde(default = "default_busy_retry_after_ms")]
+    pub busy_retry_after_ms: u64,
+    #[serde(default)]
+    pub client_rate_limit: Option<f64>, // clients are not rate limited if None
+    #[serde(default = "default_client_burst")]
+    pub client_burst: u32,
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u64, // heartbeats are disabled if 0
+    #[serde(default = "default_peer_timeout_ms")]
+    pub peer_timeout_ms: u64,
+    #[serde(default)]
+    pub compression_threshold: Option<usize>, // messages are never compressed if None, on a peer that accepts it otherwise
+    #[serde(default)]
+    pub tree_fanout: Option<usize>, // replicas multicast directly if None
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicaConfig {
+    pub id: u32,
+    pub address: String, // url:port, or a socket path with the unix transport
+    #[serde(default)]
+    pub key_path: Option<PathBuf>, // a fresh key is generated if None, only possible when running all replicas in one process
+    #[serde(default)]
+    pub public_key: Option<String>, // read from `key_path` if None
+    #[serde(default)]
+    pub proof_of_possession: Option<String>, // required with BLS keys, see `KeyPair::prove_possession`
+    #[serde(default)]
+    pub recovery_key_path: Option<PathBuf>, // like `key_path`, for the long-term key that signs refreshed keys
+    #[serde(default)]
+    pub recovery_public_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientConfig {
+    pub id: u32,
+    pub address: String, // url:port of the transport (a socket path with the unix transport), replicas send replies here
+    pub api_address: String, // url:port of the HTTP API for users, may be the same as `address` for the http transport, or "unix:<socket path>"
+}
+
+fn default_auth() -> String {
+    "ed25519".to_string()
+}
+
+fn default_transport() -> String {
+    "http".to_string()
+}
+
+fn default_state_request_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_pipeline_window() -> usize {
+    Admission::default().pipeline_window
+}
+
+fn default_pending_queue_capacity() -> usize {
+    Admission::default().pending_capacity
+}
+
+fn default_busy_retry_after_ms() -> u64 {
+    Admission::default().retry_after.as_millis() as u64
+}
+
+fn default_client_burst() -> u32 {
+    10
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    Heartbeats::default().interval.as_millis() as u64
+}
+
+fn default_peer_timeout_ms() -> u64 {
+    Heartbeats::default().peer_timeout.as_millis() as u64
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            recovery_period_secs: None,
+            state_request_timeout_ms: default_state_request_timeout_ms(),
+            pipeline_window: default_pipeline_window(),
+            pending_queue_capacity: default_pending_queue_capacity(),
+            busy_retry_after_ms: default_busy_retry_after_ms(),
+            client_rate_limit: None,
+            client_burst: default_client_burst(),
+            heartbeat_interval_ms: default_heartbeat_interval_ms(),
+            peer_timeout_ms: default_peer_timeout_ms(),
+            compression_threshold: None,
+            tree_fanout: None,
+        }
+    }
+}
+
+// Where a replica keeps the keys installed by proactive recovery, so that it restarts with them:
+// its own refreshed key, and the refreshed keys of the others with their epochs, so that it neither signs with a key
+// the others have replaced nor accepts the replayed announcement of a key that has been replaced since.
+// They are kept next to the key file, in "<key file>.refreshed.toml", written at once so a crash never leaves half of it.
+// The key file itself keeps the key of the configuration.
+#[derive(Clone, Debug)]
+pub(crate) struct KeyStore {
+    replica_id: u32,
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RefreshedKeys {
+    #[serde(default)]
+    secret_key: Option<String>, // hex encoded, the current key of this replica once it has refreshed it
+    #[serde(default)]
+    keys: Vec<RefreshedKey>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RefreshedKey {
+    node_id: u32,
+    epoch: u64,
+    public_key: String, // hex encoded
+}
+
+impl KeyStore {
+    pub(crate) fn new(replica_id: u32, key_path: &Path) -> Self {
+        let mut path = key_path.as_os_str().to_owned();
+        path.push(".refreshed.toml");
+        Self { replica_id, path: PathBuf::from(path) }
+    }
+
+    // Installs the refreshed keys in `public_keys`, and returns the refreshed key of the replica if it has one.
+    pub(crate) fn load(&self, scheme: AuthScheme, public_keys: &mut PublicKeyTable) -> io::Result<Option<KeyPair>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let refreshed: RefreshedKeys = toml::from_str(&contents).map_err(invalid_input)?;
+        for key in refreshed.keys {
+            let public_key = PublicKey::from_hex(&key.public_key)
+                .ok_or_else(|| invalid_input(format!("invalid refreshed key of replica {} in {}", key.node_id, self.path.display())))?;
+            public_keys.restore(key.node_id, key.epoch, public_key);
+        }
+        let own_key = public_keys.refreshed()
+            .find(|(node_id, _, _)| *node_id == self.replica_id)
+            .map(|(_, _, public_key)| public_key.to_hex());
+        let key_pair = match refreshed.secret_key {
+            Some(secret_key) => KeyPair::from_secret_hex(scheme, &secret_key)
+                .ok_or_else(|| invalid_input(format!("invalid refreshed key in {}", self.path.display())))?,
+            None if own_key.is_none() => return Ok(None),
+            None => return Err(invalid_input(format!("no secret key for the refreshed key of replica {} in {}", self.replica_id, self.path.display()))),
+        };
+        if own_key != Some(key_pair.public_key().to_hex()) {
+            return Err(invalid_input(format!("refreshed key in {} does not match the key recorded for replica {}", self.path.display(), self.replica_id)));
+        }
+        Ok(Some(key_pair))
+    }
+
+    pub(crate) fn save(&self, key_pair: &KeyPair, public_keys: &PublicKeyTable) -> io::Result<()> {
+        let has_refreshed = public_keys.refreshed().any(|(node_id, _, _)| node_id == self.replica_id);
+        let refreshed = RefreshedKeys {
+            secret_key: has_refreshed.then(|| key_pair.to_secret_hex()),
+            keys: public_keys.refreshed()
+                .map(|(node_id, epoch, public_key)| RefreshedKey { node_id, epoch, public_key: public_key.to_hex() })
+                .collect(),
+        };
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, toml::to_string(&refreshed).map_err(invalid_input)?)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn read_key_pair(scheme: AuthScheme, key_path: Option<&Path>, public_key: Option<&str>, replica_id: u32) -> io::Result<KeyPair> {
+    let Some(key_path) = key_path else {
+        return Ok(KeyPair::generate(scheme));
+    };
+    let key_pair = KeyPair::from_secret_hex(scheme, &fs::read_to_string(key_path)?)
+        .ok_or_else(|| invalid_input(format!("invalid {:?} key in {}", scheme, key_path.display())))?;
+    if public_key.is_some_and(|public_key| public_key != key_pair.public_key().to_hex()) {
+        return Err(invalid_input(format!("key in {} does not match the public key of replica {}", key_path.display(), replica_id)));
+    }
+    Ok(key_pair)
+}
+
+// The key of a client that is not in the configuration, see `ExternalClient`. A fresh ed25519 key is saved
+// to `key_path` the first time, so that the client keeps its key when it restarts.
+pub(crate) fn load_client_key(key_path: &Path) -> io::Result<KeyPair> {
+    if !key_path.exists() {
+        let key_pair = KeyPair::generate(AuthScheme::Ed25519);
+        fs::write(key_path, key_pair.to_secret_hex())?;
+        return Ok(key_pair);
+    }
+    KeyPair::from_secret_hex(AuthScheme::Ed25519, &fs::read_to_string(key_path)?)
+        .ok_or_else(|| invalid_input(format!("invalid ed25519 key in {}", key_path.display())))
+}
+
+fn parse_public_key(public_key: Option<&str>, what: &str, replica_id: u32) -> io::Result<PublicKey> {
+    let public_key = public_key.ok_or_else(|| invalid_input(format!("no {} for replica {}, see --init-config", what, replica_id)))?;
+    PublicKey::from_hex(public_key).ok_or_else(|| invalid_input(format!("invalid {} for replica {}", what, replica_id)))
+}
+
+// Where `ClusterConfig::local` puts the sockets of the unix transport, removed once the cluster has stopped
+pub(crate) fn local_socket_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("pbft-{}", std::process::id()))
+}
+
+// Whether the host of `address` (host:port) is this machine, e.g. "localhost:8000" or "[::1]:8000"
+fn is_loopback(address: &str) -> bool {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn invalid_input(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+impl ClusterConfig {
+    // The configuration used without a file: n replicas on ports 8000+i and one client with its API on port 9000.
+    // With the unix transport every node, and the client API, listens on a socket in a directory of this process instead,
+    // so that several local clusters can run at once.
+    pub fn local(n: u32, f: u32, auth: &str, transport: &str, tls_dir: Option<PathBuf>, recovery_period: Option<Duration>) -> Self {
+        // an HTTP transport shares the port of the client API, other transports listen next to it
+        let client_address = if transport == "http" { "127.0.0.1:9000" } else { "127.0.0.1:9001" };
+        let mut config = Self {
+            f,
+            auth: auth.to_string(),
+            transport: transport.to_string(),
+            tls_dir,
+            protocol: ProtocolConfig {
+                recovery_period_secs: recovery_period.map(|period| period.as_secs()),
+                ..ProtocolConfig::default()
+            },
+            replicas: (0..n)
+                .map(|i| ReplicaConfig {
+                    id: i,
+                    address: format!("localhost:{}", 8000 + i),
+                    key_path: None,
+                    public_key: None,
+                    proof_of_possession: None,
+                    recovery_key_path: None,
+                    recovery_public_key: None,
+                })
+                .collect(),
+            clients: vec![ClientConfig {
+                id: 0,
+                address: client_address.to_string(),
+                api_address: "127.0.0.1:9000".to_string(),
+            }],
+        };
+        if transport == "unix" {
+            config.place_sockets(&local_socket_dir());
+        }
+        config
+    }
+
+    // Puts the sockets of all nodes of the unix transport, and of the client APIs, into `dir`.
+    fn place_sockets(&mut self, dir: &Path) {
+        for replica in self.replicas.iter_mut() {
+            replica.address = dir.join(format!("node{}.sock", replica.id)).display().to_string();
+        }
+        for client in self.clients.iter_mut() {
+            client.address = dir.join(format!("client{}.sock", client.id)).display().to_string();
+            client.api_address = format!("unix:{}", dir.join(format!("client{}-api.sock", client.id)).display());
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut config: ClusterConfig = toml::from_str(&fs::read_to_string(path)?).map_err(invalid_input)?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        config.tls_dir = config.tls_dir.map(|tls_dir| dir.join(tls_dir));
+        for replica in config.replicas.iter_mut() {
+            replica.key_path = replica.key_path.take().map(|key_path| dir.join(key_path));
+            replica.recovery_key_path = replica.recovery_key_path.take().map(|key_path| dir.join(key_path));
+        }
+        // socket paths are relative to the file as well
+        let unix = config.transport == "unix";
+        for replica in config.replicas.iter_mut().filter(|_| unix) {
+            replica.address = dir.join(&replica.address).display().to_string();
+        }
+        for client in config.clients.iter_mut() {
+            if unix {
+                client.address = dir.join(&client.address).display().to_string();
+            }
+            if let Some(path) = client.api_address.strip_prefix("unix:") {
+                client.api_address = format!("unix:{}", dir.join(path).display());
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Generates a key and a recovery key for every replica, writes them next to `path` and saves the configuration to `path`.
+    pub fn init(&mut self, path: &Path) -> io::Result<()> {
+        self.validate()?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir.join("keys"))?;
+        let scheme = self.auth_scheme()?;
+        if self.transport == "unix" {
+            // next to the file rather than in the directory of this process
+            self.place_sockets(Path::new("sockets"));
+        }
+        for replica in self.replicas.iter_mut() {
+            let key_pair = KeyPair::generate(scheme);
+            let key_path = PathBuf::from("keys").join(format!("node{}.key", replica.id));
+            fs::write(dir.join(&key_path), key_pair.to_secret_hex())?;
+            replica.key_path = Some(key_path);
+            replica.public_key = Some(key_pair.public_key().to_hex());
+            replica.proof_of_possession = Some(key_pair.prove_possession()).filter(|proof| !proof.is_empty());
+            let recovery_key = KeyPair::generate(AuthScheme::Ed25519);
+            let recovery_key_path = PathBuf::from("keys").join(format!("node{}.recovery.key", replica.id));
+            fs::write(dir.join(&recovery_key_path), recovery_key.to_secret_hex())?;
+            replica.recovery_key_path = Some(recovery_key_path);
+            replica.recovery_public_key = Some(recovery_key.public_key().to_hex());
+        }
+        fs::write(path, toml::to_string(self).map_err(invalid_input)?)
+    }
+
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        self.auth_scheme()?;
+        if !["http", "tcp", "unix", "grpc", "quic", "memory"].contains(&self.transport.as_str()) {
+            return Err(invalid_input(format!("unknown transport: {}", self.transport)));
+        }
+        if self.tls_dir.is_some() && !["http", "quic"].contains(&self.transport.as_str()) {
+            return Err(invalid_input("TLS is only supported by the http and quic transports"));
+        }
+        if self.tls_dir.is_some() && self.clients.iter().any(|client| client.api_address.starts_with("unix:")) {
+            return Err(invalid_input("TLS is not supported for client APIs on Unix sockets"));
+        }
+        // tcp and grpc carry messages in the clear, so their nodes may only reach each other on this host
+        if ["tcp", "grpc"].contains(&self.transport.as_str()) {
+            let addresses = self.replicas.iter().map(|replica| &replica.address).chain(self.clients.iter().map(|client| &client.address));
+            if let Some(address) = addresses.into_iter().find(|address| !is_loopback(address)) {
+                return Err(invalid_input(format!("the {} transport is not encrypted, its nodes must listen on loopback addresses, not {} (use http or quic with tls_dir across hosts)", self.transport, address)));
+            }
+        }
+        // the quorums are derived from n, so the configured f must be the one they tolerate (n >= 3f+1)
+        if self.f != max_faulty(self.n()) {
+            return Err(invalid_input(format!("{} replicas tolerate f = {} faulty ones, not f = {} (n >= 3f+1)", self.n(), max_faulty(self.n()), self.f)));
+        }
+        let mut ids: Vec<u32> = self.replicas.iter().map(|replica| replica.id).collect();
+        ids.sort();
+        if ids != (0..self.n()).collect::<Vec<u32>>() {
+            return Err(invalid_input("replica ids must be 0..n-1"));
+        }
+        if self.protocol.pipeline_window == 0 {
+            return Err(invalid_input("the pipeline window must hold at least one request"));
+        }
+        if self.protocol.client_rate_limit.is_some_and(|requests_per_sec| requests_per_sec <= 0.0 || !requests_per_sec.is_finite()) {
+            return Err(invalid_input("the client rate limit must be a positive number of requests per second"));
+        }
+        if self.protocol.client_rate_limit.is_some() && self.protocol.client_burst == 0 {
+            return Err(invalid_input("the client burst must allow at least one request"));
+        }
+        if self.protocol.tree_fanout == Some(0) {
+            return Err(invalid_input("the tree fan-out must be at least one replica"));
+        }
+        if self.protocol.heartbeat_interval_ms > 0 && self.protocol.peer_timeout_ms <= self.protocol.heartbeat_interval_ms {
+            return Err(invalid_input("the peer timeout must be longer than the heartbeat interval"));
+        }
+        if self.clients.is_empty() {
+            return Err(invalid_input("at least one client is required"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn n(&self) -> u32 {
+        self.replicas.len() as u32
+    }
+
+    pub(crate) fn auth_scheme(&self) -> io::Result<AuthScheme> {
+        self.auth.parse().map_err(invalid_input)
+    }
+
+    pub(crate) fn recovery_period(&self) -> Option<Duration> {
+        self.protocol.recovery_period_secs.map(Duration::from_secs)
+    }
+
+    pub(crate) fn recovery_schedule(&self) -> Option<RecoverySchedule> {
+        self.recovery_period().map(|period| RecoverySchedule { n: self.n(), period })
+    }
+
+    pub(crate) fn state_request_timeout(&self) -> Duration {
+        Duration::from_millis(self.protocol.state_request_timeout_ms)
+    }
+
+    pub(crate) fn admission(&self) -> Admission {
+        Admission {
+            pipeline_window: self.protocol.pipeline_window,
+            pending_capacity: self.protocol.pending_queue_capacity,
+            retry_after: Duration::from_millis(self.protocol.busy_retry_after_ms),
+            client_rate_limit: self.protocol.client_rate_limit.map(|requests_per_sec| RateLimit {
+                requests_per_sec,
+                burst: self.protocol.client_burst,
+            }),
+        }
+    }
+
+    pub(crate) fn heartbeats(&self) -> Heartbeats {
+        Heartbeats {
+            interval: Duration::from_millis(self.protocol.heartbeat_interval_ms),
+            peer_timeout: Duration::from_millis(self.protocol.peer_timeout_ms),
+        }
+    }
+
+    pub(crate) fn dissemination(&self) -> Dissemination {
+        match self.protocol.tree_fanout {
+            Some(fanout) => Dissemination::Tree { fanout },
+            None => Dissemination::Direct,
+        }
+    }
+
+    // Node.id -> url:port
+    pub(crate) fn replica_addresses(&self) -> HashMap<u32, String> {
+        self.replicas.iter().map(|replica| (replica.id, replica.address.clone())).collect()
+    }
+
+    // client id -> url:port
+    pub(crate) fn client_addresses(&self) -> HashMap<u32, String> {
+        self.clients.iter().map(|client| (client.id, client.address.clone())).collect()
+    }
+
+    // Reads the key of `replica`, or generates one if it has no key file.
+    pub(crate) fn key_pair(&self, replica: &ReplicaConfig) -> io::Result<KeyPair> {
+        read_key_pair(self.auth_scheme()?, replica.key_path.as_deref(), replica.public_key.as_deref(), replica.id)
+    }
+
+    // Reads the recovery key of `replica`, or generates one if it has no recovery key file.
+    // Recovery keys are ed25519 keys whatever the authentication scheme of the votes, they only sign key refreshes.
+    pub(crate) fn recovery_key_pair(&self, replica: &ReplicaConfig) -> io::Result<KeyPair> {
+        if replica.key_path.is_some() && replica.recovery_key_path.is_none() && self.recovery_period().is_some() {
+            return Err(invalid_input(format!("no recovery key file for replica {}, see --init-config", replica.id)));
+        }
+        read_key_pair(AuthScheme::Ed25519, replica.recovery_key_path.as_deref(), replica.recovery_public_key.as_deref(), replica.id)
+    }
+
+    // Public keys of the replicas as listed in the configuration,
+    // needed when the replicas run as separate processes and only know their own key.
+    // With proactive recovery, their recovery keys are needed as well to install their refreshed keys.
+    pub(crate) fn public_keys(&self) -> io::Result<PublicKeyTable> {
+        let mut public_keys = PublicKeyTable::default();
+        for replica in &self.replicas {
+            let public_key = parse_public_key(replica.public_key.as_deref(), "public key", replica.id)?;
+            if !public_key.verify_possession(replica.proof_of_possession.as_deref().unwrap_or_default()) {
+                return Err(invalid_input(format!("no valid proof of possession of the key of replica {}, see --init-config", replica.id)));
+            }
+            public_keys.insert(replica.id, public_key);
+        }
+        if let Some(schedule) = self.recovery_schedule() {
+            for replica in &self.replicas {
+                let recovery_key = parse_public_key(replica.recovery_public_key.as_deref(), "recovery public key", replica.id)?;
+                public_keys.insert_recovery_key(replica.id, recovery_key);
+            }
+            public_keys.set_recovery_schedule(schedule);
+        }
+        Ok(public_keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refreshed_keys_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("pbft-key-store-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key_store = KeyStore::new(0, &dir.join("node0.key"));
+        let schedule = RecoverySchedule { n: 4, period: Duration::from_secs(60) };
+        let mut public_keys = PublicKeyTable::default();
+        public_keys.set_recovery_schedule(schedule);
+        let configured_key = KeyPair::generate(AuthScheme::Ed25519);
+        public_keys.insert(0, configured_key.public_key());
+
+        // the key of another replica, this replica has not refreshed its own yet
+        let epoch = schedule.next_recovery(1).1 - 4;
+        assert!(public_keys.refresh(1, epoch, KeyPair::generate(AuthScheme::Ed25519).public_key()));
+        key_store.save(&configured_key, &public_keys).unwrap();
+        let mut restored = PublicKeyTable::default();
+        assert!(key_store.load(AuthScheme::Ed25519, &mut restored).unwrap().is_none());
+
+        let refreshed_key = KeyPair::generate(AuthScheme::Ed25519);
+        let own_epoch = schedule.next_recovery(0).1 - 4;
+        assert!(public_keys.refresh(0, own_epoch, refreshed_key.public_key()));
+        key_store.save(&refreshed_key, &public_keys).unwrap();
+        let mut restored = PublicKeyTable::default();
+        restored.set_recovery_schedule(schedule);
+        let key_pair = key_store.load(AuthScheme::Ed25519, &mut restored).unwrap().unwrap();
+        assert_eq!(key_pair.public_key().to_hex(), refreshed_key.public_key().to_hex());
+        // a replayed announcement of the epoch before is still refused
+        assert!(!restored.refresh(1, epoch, KeyPair::generate(AuthScheme::Ed25519).public_key()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_file_is_read_with_defaults_and_paths_relative_to_it() {
+        let dir = std::env::temp_dir().join(format!("pbft-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cluster.toml");
+        let mut file = String::from("f = 1\ntransport = \"unix\"\n[protocol]\npipeline_window = 4\n");
+        for id in 0..4 {
+            file += &format!("[[replicas]]\nid = {}\naddress = \"sockets/node{}.sock\"\nkey_path = \"keys/node{}.key\"\n", id, id, id);
+        }
+        file += "[[clients]]\nid = 0\naddress = \"sockets/client0.sock\"\napi_address = \"unix:sockets/api.sock\"\n";
+        fs::write(&path, file).unwrap();
+
+        let config = ClusterConfig::load(&path).unwrap();
+        assert_eq!(config.auth, "ed25519");
+        assert_eq!(config.protocol.pipeline_window, 4);
+        assert_eq!(config.protocol.pending_queue_capacity, Admission::default().pending_capacity);
+        assert_eq!(config.replicas[2].address, dir.join("sockets/node2.sock").display().to_string());
+        assert_eq!(config.replicas[2].key_path, Some(dir.join("keys/node2.key")));
+        assert_eq!(config.clients[0].api_address, format!("unix:{}", dir.join("sockets/api.sock").display()));
+
+        fs::write(&path, "f = 1\nreplicas = []\nclients = []\n[protocol]\nunknown = 1\npipeline_window = \"4\"\n").unwrap();
+        assert_eq!(ClusterConfig::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_configurations_are_refused() {
+        let valid = ClusterConfig::local(7, 2, "ed25519", "http", None, None);
+        assert!(valid.validate().is_ok());
+        let invalid: Vec<fn(&mut ClusterConfig)> = vec![
+            |config| config.f = 1, // 7 replicas tolerate 2 faulty ones
+            |config| config.f = 3,
+            |config| config.replicas.truncate(6), // 6 replicas only tolerate 1
+            |config| config.replicas[6].id = 7,
+            |config| config.auth = "rsa".to_string(),
+            |config| config.transport = "smoke signals".to_string(),
+            |config| {
+                config.transport = "grpc".to_string();
+                config.tls_dir = Some(PathBuf::from("certs"));
+            },
+            |config| config.protocol.pipeline_window = 0,
+            |config| config.protocol.client_rate_limit = Some(f64::NAN),
+            |config| config.protocol.tree_fanout = Some(0),
+            |config| config.protocol.peer_timeout_ms = config.protocol.heartbeat_interval_ms,
+            |config| config.clients.clear(),
+        ];
+        for (i, invalidate) in invalid.into_iter().enumerate() {
+            let mut config = valid.clone();
+            invalidate(&mut config);
+            assert_eq!(config.validate().map_err(|e| e.kind()), Err(io::ErrorKind::InvalidInput), "configuration {}", i);
+        }
+    }
+
+    #[test]
+    fn unencrypted_transports_stay_on_this_host() {
+        let mut config = ClusterConfig::local(4, 1, "ed25519", "tcp", None, None);
+        config.replicas[1].address = "[::1]:8001".to_string();
+        assert!(config.validate().is_ok());
+        config.replicas[2].address = "10.0.0.2:8002".to_string();
+        assert_eq!(config.validate().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        config.transport = "grpc".to_string();
+        assert_eq!(config.validate().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        config.transport = "http".to_string();
+        assert!(config.validate().is_ok());
+    }
+}
@@ -1,25 +1,448 @@
+use std::collections::BTreeMap;
 use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
+use crate::network::config::{load_client_key, local_socket_dir, ClientConfig, ClusterConfig, KeyStore, ReplicaConfig};
+use crate::network::node::{Node, PeerStatus};
 use crate::network::server::Server;
-use crate::network::client::Client;
+use crate::network::client::{Client, ExternalClient};
+use crate::network::tls::TlsConfig;
+use crate::consensus::crypto::PublicKeyTable;
+use crate::network::transport::grpc::GrpcTransport;
+use crate::network::transport::http::{HttpListener, HttpTransport};
+use crate::network::transport::memory::MemoryNetwork;
+use crate::network::transport::quic::QuicTransport;
+use crate::network::transport::tcp::TcpTransport;
+use crate::network::transport::unix::UnixTransport;
+use crate::network::transport::{Endpoint, Inbound, Transport};
 
-pub fn launch(n: u32, f: u32) -> io::Result<()> {
-    let mut servers = Vec::new();
-    for i in 0..n {
-        let mut is_faulty = false;
-        if i >= n - f {
-            is_faulty = true;
+// Runs every replica and client of `config` in this process, until Ctrl-C or SIGTERM stops them.
+pub fn launch(config: &ClusterConfig, shared_runtime: bool) -> io::Result<()> {
+    config.validate()?; // a configuration from the command line has not been checked yet
+    let cluster = LocalCluster::start(config, shared_runtime)?;
+    wait_for_signal()?;
+    cluster.shutdown();
+    Ok(())
+}
+
+// A whole cluster in this process, see `launch`. Replicas can be stopped and started again one at a time.
+// The last f replicas are faulty, they do not take part in the protocol.
+// Every replica gets its own thread and runtime, unless `shared_runtime` is set:
+// then all replicas are tasks of one multi-threaded runtime, which lets a laptop simulate large clusters.
+// Replicas of the http transport each need their own HTTP server, so they cannot share a runtime.
+pub struct LocalCluster {
+    config: ClusterConfig,
+    tls: Option<TlsConfig>,
+    memory_network: MemoryNetwork,
+    servers: BTreeMap<u32, Server>, // Node.id -> server
+    clients: Vec<Client>,
+    runtime: Option<Runtime>, // shared by the replicas
+}
+
+impl LocalCluster {
+    pub fn start(config: &ClusterConfig, shared_runtime: bool) -> io::Result<Self> {
+        if shared_runtime && config.transport == "http" {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the shared runtime does not support the http transport"));
+        }
+        let n = config.n();
+        let f = config.f;
+        let tls = match load_tls(config)? {
+            // all nodes are in this process, so they can share a CA that only exists for this run
+            None if config.transport == "quic" => Some(TlsConfig::generate(
+                config.replicas.iter().map(|replica| TlsConfig::node_identity(replica.id)).chain([TlsConfig::client_identity()]),
+            )?),
+            tls => tls,
+        };
+        let mut key_pairs = Vec::new();
+        let mut public_keys = PublicKeyTable::default();
+        for replica in &config.replicas {
+            let key_pair = config.key_pair(replica)?;
+            let recovery_key = config.recovery_key_pair(replica)?;
+            public_keys.insert(replica.id, key_pair.public_key());
+            public_keys.insert_recovery_key(replica.id, recovery_key.public_key());
+            key_pairs.push((replica, key_pair, recovery_key));
+        }
+        if let Some(schedule) = config.recovery_schedule() {
+            public_keys.set_recovery_schedule(schedule);
+        }
+        let memory_network = MemoryNetwork::default();
+
+        let mut servers = BTreeMap::new();
+        for (replica, key_pair, recovery_key) in key_pairs {
+            let is_faulty = replica.id >= n - f;
+            let mut node = Node::new(replica.id, config.replica_addresses(), is_faulty, key_pair, public_keys.clone());
+            node.recovery_key = Some(recovery_key);
+            restore_refreshed_keys(config, replica, &mut node)?;
+            node.admission = config.admission();
+            node.liveness.heartbeats = config.heartbeats();
+            node.dissemination = config.dissemination();
+            let (transport, inbound, listener) = connect(config, &tls, &memory_network, Endpoint::Replica(replica.id), &replica.address)?;
+            servers.insert(replica.id, server(config, node, transport, inbound, listener));
+        }
+        let mut clients = Vec::new();
+        for client_config in &config.clients {
+            let (transport, inbound, listener) = connect(config, &tls, &memory_network, Endpoint::Client(client_config.id), &client_config.address)?;
+            clients.push(Client::new(client_config, n, public_keys.clone(), transport, inbound, listener, None));
+        }
+        let runtime = match shared_runtime {
+            true => Some(tokio::runtime::Builder::new_multi_thread().enable_all().build()?),
+            false => None,
+        };
+        let mut cluster = Self {
+            config: config.clone(),
+            tls,
+            memory_network,
+            servers,
+            clients,
+            runtime,
+        };
+        // start only once every node has joined the transport
+        for server in cluster.servers.values_mut() {
+            match &cluster.runtime {
+                Some(runtime) => {
+                    let _guard = runtime.enter();
+                    server.spawn()?;
+                }
+                None => server.start(),
+            }
+        }
+        if cluster.runtime.is_some() {
+            // the replicas keep running on the worker threads of the runtime while the clients run
+            println!("Started {} replicas on a shared runtime", cluster.servers.len());
         }
-        let port = 8000 + i;
-        let mut server = Server::new(i, port as u16, n, is_faulty);
-        server.start();
-        servers.push(server);
+        for client in cluster.clients.iter_mut() {
+            client.start();
+        }
+        Ok(cluster)
     }
-    let mut client = Client::new(n);
-    client.start();
-    // wait for all server threads (servers will run indefinitely)
-    for server in servers {
+
+    // Stops replica `id`, once the instances it has in flight have committed (see `run_replica`),
+    // and closes its sockets. The other nodes keep retransmitting the messages it misses.
+    pub fn stop_replica(&mut self, id: u32) -> io::Result<()> {
+        let server = self.servers.get(&id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("replica {} is not in the cluster", id)))?;
+        server.shutdown();
         server.join();
+        Ok(())
+    }
+
+    // Starts replica `id` again after `stop_replica`, with the state it had when it stopped.
+    // Nothing is kept on disk, so this is only possible within the same process.
+    pub fn restart_replica(&mut self, id: u32) -> io::Result<()> {
+        let replica = self.config.replicas.iter().find(|replica| replica.id == id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("replica {} is not in the cluster", id)))?;
+        let node = self.servers[&id].take_node()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("replica {} is still running", id)))?;
+        let (transport, inbound, listener) = connect(&self.config, &self.tls, &self.memory_network, Endpoint::Replica(id), &replica.address)?;
+        let mut server = server(&self.config, node, transport, inbound, listener);
+        match &self.runtime {
+            Some(runtime) => {
+                let _guard = runtime.enter();
+                server.spawn()?;
+            }
+            None => server.start(),
+        }
+        println!("Restarted replica {}", id);
+        self.servers.insert(id, server);
+        Ok(())
+    }
+
+    // Whether replica `id` has heard from each of the other replicas lately, see `liveness`.
+    pub fn peer_status(&self, id: u32) -> Option<BTreeMap<u32, PeerStatus>> {
+        self.servers.get(&id).map(|server| server.peer_status())
     }
 
+    // Stops every node: clients refuse new requests, replicas finish the instances in flight, then all sockets are closed.
+    pub fn shutdown(self) {
+        println!("🛑 Shutting down the cluster");
+        for client in &self.clients {
+            client.shutdown();
+        }
+        for server in self.servers.values() {
+            server.shutdown();
+        }
+        for client in &self.clients {
+            client.join();
+        }
+        for server in self.servers.values() {
+            server.join();
+        }
+        // the sockets of a cluster started without a configuration file, see `ClusterConfig::local`
+        let socket_dir = local_socket_dir();
+        if self.config.transport == "unix" && self.config.replicas.iter().all(|replica| Path::new(&replica.address).starts_with(&socket_dir)) {
+            if let Err(e) = std::fs::remove_dir_all(&socket_dir) {
+                eprintln!(" -- Error removing {}: {}", socket_dir.display(), e);
+            }
+        }
+    }
+}
+
+// Runs the replica `id` of `config` in this process, the other nodes run in their own processes.
+// Its key is read from its key file, the keys of the other replicas from the configuration.
+pub fn launch_replica(config: &ClusterConfig, id: u32, is_faulty: bool) -> io::Result<()> {
+    let replica = config.replicas.iter().find(|replica| replica.id == id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("replica {} is not in the configuration", id)))?;
+    if replica.key_path.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no key file for replica {}, see --init-config", id)));
+    }
+    let tls = load_tls(config)?;
+    let mut node = Node::new(id, config.replica_addresses(), is_faulty, config.key_pair(replica)?, config.public_keys()?);
+    if config.recovery_period().is_some() {
+        node.recovery_key = Some(config.recovery_key_pair(replica)?);
+    }
+    restore_refreshed_keys(config, replica, &mut node)?;
+    node.admission = config.admission();
+    node.liveness.heartbeats = config.heartbeats();
+    node.dissemination = config.dissemination();
+    let (transport, inbound, listener) = connect(config, &tls, &separate_processes(config)?, Endpoint::Replica(id), &replica.address)?;
+    let mut server = server(config, node, transport, inbound, listener);
+    server.start();
+    wait_for_signal()?;
+    server.shutdown();
+    server.join();
+
     Ok(())
-}
\ No newline at end of file
+}
+
+// Runs the client `id` of `config` in this process, the replicas run in their own processes.
+pub fn launch_client(config: &ClusterConfig, id: u32) -> io::Result<()> {
+    let client_config = config.clients.iter().find(|client| client.id == id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("client {} is not in the configuration", id)))?;
+    run_client(config, client_config.clone(), None)
+}
+
+// Runs a client that is not in `config`, the replicas learn where to send its replies from its requests.
+// Its id must not be used by another client, the replicas bind an id to the key in `key_path` it is first used with.
+pub fn launch_external_client(config: &ClusterConfig, client_config: ClientConfig, key_path: &Path) -> io::Result<()> {
+    if config.clients.iter().any(|client| client.id == client_config.id) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("client {} is in the configuration already", client_config.id)));
+    }
+    let external = ExternalClient { reply_address: client_config.address.clone(), key_pair: load_client_key(key_path)? };
+    let mut config = config.clone();
+    config.clients.push(client_config.clone());
+    run_client(&config, client_config, Some(external))
+}
+
+fn run_client(config: &ClusterConfig, client_config: ClientConfig, external: Option<ExternalClient>) -> io::Result<()> {
+    let tls = load_tls(config)?;
+    let (transport, inbound, listener) = connect(config, &tls, &separate_processes(config)?, Endpoint::Client(client_config.id), &client_config.address)?;
+    let mut client = Client::new(&client_config, config.n(), config.public_keys()?, transport, inbound, listener, external);
+    client.start();
+    wait_for_signal()?;
+    client.shutdown();
+    client.join();
+
+    Ok(())
+}
+
+// Waits for Ctrl-C, or for SIGTERM as sent by process managers.
+fn wait_for_signal() -> io::Result<()> {
+    tokio::runtime::Builder::new_current_thread().enable_all().build()?.block_on(async {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    })
+}
+
+// Replicas with a key file save the keys refreshed by proactive recovery next to it (see `KeyStore`),
+// a restarted replica uses them instead of the keys of the configuration.
+fn restore_refreshed_keys(config: &ClusterConfig, replica: &ReplicaConfig, node: &mut Node) -> io::Result<()> {
+    let Some(key_path) = &replica.key_path else {
+        return Ok(());
+    };
+    let key_store = KeyStore::new(replica.id, key_path);
+    if let Some(key_pair) = key_store.load(config.auth_scheme()?, &mut node.public_keys)? {
+        node.key_pair = key_pair;
+    }
+    node.key_store = Some(key_store);
+    Ok(())
+}
+
+fn load_tls(config: &ClusterConfig) -> io::Result<Option<TlsConfig>> {
+    match &config.tls_dir {
+        Some(dir) => Ok(Some(TlsConfig::from_dir(dir)?)),
+        None => Ok(None),
+    }
+}
+
+// Nodes in separate processes cannot share an in-memory network, nor generated certificates.
+fn separate_processes(config: &ClusterConfig) -> io::Result<MemoryNetwork> {
+    if config.transport == "memory" {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the memory transport only works when all nodes run in one process"));
+    }
+    if config.transport == "quic" && config.tls_dir.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the quic transport needs a tls_dir when nodes run in separate processes"));
+    }
+    Ok(MemoryNetwork::default())
+}
+
+fn server(config: &ClusterConfig, node: Node, transport: Arc<dyn Transport>, inbound: Inbound, listener: Option<HttpListener>) -> Server {
+    let mut server = Server::new(node, transport, inbound, listener);
+    if let Some(period) = config.recovery_period() {
+        server.enable_proactive_recovery(period, config.state_request_timeout());
+    }
+    server
+}
+
+// Gives a node its own transport, HTTP transports also need a listener to receive messages
+fn connect(
+    config: &ClusterConfig,
+    tls: &Option<TlsConfig>,
+    memory_network: &MemoryNetwork,
+    endpoint: Endpoint,
+    address: &str,
+) -> io::Result<(Arc<dyn Transport>, Inbound, Option<HttpListener>)> {
+    let replicas = config.replica_addresses();
+    let clients = config.client_addresses();
+    let compress_above = config.protocol.compression_threshold;
+    match config.transport.as_str() {
+        "http" => {
+            let (transport, inbound) = HttpTransport::new(endpoint, replicas, clients, tls, compress_above)?;
+            let transport = Arc::new(transport);
+            let listener = HttpListener { address: address.to_string(), transport: transport.clone(), tls: tls.clone() };
+            Ok((transport, inbound, Some(listener)))
+        }
+        "tcp" => {
+            let (transport, inbound) = TcpTransport::new(endpoint, replicas, clients, compress_above)?;
+            Ok((Arc::new(transport), inbound, None))
+        }
+        "unix" => {
+            let (transport, inbound) = UnixTransport::new(endpoint, replicas, clients, compress_above)?;
+            Ok((Arc::new(transport), inbound, None))
+        }
+        "grpc" => {
+            let (transport, inbound) = GrpcTransport::new(endpoint, replicas, clients, compress_above)?;
+            Ok((Arc::new(transport), inbound, None))
+        }
+        "quic" => {
+            let tls = tls.clone().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the quic transport needs TLS certificates"))?;
+            let (transport, inbound) = QuicTransport::new(endpoint, replicas, clients, tls, compress_above)?;
+            Ok((Arc::new(transport), inbound, None))
+        }
+        "memory" => {
+            let (transport, inbound) = memory_network.join(endpoint);
+            Ok((Arc::new(transport), inbound, None))
+        }
+        transport => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown transport: {}", transport))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+    use crate::consensus::message::{Message, RequestMsg};
+
+    // Replicas that replied to the request of time stamp `time_stamp`, each counted once, until `expected` of them have arrived or a few seconds have passed.
+    fn replies(inbound: &mut Inbound, time_stamp: u64, expected: usize) -> Vec<u32> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut replied = Vec::new();
+        while replied.len() < expected && Instant::now() < deadline {
+            match inbound.try_recv() {
+                Ok(Message::Reply(reply_msg)) if reply_msg.time_stamp == time_stamp && !replied.contains(&reply_msg.node_id) => {
+                    replied.push(reply_msg.node_id)
+                }
+                Ok(_) => {}
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        replied.sort();
+        replied
+    }
+
+    fn request(time_stamp: u64) -> Message {
+        Message::Request(RequestMsg {
+            operation: format!("op{}", time_stamp),
+            time_stamp,
+            client_id: 0,
+            sequence_id: 0,
+            digest: String::new(),
+            reply_address: None,
+            client_key: None,
+            client_signature: None,
+        })
+    }
+
+    #[test]
+    fn separate_processes_verify_each_other_with_the_keys_of_the_configuration() {
+        let dir = std::env::temp_dir().join(format!("pbft-separate-processes-{}", std::process::id()));
+        let path = dir.join("cluster.toml");
+        ClusterConfig::local(4, 1, "ed25519", "memory", None, None).init(&path).unwrap();
+        let config = ClusterConfig::load(&path).unwrap();
+        let public_keys = config.public_keys().unwrap();
+        for replica in &config.replicas {
+            // the key each replica reads from its own file
+            let signature = config.key_pair(replica).unwrap().sign(b"vote");
+            assert!(public_keys.verify(replica.id, b"vote", &signature));
+            assert!(!public_keys.verify((replica.id + 1) % 4, b"vote", &signature));
+        }
+
+        let kind = |result: io::Result<()>| result.unwrap_err().kind();
+        assert_eq!(kind(launch_replica(&config, 4, false)), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(launch_client(&config, 1)), io::ErrorKind::InvalidInput);
+        // nodes of separate processes cannot share the memory network
+        assert_eq!(kind(launch_replica(&config, 0, false)), io::ErrorKind::InvalidInput);
+        let external = ClientConfig { id: 0, address: "127.0.0.1:9101".to_string(), api_address: "127.0.0.1:9100".to_string() };
+        assert_eq!(kind(launch_external_client(&config, external, &dir.join("client0.key"))), io::ErrorKind::InvalidInput);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_shared_runtime_runs_a_large_cluster() {
+        let config = ClusterConfig::local(4, 1, "ed25519", "http", None, None);
+        assert_eq!(LocalCluster::start(&config, true).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        // n = 16 with f = 5: the 11 correct replicas commit, as tasks of one runtime
+        let mut config = ClusterConfig::local(16, 5, "ed25519", "memory", None, None);
+        config.clients.clear();
+        let cluster = LocalCluster::start(&config, true).unwrap();
+        assert!(cluster.runtime.is_some());
+        let (client, mut inbound) = cluster.memory_network.join(Endpoint::Client(0));
+        client.broadcast(request(1));
+        assert_eq!(replies(&mut inbound, 1, 11), (0..11).collect::<Vec<u32>>());
+        cluster.shutdown();
+    }
+
+    #[test]
+    fn the_sockets_of_a_local_cluster_are_removed_on_shutdown() {
+        let config = ClusterConfig::local(4, 1, "ed25519", "unix", None, None);
+        let cluster = LocalCluster::start(&config, true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !Path::new(&config.replicas[3].address).exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(local_socket_dir().exists());
+        cluster.shutdown();
+        assert!(!local_socket_dir().exists());
+    }
+
+    #[test]
+    fn stopped_replicas_restart_and_take_part_again() {
+        // n = 5 with f = 1: replica 4 is faulty, any three of the other four commit
+        let mut config = ClusterConfig::local(5, 1, "ed25519", "memory", None, None);
+        config.clients.clear(); // the test is the client, on the memory network of the cluster
+        let mut cluster = LocalCluster::start(&config, true).unwrap();
+        let (client, mut inbound) = cluster.memory_network.join(Endpoint::Client(0));
+
+        client.broadcast(request(1));
+        assert_eq!(replies(&mut inbound, 1, 4), vec![0, 1, 2, 3]);
+
+        cluster.stop_replica(2).unwrap();
+        client.broadcast(request(2));
+        assert_eq!(replies(&mut inbound, 2, 3), vec![0, 1, 3]);
+
+        // with replica 3 stopped in turn, requests only commit if replica 2 is back
+        cluster.restart_replica(2).unwrap();
+        cluster.stop_replica(3).unwrap();
+        client.broadcast(request(3));
+        assert_eq!(replies(&mut inbound, 3, 3), vec![0, 1, 2]);
+
+        // a running replica cannot be restarted
+        assert!(cluster.restart_replica(2).is_err());
+        cluster.shutdown();
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+use crate::consensus::message::{HeartbeatMsg, Message};
+use crate::consensus::protocol::Event;
+use crate::network::node::{Node, PeerStatus};
+use crate::network::transport::Transport;
+
+// Heartbeats between replicas, so that a silent replica can be told from an idle one.
+// Every replica broadcasts a signed heartbeat every `Heartbeats::interval`. A peer counts as up while its heartbeats,
+// or its signed votes, keep arriving, and as down once none has arrived for `Heartbeats::peer_timeout`.
+//
+// A backup suspects the primary of having failed as soon as the primary is down.
+// There is no view change yet (paper 4.4), so a suspected primary is reported but stays the primary.
+//
+// Like recovery, the work happens in the event loop of the replica (see `protocol::run_replica`),
+// this timer only tells the loop when to send the next heartbeat.
+pub(crate) async fn run_heartbeat_timer(interval: Duration, events_tx: UnboundedSender<Event>) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if events_tx.send(Event::Heartbeat).is_err() {
+            return; // the replica has stopped
+        }
+    }
+}
+
+// Sends a heartbeat to the other replicas, then checks on them.
+pub(crate) fn beat(node: &mut Node, transport: &dyn Transport) {
+    let mut heartbeat_msg = HeartbeatMsg {
+        node_id: node.id,
+        view_id: node.view.id,
+        time_stamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+        signature: String::new(),
+    };
+    heartbeat_msg.signature = node.key_pair.sign(&heartbeat_msg.signing_bytes());
+    node.progress.send_modify(|progress| progress.heartbeat_time_stamp = heartbeat_msg.time_stamp);
+    transport.broadcast(Message::Heartbeat(heartbeat_msg));
+    check_peers(node);
+}
+
+pub(crate) fn handle_heartbeat(node: &mut Node, heartbeat_msg: HeartbeatMsg) {
+    if !node.public_keys.verify(heartbeat_msg.node_id, &heartbeat_msg.signing_bytes(), &heartbeat_msg.signature) {
+        eprintln!("[😃 Node{}] Rejected HeartbeatMsg of node {}: invalid signature", node.id, heartbeat_msg.node_id);
+        return;
+    }
+    if node.liveness.heartbeat(heartbeat_msg.node_id, heartbeat_msg.time_stamp)
+        && node.liveness.status.borrow().get(&heartbeat_msg.node_id) == Some(&PeerStatus::Down) {
+        // report it right away rather than on the next heartbeat of this replica
+        check_peers(node);
+    }
+}
+
+// Publishes the status of every peer, and reports the ones that went up or down since the last check.
+fn check_peers(node: &mut Node) {
+    let liveness = &node.liveness;
+    let mut changed = Vec::new();
+    liveness.status.send_if_modified(|statuses| {
+        for (id, status) in statuses.iter_mut() {
+            let new_status = liveness.status_of(*id);
+            if new_status != *status {
+                *status = new_status;
+                changed.push((*id, new_status));
+            }
+        }
+        !changed.is_empty()
+    });
+    for (id, status) in changed {
+        match status {
+            PeerStatus::Up => println!("💓[😃 Node{}] Node{} is up", node.id, id),
+            PeerStatus::Down => println!("💔[😃 Node{}] Node{} is down, not heard from for {:?}", node.id, id, liveness.silence(id)),
+        }
+    }
+
+    let primary = node.view.primary_node_id;
+    if primary == node.id {
+        return;
+    }
+    let primary_down = liveness.status_of(primary) == PeerStatus::Down;
+    if primary_down && !liveness.primary_suspected {
+        println!("🚨[😃 Node{}] Suspecting that primary Node{} of view {} has failed", node.id, primary, node.view.id);
+    } else if !primary_down && liveness.primary_suspected {
+        println!("🚨[😃 Node{}] Primary Node{} is back", node.id, primary);
+    }
+    node.liveness.primary_suspected = primary_down;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::consensus::crypto::{AuthScheme, KeyPair, PublicKeyTable};
+    use crate::network::node::PRIMARY_NODE_ID;
+    use crate::network::transport::memory::MemoryNetwork;
+    use crate::network::transport::{Endpoint, Inbound};
+
+    fn heartbeat(inbound: &mut Inbound) -> HeartbeatMsg {
+        match inbound.try_recv() {
+            Ok(Message::Heartbeat(heartbeat_msg)) => heartbeat_msg,
+            msg => panic!("expected a heartbeat, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn a_backup_suspects_a_silent_primary_until_it_beats_again() {
+        let key_pairs: Vec<KeyPair> = (0..2).map(|_| KeyPair::generate(AuthScheme::Ed25519)).collect();
+        let mut public_keys = PublicKeyTable::default();
+        for (id, key_pair) in key_pairs.iter().enumerate() {
+            public_keys.insert(id as u32, key_pair.public_key());
+        }
+        let node_table: HashMap<u32, String> = (0..2).map(|id| (id, String::new())).collect();
+        let network = MemoryNetwork::default();
+        let mut primary = Node::new(PRIMARY_NODE_ID, node_table.clone(), false, key_pairs[0].clone(), public_keys.clone());
+        let (primary_transport, _) = network.join(Endpoint::Replica(PRIMARY_NODE_ID));
+        let mut backup = Node::new(1, node_table, false, key_pairs[1].clone(), public_keys);
+        backup.liveness.heartbeats.peer_timeout = Duration::from_millis(50);
+        let (backup_transport, mut backup_inbound) = network.join(Endpoint::Replica(1));
+
+        beat(&mut primary, &primary_transport);
+        let heartbeat_msg = heartbeat(&mut backup_inbound);
+        handle_heartbeat(&mut backup, heartbeat_msg.clone());
+        assert!(backup.liveness.has_heard_from(PRIMARY_NODE_ID));
+
+        // silent for longer than the timeout: a replayed or forged heartbeat does not count
+        std::thread::sleep(Duration::from_millis(60));
+        handle_heartbeat(&mut backup, heartbeat_msg.clone());
+        let forged = HeartbeatMsg { time_stamp: heartbeat_msg.time_stamp + 1, ..heartbeat_msg };
+        handle_heartbeat(&mut backup, forged);
+        beat(&mut backup, &backup_transport);
+        assert_eq!(backup.liveness.status.borrow()[&PRIMARY_NODE_ID], PeerStatus::Down);
+        assert!(backup.liveness.primary_suspected);
+
+        // a new heartbeat clears the suspicion right away
+        std::thread::sleep(Duration::from_millis(2)); // heartbeats carry time stamps in milliseconds
+        beat(&mut primary, &primary_transport);
+        handle_heartbeat(&mut backup, heartbeat(&mut backup_inbound));
+        assert_eq!(backup.liveness.status.borrow()[&PRIMARY_NODE_ID], PeerStatus::Up);
+        assert!(!backup.liveness.primary_suspected);
+    }
+}
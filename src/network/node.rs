@@ -1,9 +1,16 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use crate::consensus;
-use crate::consensus::message::RequestMsg;
+use crate::consensus::crypto::{KeyPair, PublicKeyTable};
+use crate::consensus::message::{CommittedEntry, ReplyMsg, RequestMsg};
+use crate::network::config::KeyStore;
 
-#[derive(Clone)]
+// There are no view changes yet, the primary of every view is this replica
+pub(crate) const PRIMARY_NODE_ID: u32 = 0;
+
+// State of a replica. It is owned by the event loop of the replica (see `protocol::run_replica`),
+// which handles one message at a time, so none of it needs a lock.
 pub(crate) struct Node {
     pub(crate) id: u32,
     pub(crate) is_faulty: bool, // whether this node is faulty
@@ -11,6 +18,121 @@ pub(crate) struct Node {
     pub(crate) view: View,
     pub(crate) current_state: consensus::pbft::State, // current state of the node
     pub(crate) msg_buffer : MsgBuffer,
+    pub(crate) key_pair: KeyPair, // signs the prepare and commit votes of this node, refreshed on recovery
+    pub(crate) recovery_key: Option<KeyPair>, // long-term key that signs the refreshed keys, needed for proactive recovery
+    pub(crate) key_store: Option<KeyStore>, // where refreshed keys are saved, they are only kept in memory if None
+    pub(crate) public_keys: PublicKeyTable, // Node.id -> public key, to verify votes of other nodes
+    pub(crate) committed_log: Vec<CommittedEntry>, // requests committed by this node, in order
+    pub(crate) progress: watch::Sender<Progress>, // published for the sender tasks of the transport
+    pub(crate) admission: Admission,
+    pub(crate) pending_requests: PendingRequests, // requests the primary has accepted but not pre-prepared yet
+    pub(crate) client_buckets: HashMap<u32, TokenBucket>, // client id -> requests it may still send, see `Admission` and `MAX_CLIENT_BUCKETS`
+    pub(crate) next_sequence_id: u32, // assigned by the primary to the next pre-prepare
+    pub(crate) liveness: Liveness, // of the other replicas, see `liveness`
+    pub(crate) dissemination: Dissemination, // how pre-prepares and votes reach the other replicas
+    // Paper 4.1: replicas remember the last reply they sent to each client, and send it again for a retransmitted request.
+    pub(crate) last_replies: HashMap<u32, (String, ReplyMsg)>, // client id -> (digest of the request, reply)
+    // requests received by a backup, forwarded to the primary at their deadline unless pre-prepared by then
+    pub(crate) forward_deadlines: VecDeque<(Instant, RequestMsg)>,
+}
+
+// Admission control at the primary: at most `pipeline_window` pre-prepares are in flight (not committed yet),
+// further requests wait in a queue of at most `pending_capacity` requests (none at all if zero),
+// and requests beyond that are rejected with a `BusyMsg` asking the client to retry after `retry_after`.
+// Every replica also limits the requests of each client to `client_rate_limit`, if set.
+#[derive(Clone, Debug)]
+pub(crate) struct Admission {
+    pub(crate) pipeline_window: usize,
+    pub(crate) pending_capacity: usize,
+    pub(crate) retry_after: Duration,
+    pub(crate) client_rate_limit: Option<RateLimit>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RateLimit {
+    pub(crate) requests_per_sec: f64,
+    pub(crate) burst: u32, // requests a client may send at once after being idle
+}
+
+// Client ids are not authenticated, so a sender can claim any number of them. A replica keeps at most this many
+// token buckets: once there are as many, the buckets that have refilled are dropped (a full bucket is the same
+// as a new one), and new clients are refused until there is room. Clients that keep sending keep their buckets,
+// so a flood of made-up ids cannot reset the limits of the others.
+pub(crate) const MAX_CLIENT_BUCKETS: usize = 1024;
+
+// Token bucket of one client: it holds up to `burst` tokens, refilled at `requests_per_sec`,
+// and every new request of the client takes one.
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    // Takes a token, or returns how long it takes until the next one is available.
+    pub(crate) fn take(&mut self, limit: RateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = self.tokens_at(limit, now);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.requests_per_sec))
+    }
+
+    fn is_full(&self, limit: RateLimit, now: Instant) -> bool {
+        self.tokens_at(limit, now) >= limit.burst as f64
+    }
+
+    fn tokens_at(&self, limit: RateLimit, now: Instant) -> f64 {
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * limit.requests_per_sec;
+        (self.tokens + refill).min(limit.burst as f64)
+    }
+}
+
+// Requests accepted by the primary and not pre-prepared yet, in one queue per client.
+// They are taken round-robin across clients, so a client sending many requests cannot starve the others.
+#[derive(Default)]
+pub(crate) struct PendingRequests {
+    queues: BTreeMap<u32, VecDeque<RequestMsg>>, // client id -> its requests in arrival order, never empty
+    len: usize,
+    next_client: u32, // the client to take the next request from, or the one after it
+}
+
+impl PendingRequests {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn push(&mut self, request_msg: RequestMsg) {
+        self.queues.entry(request_msg.client_id).or_default().push_back(request_msg);
+        self.len += 1;
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<RequestMsg> {
+        let client_id = self.queues.range(self.next_client..).next()
+            .or_else(|| self.queues.iter().next())
+            .map(|(client_id, _)| *client_id)?;
+        let queue = self.queues.get_mut(&client_id)?;
+        let request_msg = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&client_id);
+        }
+        self.len -= 1;
+        self.next_client = client_id.wrapping_add(1);
+        request_msg
+    }
 }
 
 #[derive(Clone)]
@@ -19,45 +141,279 @@ pub(crate) struct View {
     pub(crate) primary_node_id: u32,
 }
 
-#[derive(Clone)]
+#[derive(Default)]
 pub(crate) struct MsgBuffer {
-    pub(crate) request_msgs: Arc<Mutex<Vec<RequestMsg>>>,
-    pub(crate) preprepare_msgs: Arc<Mutex<Vec<consensus::message::PrePrepareMsg>>>,
-    pub(crate) prepare_msgs: Arc<Mutex<Vec<consensus::message::VoteMsg>>>,
-    pub(crate) commit_msgs: Arc<Mutex<Vec<consensus::message::VoteMsg>>>,
+    pub(crate) request_msgs: Vec<RequestMsg>,
+    pub(crate) preprepare_msgs: Vec<consensus::message::PrePrepareMsg>,
+    pub(crate) prepare_msgs: Vec<consensus::message::VoteMsg>,
+    pub(crate) commit_msgs: Vec<consensus::message::VoteMsg>,
+    pub(crate) state_msgs: Vec<consensus::message::StateMsg>, // committed logs of other replicas, during recovery
 }
 
-impl Node {
-    pub fn new(id: u32, n: u32, is_faulty: bool) -> Node {
-        let mut node_table = HashMap::new();
-        let base_port = 8000;
-        for i in 0..n {
-            node_table.insert(i, "localhost:".to_string() + &(base_port + i).to_string());
+// What other tasks may know about the progress of the replica, without access to its state.
+#[derive(Clone, Default)]
+pub(crate) struct Progress {
+    pub(crate) view_id: u32,
+    pub(crate) committed: HashSet<(u32, u32)>, // (view_id, sequence_id) of committed requests
+    pub(crate) heartbeat_time_stamp: u64, // of the last heartbeat sent, older ones are not worth delivering
+}
+
+// Every replica sends a heartbeat to the others every `interval` (never if zero),
+// and considers a peer down once it has not heard from it for `peer_timeout`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Heartbeats {
+    pub(crate) interval: Duration,
+    pub(crate) peer_timeout: Duration,
+}
+
+impl Default for Heartbeats {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            peer_timeout: Duration::from_secs(3),
         }
+    }
+}
+
+// How a replica multicasts its pre-prepares and votes, see `overlay`.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) enum Dissemination {
+    #[default]
+    Direct, // to every other replica itself
+    Tree { fanout: usize }, // to `fanout` replicas, which relay them further
+}
+
+// Whether a replica has heard from a peer recently, see `Liveness`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    Up,
+    Down,
+}
+
+// When this replica last heard from each of the other replicas, through a heartbeat or a vote signed by them.
+pub(crate) struct Liveness {
+    pub(crate) heartbeats: Heartbeats,
+    last_seen: HashMap<u32, Instant>, // Node.id -> when it was last heard from
+    heartbeat_time_stamps: HashMap<u32, u64>, // Node.id -> time stamp of its newest heartbeat, to ignore replayed ones
+    since: Instant, // peers not heard from yet count from here, so that they have `peer_timeout` to start
+    pub(crate) status: watch::Sender<BTreeMap<u32, PeerStatus>>, // Node.id -> status, as of the last check
+    pub(crate) primary_suspected: bool,
+}
+
+impl Liveness {
+    pub(crate) fn new(peers: impl IntoIterator<Item = u32>) -> Self {
+        let (status, _) = watch::channel(peers.into_iter().map(|id| (id, PeerStatus::Up)).collect());
+        Self {
+            heartbeats: Heartbeats::default(),
+            last_seen: HashMap::new(),
+            heartbeat_time_stamps: HashMap::new(),
+            since: Instant::now(),
+            status,
+            primary_suspected: false,
+        }
+    }
+
+    pub(crate) fn seen(&mut self, id: u32) {
+        self.last_seen.insert(id, Instant::now());
+    }
+
+    // Records the heartbeat of `id` unless a newer one has arrived already.
+    pub(crate) fn heartbeat(&mut self, id: u32, time_stamp: u64) -> bool {
+        let newest = self.heartbeat_time_stamps.entry(id).or_default();
+        if time_stamp <= *newest {
+            return false;
+        }
+        *newest = time_stamp;
+        self.seen(id);
+        true
+    }
+
+    pub(crate) fn has_heard_from(&self, id: u32) -> bool {
+        self.last_seen.contains_key(&id)
+    }
+
+    // How long ago `id` was last heard from, or the time since this replica started if never
+    pub(crate) fn silence(&self, id: u32) -> Duration {
+        self.last_seen.get(&id).unwrap_or(&self.since).elapsed()
+    }
+
+    pub(crate) fn status_of(&self, id: u32) -> PeerStatus {
+        if self.silence(id) > self.heartbeats.peer_timeout { PeerStatus::Down } else { PeerStatus::Up }
+    }
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Self {
+            pipeline_window: 16,
+            pending_capacity: 256,
+            retry_after: Duration::from_millis(100),
+            client_rate_limit: None,
+        }
+    }
+}
+
+impl Node {
+    pub fn new(id: u32, node_table: HashMap<u32, String>, is_faulty: bool, key_pair: KeyPair, public_keys: PublicKeyTable) -> Node {
         let view = View {
             id: 9999, // initial view id
-            primary_node_id: 0, // static primary node with node_id = 0
-        };
-
-        let msg_buffer = MsgBuffer {
-            request_msgs: Arc::new(Mutex::new(Vec::new())),
-            preprepare_msgs: Arc::new(Mutex::new(Vec::new())),
-            prepare_msgs: Arc::new(Mutex::new(Vec::new())),
-            commit_msgs: Arc::new(Mutex::new(Vec::new())),
+            primary_node_id: PRIMARY_NODE_ID,
         };
 
         let current_state = consensus::pbft::State {
-            view_id: 0,
-            current_stage: Arc::new(Mutex::new(consensus::pbft::Stage::Idle)),
+            current_stage: consensus::pbft::Stage::Idle,
         };
 
+        let (progress, _) = watch::channel(Progress {
+            view_id: view.id,
+            committed: HashSet::new(),
+            heartbeat_time_stamp: 0,
+        });
+        let liveness = Liveness::new(node_table.keys().copied().filter(|peer| *peer != id));
+
         Self {
             id,
             is_faulty,
             node_table,
             view,
             current_state,
-            msg_buffer,
+            msg_buffer: MsgBuffer::default(),
+            key_pair,
+            recovery_key: None,
+            key_store: None,
+            public_keys,
+            committed_log: Vec::new(),
+            progress,
+            admission: Admission::default(),
+            pending_requests: PendingRequests::default(),
+            client_buckets: HashMap::new(),
+            next_sequence_id: 0,
+            liveness,
+            dissemination: Dissemination::Direct,
+            last_replies: HashMap::new(),
+            forward_deadlines: VecDeque::new(),
+        }
+    }
+
+    // Pre-prepares of the current view this node has sent or accepted and not committed yet.
+    pub(crate) fn in_flight(&self) -> usize {
+        let progress = self.progress.borrow();
+        self.msg_buffer.preprepare_msgs.iter()
+            .filter(|msg| msg.view_id == self.view.id && !progress.committed.contains(&(msg.view_id, msg.sequence_id)))
+            .count()
+    }
+
+    // Takes a token from the bucket of `client_id`, see `TokenBucket::take`. Always succeeds without a rate limit.
+    pub(crate) fn take_token(&mut self, client_id: u32) -> Result<(), Duration> {
+        let Some(limit) = self.admission.client_rate_limit else {
+            return Ok(());
+        };
+        if !self.client_buckets.contains_key(&client_id) && self.client_buckets.len() >= MAX_CLIENT_BUCKETS {
+            let now = Instant::now();
+            self.client_buckets.retain(|_, bucket| !bucket.is_full(limit, now));
+            if self.client_buckets.len() >= MAX_CLIENT_BUCKETS {
+                return Err(Duration::from_secs_f64(1.0 / limit.requests_per_sec));
+            }
+        }
+        self.client_buckets.entry(client_id)
+            .or_insert_with(|| TokenBucket::new(limit))
+            .take(limit)
+    }
+
+    // Appends to the committed log and publishes the new progress.
+    pub(crate) fn commit(&mut self, entry: CommittedEntry) {
+        self.progress.send_modify(|progress| {
+            progress.committed.insert((entry.view_id, entry.sequence_id));
+        });
+        self.committed_log.push(entry);
+    }
+
+    // Replaces the committed log with one repaired from other replicas, see `recovery::repair_state`.
+    pub(crate) fn replace_committed_log(&mut self, log: Vec<CommittedEntry>) {
+        self.progress.send_modify(|progress| {
+            progress.committed = log.iter().map(|entry| (entry.view_id, entry.sequence_id)).collect();
+        });
+        self.committed_log = log;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::crypto::AuthScheme;
+
+    const LIMIT: RateLimit = RateLimit { requests_per_sec: 10.0, burst: 3 };
+
+    fn request(client_id: u32, time_stamp: u64) -> RequestMsg {
+        RequestMsg { operation: String::new(), time_stamp, client_id, sequence_id: 0, digest: String::new(), reply_address: None, client_key: None, client_signature: None }
+    }
+
+    #[test]
+    fn token_buckets_allow_a_burst_and_refill_at_the_rate() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        for _ in 0..3 {
+            assert!(bucket.take(LIMIT).is_ok());
         }
+        let retry_after = bucket.take(LIMIT).unwrap_err();
+        assert!(retry_after > Duration::from_millis(90) && retry_after <= Duration::from_millis(100));
+
+        // 250ms later, two tokens have come back
+        bucket.refilled_at -= Duration::from_millis(250);
+        assert!(bucket.take(LIMIT).is_ok());
+        assert!(bucket.take(LIMIT).is_ok());
+        assert!(bucket.take(LIMIT).is_err());
+        // and never more than the burst, however long the client has been idle
+        bucket.refilled_at -= Duration::from_secs(60);
+        assert!(bucket.is_full(LIMIT, Instant::now()));
+        for _ in 0..3 {
+            assert!(bucket.take(LIMIT).is_ok());
+        }
+        assert!(bucket.take(LIMIT).is_err());
+    }
+
+    #[test]
+    fn buckets_are_bounded_without_resetting_the_limits_of_active_clients() {
+        let mut node = Node::new(0, HashMap::from([(0, String::new())]), false, KeyPair::generate(AuthScheme::Ed25519), PublicKeyTable::default());
+        node.admission.client_rate_limit = Some(LIMIT);
+        for client_id in 0..MAX_CLIENT_BUCKETS as u32 {
+            assert!(node.take_token(client_id).is_ok());
+        }
+        // client 0 has used up its burst, made-up ids cannot push its bucket out
+        assert!(node.take_token(0).is_ok());
+        assert!(node.take_token(0).is_ok());
+        assert!(node.take_token(0).is_err());
+        assert!(node.take_token(5000).is_err());
+        assert_eq!(node.client_buckets.len(), MAX_CLIENT_BUCKETS);
+
+        // once the others have refilled, their buckets make room
+        for (client_id, bucket) in node.client_buckets.iter_mut() {
+            if *client_id != 0 {
+                bucket.refilled_at -= Duration::from_secs(1);
+            }
+        }
+        assert!(node.take_token(5000).is_ok());
+        assert_eq!(node.client_buckets.len(), 2);
+        assert!(node.take_token(0).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn pending_requests_are_taken_round_robin_across_clients() {
+        let mut pending_requests = PendingRequests::default();
+        for (client_id, time_stamp) in [(1, 1), (1, 2), (1, 3), (2, 1), (5, 1), (5, 2)] {
+            pending_requests.push(request(client_id, time_stamp));
+        }
+        assert_eq!(pending_requests.len(), 6);
+        let mut taken = Vec::new();
+        while let Some(request_msg) = pending_requests.pop() {
+            taken.push((request_msg.client_id, request_msg.time_stamp));
+        }
+        assert_eq!(taken, [(1, 1), (2, 1), (5, 1), (1, 2), (5, 2), (1, 3)]);
+        assert!(pending_requests.is_empty());
+
+        // a client arriving later takes its turn after the client served last
+        pending_requests.push(request(1, 4));
+        pending_requests.push(request(3, 1));
+        assert_eq!(pending_requests.pop().unwrap().client_id, 3);
+        assert_eq!(pending_requests.pop().unwrap().client_id, 1);
+    }
+}
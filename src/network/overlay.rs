@@ -0,0 +1,174 @@
+use crate::consensus::message::Message;
+use crate::network::node::{Dissemination, Node, PeerStatus};
+use crate::network::transport::{Endpoint, Transport};
+
+// Dissemination of pre-prepares and votes along a tree, for clusters of hundreds of replicas.
+// With direct multicast the replica that multicasts sends n-1 copies itself, with `Dissemination::Tree`
+// it sends `fanout` of them, and every replica relays the messages it receives to its own children.
+// A message still reaches every replica, but the copies are spread over the replicas in the tree,
+// so that the primary does not send every pre-prepare (and the request in it) n-1 times.
+//
+// Every replica that multicasts is the root of its own tree: with the replicas numbered from the root,
+// position p = (id - root) mod n, the children of position p are the positions p * fanout + 1 ..= p * fanout + fanout.
+// A relay that has not been heard from yet, or is down (see `Liveness`), could cut its subtree off,
+// so its parent also sends to the children of such a relay. A relay that is up but drops what it should relay
+// is not detected.
+//
+// Heartbeats, requests of clients and the messages of recovery are always sent directly.
+// Heartbeats are the exception to the savings of the tree: every replica still sends n-1 of them per interval.
+// They tell which relays to bypass, so they must not depend on the tree themselves: a relayed heartbeat would
+// only show that the chain of relays to its sender is up, and a relay that is down would make its whole subtree
+// look down. They are small, and larger clusters can send them less often (`heartbeat_interval_ms`).
+
+// Multicasts a message of this replica to the other replicas.
+pub(crate) fn multicast(node: &Node, transport: &dyn Transport, msg: Message) {
+    match node.dissemination {
+        Dissemination::Direct => transport.broadcast(msg),
+        Dissemination::Tree { fanout } => send_to_subtree(node, transport, node.id, fanout, msg),
+    }
+}
+
+// Passes a message multicast by `root` on to the subtree of this replica.
+// Called once per message, once it has been accepted: pre-prepares and votes are signed by `root`,
+// so a relay can drop them but not forge or alter them.
+pub(crate) fn relay(node: &Node, transport: &dyn Transport, root: u32, msg: &Message) {
+    if let Dissemination::Tree { fanout } = node.dissemination {
+        send_to_subtree(node, transport, root, fanout, msg.clone());
+    }
+}
+
+fn send_to_subtree(node: &Node, transport: &dyn Transport, root: u32, fanout: usize, msg: Message) {
+    let n = node.node_table.len() as u32;
+    let position = (node.id + n - root % n) % n;
+    let mut targets = Vec::new();
+    let mut positions: Vec<u32> = children(position, fanout, n).collect();
+    while let Some(position) = positions.pop() {
+        let id = (root + position) % n;
+        targets.push(id);
+        if !node.liveness.has_heard_from(id) || node.liveness.status_of(id) == PeerStatus::Down {
+            positions.extend(children(position, fanout, n));
+        }
+    }
+    for id in targets {
+        transport.send(Endpoint::Replica(id), msg.clone());
+    }
+}
+
+fn children(position: u32, fanout: usize, n: u32) -> impl Iterator<Item = u32> {
+    let first = position as u64 * fanout as u64 + 1;
+    (first..first + fanout as u64).take_while(move |child| *child < n as u64).map(|child| child as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::consensus::crypto::{AuthScheme, KeyPair, PublicKeyTable};
+    use crate::consensus::message::StateRequestMsg;
+    use crate::network::transport::memory::{MemoryNetwork, MemoryTransport};
+    use crate::network::transport::Inbound;
+
+    const N: u32 = 13;
+    const FANOUT: usize = 3;
+
+    // Replicas disseminating along trees of `FANOUT`, that have heard from every replica but the ones in `down`.
+    fn replicas(down: &[u32]) -> Vec<(Node, MemoryTransport, Inbound)> {
+        let network = MemoryNetwork::default();
+        let node_table: HashMap<u32, String> = (0..N).map(|id| (id, String::new())).collect();
+        (0..N)
+            .map(|id| {
+                let mut node = Node::new(id, node_table.clone(), false, KeyPair::generate(AuthScheme::Ed25519), PublicKeyTable::default());
+                node.dissemination = Dissemination::Tree { fanout: FANOUT };
+                for peer in (0..N).filter(|peer| *peer != id && !down.contains(peer)) {
+                    node.liveness.seen(peer);
+                }
+                let (transport, inbound) = network.join(Endpoint::Replica(id));
+                (node, transport, inbound)
+            })
+            .collect()
+    }
+
+    fn msg(root: u32) -> Message {
+        Message::StateRequest(StateRequestMsg { node_id: root })
+    }
+
+    // Node.id of the replicas that have received a message
+    fn received(replicas: &mut [(Node, MemoryTransport, Inbound)]) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for (node, _, inbound) in replicas.iter_mut() {
+            while inbound.try_recv().is_ok() {
+                ids.push(node.id);
+            }
+        }
+        ids.sort();
+        ids
+    }
+
+    // Multicasts a message of `root`, and lets every replica that is up relay what it receives, until nothing moves.
+    // Returns how many copies of the message each replica has received.
+    fn disseminate(root: u32, down: &[u32]) -> Vec<usize> {
+        let mut replicas = replicas(down);
+        let (node, transport, _) = &replicas[root as usize];
+        multicast(node, transport, msg(root));
+        let mut copies = vec![0; N as usize];
+        let mut delivered = true;
+        while delivered {
+            delivered = false;
+            for (node, transport, inbound) in &mut replicas {
+                while inbound.try_recv().is_ok() {
+                    copies[node.id as usize] += 1;
+                    delivered = true;
+                    if !down.contains(&node.id) {
+                        relay(node, transport, root, &msg(root));
+                    }
+                }
+            }
+        }
+        copies
+    }
+
+    #[test]
+    fn replicas_send_to_their_children_only() {
+        let mut replicas = replicas(&[]);
+        let (node, transport, _) = &replicas[0];
+        multicast(node, transport, msg(0));
+        assert_eq!(received(&mut replicas), [1, 2, 3]);
+        let (node, transport, _) = &replicas[1];
+        relay(node, transport, 0, &msg(0));
+        assert_eq!(received(&mut replicas), [4, 5, 6]);
+        // the leaves relay to nobody
+        let (node, transport, _) = &replicas[4];
+        relay(node, transport, 0, &msg(0));
+        assert!(received(&mut replicas).is_empty());
+
+        // every replica multicasting is the root of its own tree
+        let (node, transport, _) = &replicas[11];
+        multicast(node, transport, msg(11));
+        assert_eq!(received(&mut replicas), [0, 1, 12]);
+        let (node, transport, _) = &replicas[12];
+        relay(node, transport, 11, &msg(11));
+        assert_eq!(received(&mut replicas), [2, 3, 4]);
+    }
+
+    #[test]
+    fn every_replica_receives_a_message_once() {
+        for root in [0, 5, 12] {
+            let mut copies = disseminate(root, &[]);
+            assert_eq!(copies.remove(root as usize), 0);
+            assert!(copies.iter().all(|copies| *copies == 1), "root {}: {:?}", root, copies);
+        }
+    }
+
+    #[test]
+    fn the_subtree_of_a_relay_that_is_down_is_still_reached() {
+        // replica 2 relays to 7, 8 and 9 in the tree of 0, replica 4 is a leaf
+        let copies = disseminate(0, &[2, 4]);
+        for id in 1..N {
+            assert_eq!(copies[id as usize], 1, "replica {}", id);
+        }
+        let mut replicas = replicas(&[2]);
+        let (node, transport, _) = &replicas[0];
+        multicast(node, transport, msg(0));
+        assert_eq!(received(&mut replicas), [1, 2, 3, 7, 8, 9]);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+use crate::consensus::crypto::KeyPair;
+use crate::consensus::message::{Message, NewKeyMsg, StateMsg, StateRequestMsg};
+use crate::consensus::pbft::{max_faulty, Stage};
+use crate::consensus::protocol::Event;
+use crate::network::node::{MsgBuffer, Node};
+use crate::network::transport::{Endpoint, Transport};
+use crate::network::utils::compute_state_digest;
+
+// Proactive recovery, following BFT-PR (Castro & Liskov, "Proactive Recovery in a Byzantine-Fault-Tolerant System").
+// Every replica periodically
+//   1. refreshes its key and announces the new public key, signed with its long-term recovery key,
+//   2. discards its protocol state (message buffers and stage) and restarts from its committed log,
+//   3. fetches the signed committed logs of the other replicas, and repairs its own log when f+1 of them agree on another one.
+// There is no checkpoint protocol yet, so the committed log plays the role of the last stable checkpoint.
+//
+// Replicas are split into groups of at most f that recover in turns, each during its own slot of `period / groups`.
+// Slots are aligned to the wall clock, so replicas running as separate processes agree on the schedule
+// and at most f replicas recover at the same time.
+//
+// The recovery itself runs in the event loop of the replica (see `protocol::run_replica`),
+// this timer only tells the loop when to start it.
+pub(crate) async fn run_recovery_timer(node_id: u32, schedule: RecoverySchedule, events_tx: UnboundedSender<Event>) {
+    loop {
+        let (delay, epoch) = schedule.next_recovery(node_id);
+        tokio::time::sleep(delay).await;
+        if events_tx.send(Event::Recover(epoch)).is_err() {
+            return; // the replica has stopped
+        }
+    }
+}
+
+// How far the clock of a recovering replica may be ahead of the clocks of the nodes that install its new key
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(1);
+
+// When the replicas of a cluster of `n` recover, the same on every node.
+// The number of a recovery slot is used as the key refresh epoch, it only ever increases.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RecoverySchedule {
+    pub(crate) n: u32,
+    pub(crate) period: Duration,
+}
+
+impl RecoverySchedule {
+    fn groups(&self) -> u64 {
+        self.n.div_ceil(self.group_size()) as u64
+    }
+
+    fn group_size(&self) -> u32 {
+        max_faulty(self.n).max(1)
+    }
+
+    fn group(&self, node_id: u32) -> u64 {
+        (node_id / self.group_size()) as u64
+    }
+
+    fn slot_millis(&self) -> u64 {
+        (self.period.as_millis() as u64 / self.groups()).max(1)
+    }
+
+    // Returns how long to wait until the next recovery slot of `node_id`, and the number of that slot.
+    pub(crate) fn next_recovery(&self, node_id: u32) -> (Duration, u64) {
+        let slot_millis = self.slot_millis();
+        let now_millis = now_millis();
+        let mut slot = now_millis / slot_millis + 1;
+        while slot % self.groups() != self.group(node_id) {
+            slot += 1;
+        }
+        (Duration::from_millis(slot * slot_millis - now_millis), slot)
+    }
+
+    // Whether `epoch` is a recovery slot of `node_id` that has started by the clock of this node.
+    pub(crate) fn is_valid_epoch(&self, node_id: u32, epoch: u64) -> bool {
+        let starts_at = epoch.saturating_mul(self.slot_millis());
+        epoch % self.groups() == self.group(node_id) && starts_at <= now_millis() + MAX_CLOCK_SKEW.as_millis() as u64
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// Steps 1 and 2, and the request for the committed logs of step 3.
+// The event loop calls `repair_state` once the logs have had time to arrive.
+pub(crate) fn recover(node: &mut Node, transport: &dyn Transport, epoch: u64) {
+    println!("🔄[😃 Node{}] Starting proactive recovery, epoch {}", node.id, epoch);
+    refresh_key(node, transport, epoch);
+
+    // restart the protocol state
+    node.msg_buffer = MsgBuffer::default();
+    node.current_state.current_stage = Stage::Idle;
+
+    transport.broadcast(Message::StateRequest(StateRequestMsg { node_id: node.id }));
+}
+
+// Generates a fresh key and announces it to the other replicas and the clients.
+// The announcement is signed with the long-term recovery key rather than the previous key,
+// which may be what the recovery is meant to get rid of. The new key is only used once it has been sent.
+fn refresh_key(node: &mut Node, transport: &dyn Transport, epoch: u64) {
+    let Some(recovery_key) = &node.recovery_key else {
+        eprintln!("[😃 Node{}] No recovery key, keeping the current key", node.id);
+        return;
+    };
+    let new_key_pair = KeyPair::generate(node.key_pair.scheme());
+    let public_key = new_key_pair.public_key();
+    let mut new_key_msg = NewKeyMsg {
+        node_id: node.id,
+        epoch,
+        public_key: public_key.to_hex(),
+        signature: String::new(),
+        proof_of_possession: new_key_pair.prove_possession(),
+    };
+    new_key_msg.signature = recovery_key.sign(&new_key_msg.signing_bytes());
+    let mut public_keys = node.public_keys.clone();
+    public_keys.refresh(node.id, epoch, public_key);
+    // the new key is only announced once the replica would restart with it
+    if let Some(key_store) = &node.key_store {
+        if let Err(e) = key_store.save(&new_key_pair, &public_keys) {
+            eprintln!("[😃 Node{}] -- Error saving the refreshed key, keeping the current key: {}", node.id, e);
+            return;
+        }
+    }
+
+    transport.broadcast(Message::NewKey(new_key_msg.clone()));
+    for client_id in transport.clients() {
+        transport.send(Endpoint::Client(client_id), Message::NewKey(new_key_msg.clone()));
+    }
+
+    node.public_keys = public_keys;
+    node.key_pair = new_key_pair;
+}
+
+// Saves the refreshed keys after installing the key of another replica, see `KeyStore`
+pub(crate) fn save_keys(node: &Node) {
+    if let Some(key_store) = &node.key_store {
+        if let Err(e) = key_store.save(&node.key_pair, &node.public_keys) {
+            eprintln!("[😃 Node{}] -- Error saving the refreshed keys: {}", node.id, e);
+        }
+    }
+}
+
+// Compares the committed log with the ones of the other replicas.
+// A log reported by f+1 replicas comes from at least one correct replica, it replaces the own log if they differ.
+// Only logs signed by their sender are kept (see `protocol::handle_state`), and logs are compared by their digest,
+// which does not depend on the order in which each replica has committed.
+pub(crate) fn repair_state(node: &mut Node) {
+    let f = max_faulty(node.node_table.len() as u32) as usize;
+    let state_msgs = std::mem::take(&mut node.msg_buffer.state_msgs);
+    let mut votes: HashMap<String, (usize, StateMsg)> = HashMap::new();
+    let mut senders = Vec::new();
+    for state_msg in state_msgs {
+        if senders.contains(&state_msg.node_id) {
+            continue; // one vote per replica
+        }
+        if compute_state_digest(&state_msg.log) != state_msg.state_digest {
+            eprintln!("[😃 Node{}] StateMsg from node {} does not match its digest", node.id, state_msg.node_id);
+            continue;
+        }
+        senders.push(state_msg.node_id);
+        let vote = votes.entry(state_msg.state_digest.clone()).or_insert((0, state_msg));
+        vote.0 += 1;
+    }
+    match votes.into_values().find(|(cnt, _)| *cnt > f) {
+        None => eprintln!("[😃 Node{}] No f+1 replicas agree on a state, keeping the own committed log", node.id),
+        Some((_, state_msg)) if compute_state_digest(&node.committed_log) == state_msg.state_digest => {
+            println!("[😃 Node{}] Committed log verified against f+1 replicas", node.id);
+        }
+        Some((_, mut state_msg)) => {
+            println!("[😃 Node{}] Committed log repaired from f+1 replicas", node.id);
+            state_msg.log.sort_by_key(|entry| (entry.view_id, entry.sequence_id));
+            node.replace_committed_log(state_msg.log);
+        }
+    }
+    println!("🔄[😃 Node{}] Proactive recovery finished", node.id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEDULE: RecoverySchedule = RecoverySchedule { n: 7, period: Duration::from_secs(60) };
+
+    #[test]
+    fn next_recovery_is_a_slot_of_the_group() {
+        for node_id in 0..SCHEDULE.n {
+            let (delay, epoch) = SCHEDULE.next_recovery(node_id);
+            assert!(delay <= SCHEDULE.period);
+            assert_eq!(epoch % SCHEDULE.groups(), SCHEDULE.group(node_id));
+        }
+    }
+
+    #[test]
+    fn started_slots_of_the_group_are_valid_epochs() {
+        let (_, next) = SCHEDULE.next_recovery(2);
+        let previous = next - SCHEDULE.groups();
+        assert!(SCHEDULE.is_valid_epoch(2, previous));
+        // replicas 2 and 3 are in the same group of f = 2
+        assert!(SCHEDULE.is_valid_epoch(3, previous));
+        assert!(!SCHEDULE.is_valid_epoch(4, previous));
+    }
+
+    #[test]
+    fn future_slots_are_not_valid_epochs() {
+        let (_, next) = SCHEDULE.next_recovery(0);
+        assert!(!SCHEDULE.is_valid_epoch(0, next + SCHEDULE.groups()));
+        assert!(!SCHEDULE.is_valid_epoch(0, u64::MAX - u64::MAX % SCHEDULE.groups()));
+    }
+}
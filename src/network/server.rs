@@ -1,435 +1,171 @@
+use std::collections::BTreeMap;
 use std::{io, thread};
 use std::sync::{Arc, Mutex};
 use std::thread::{JoinHandle};
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
-use actix_web::web::{Data};
-use futures::future::join_all;
-use serde_json::json;
-use crate::consensus::message::RequestMsg;
-use crate::network::node::Node;
-use crate::network::utils::compute_digest;
+use std::time::Duration;
+use actix_web::{App, HttpServer};
+use futures::future::OptionFuture;
+use tokio::sync::watch;
+use crate::consensus::protocol::{is_obsolete, run_replica};
+use crate::network::node::{Node, PeerStatus};
+use crate::network::tls::TlsConfig;
+use crate::network::transport::http::{HttpListener, REPLICA_PATHS};
+use crate::network::transport::{flush, Inbound, Transport};
 
 #[derive(Clone)]
 pub(crate) struct Server {
-    port: u16,
-    handle: Arc<Mutex<Option<JoinHandle<()>>>>,
-    pub node: Node,
+    handle: Arc<Mutex<Option<JoinHandle<()>>>>, // thread of the replica, see `start`
+    task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>, // event loop of the replica, see `spawn`
+    id: u32,
+    replica_ids: Vec<u32>,
+    node: Arc<Mutex<Option<Node>>>, // handed to the event loop of the replica once started, and back once stopped
+    transport: Arc<dyn Transport>,
+    inbound: Arc<Mutex<Option<Inbound>>>, // taken over by the replica once started
+    listener: Option<HttpListener>, // HTTP server receiving the messages of an HTTP transport
+    recovery_period: Option<Duration>, // proactive recovery is disabled if None
+    state_request_timeout: Duration, // how long a recovering replica waits for the committed logs of the others
+    shutdown: Arc<watch::Sender<bool>>,
+    peer_status: watch::Receiver<BTreeMap<u32, PeerStatus>>, // published by the replica, see `liveness`
 }
 
 impl Server {
-    pub(crate) fn new(node_id: u32, port: u16, n: u32, is_faulty: bool) -> Self {
+    pub(crate) fn new(node: Node, transport: Arc<dyn Transport>, inbound: Inbound, listener: Option<HttpListener>) -> Self {
         Self {
-            port,
             handle: Arc::new(Mutex::new(None)),
-            node: Node::new(node_id, n, is_faulty),
+            task: Arc::new(Mutex::new(None)),
+            id: node.id,
+            replica_ids: node.node_table.keys().copied().collect(),
+            peer_status: node.liveness.status.subscribe(),
+            node: Arc::new(Mutex::new(Some(node))),
+            transport,
+            inbound: Arc::new(Mutex::new(Some(inbound))),
+            listener,
+            recovery_period: None,
+            state_request_timeout: Duration::ZERO,
+            shutdown: Arc::new(watch::Sender::new(false)),
         }
     }
 
+    // Makes the replica recover proactively, see `recovery::run_recovery_timer`.
+    pub(crate) fn enable_proactive_recovery(&mut self, period: Duration, state_request_timeout: Duration) {
+        self.recovery_period = Some(period);
+        self.state_request_timeout = state_request_timeout;
+    }
+
     pub(crate) fn start(&mut self) {
-        let port = self.port;
+        let node_id = self.id;
         let server_data = Arc::new(self.clone());
         let server_data_clone = server_data.clone();
         let handle = thread::spawn(move || {
-            if let Err(e) = start_server(port, server_data_clone) {
-                eprintln!("Server failed to start on port {}: {}", port, e);
+            if let Err(e) = start_server(server_data_clone) {
+                eprintln!("Server of node {} failed to start: {}", node_id, e);
             }
         });
         let mut handle_lock = self.handle.lock().unwrap();
         *handle_lock = Some(handle);
     }
 
-    pub(crate) fn join(self) {
-        // try to acquire lock on handle
-        if let Ok(mut handle_lock) = self.handle.lock() {
-            // use `take()` method to take out the value in `Option<JoinHandle<()>>` and set it to `None`
-            if let Some(handle) = handle_lock.take() {
-                handle.join().unwrap();
-            }
-        } else {
-            eprintln!("Failed to acquire lock on handle");
-        }
+    // Starts the transport and the event loop of the replica as tasks of the current tokio runtime,
+    // without an HTTP listener. Used directly when many replicas share one runtime (see `launcher::LocalCluster`).
+    // Once the replica has stopped, its transport is stopped as well and its state is kept for `take_node`.
+    pub(crate) fn spawn(&self) -> io::Result<()> {
+        let inbound = self.inbound.lock().unwrap().take()
+            .ok_or_else(|| io::Error::other("server already started"))?;
+        let node = self.node.lock().unwrap().take()
+            .ok_or_else(|| io::Error::other("server already started"))?;
+        let progress = node.progress.subscribe();
+        self.transport.start(Arc::new(move |msg| is_obsolete(&progress, msg)))?;
+        let server_data = self.clone();
+        let task = tokio::spawn(async move {
+            let node = run_replica(
+                node,
+                server_data.transport.clone(),
+                inbound,
+                server_data.recovery_period,
+                server_data.state_request_timeout,
+                server_data.shutdown.subscribe(),
+            ).await;
+            flush(server_data.transport.as_ref()).await;
+            server_data.transport.stop();
+            *server_data.node.lock().unwrap() = Some(node);
+        });
+        *self.task.lock().unwrap() = Some(task);
+        Ok(())
     }
-}
 
-#[post("/req")]
-async fn handle_req(request_msg: web::Json<RequestMsg>, server_data: Data<Server>) -> impl Responder {
-    let response_body = json!({"status": "ok"});
-    let mut emoji = "😃";
-    if server_data.node.is_faulty {
-        emoji = "😈";
-    }
-    println!("[{} Node{}] Received RequestMsg: {:?}", emoji, server_data.node.id, request_msg);
-    if server_data.node.is_faulty {
-        return HttpResponse::Ok().json(response_body);
+    // Asks the replica to stop, see `run_replica` for what happens to the requests in flight.
+    // Returns right away, `join` waits until it has stopped.
+    pub(crate) fn shutdown(&self) {
+        self.shutdown.send_replace(true);
     }
-    // Paper 4.3, second paragraph
-    // When the primary receives a client request,
-    // it assigns a sequence number to the request
-    // and multicasts a pre-prepare message for that sequence number.
-    let mut request_msg = request_msg.into_inner();
-    request_msg.digest = compute_digest(&request_msg);
-    // println!("digest: {}", request_msg.digest);
-    server_data.node.msg_buffer.request_msgs.lock().unwrap().push(request_msg.clone());
-    {
-        let mut current_stage_guard = server_data
-            .node
-            .current_state
-            .current_stage
-            .lock()
-            .unwrap();
-        if *current_stage_guard == crate::consensus::pbft::Stage::Idle {
-            *current_stage_guard = crate::consensus::pbft::Stage::PrePrepare;
-        }
-    }
-    println!("🌟[{} Node{}] Transitioned to PrePrepare stage!", emoji, server_data.node.id);
-    if server_data.node.id == server_data.node.view.primary_node_id {
-        // primary node
-        request_msg.sequence_id = 0; // TODO: assign sequence id
-        let digest  = request_msg.digest.clone();
-        let pre_prepare_msg = crate::consensus::message::PrePrepareMsg {
-            view_id: server_data.node.view.id,
-            sequence_id: request_msg.sequence_id,
-            digest,
-            request_msg,
-        };
-        // send pre-prepare message to all nodes
-        let client = reqwest::Client::new();
-        let requests: Vec<_> = server_data.node.node_table.iter().filter_map(|(id, url)| {
-            if *id == server_data.node.id {
-                return None; // Skip self
-            }
-
-            let pre_prepare_msg_clone = pre_prepare_msg.clone();
-            let client_clone = client.clone();
-            let url_clone = url.clone();
-            let id_clone = *id;
-            let emoji_clone = emoji;
-            let server_data_clone = server_data.clone();
-
-            // Return a future representing the request
-            Some(async move {
-                println!(
-                    "[{} Node{}] Sending PrePrepareMsg to node {}: {:?}",
-                    emoji_clone, server_data_clone.node.id, id_clone, pre_prepare_msg_clone
-                );
 
-                match client_clone
-                    .post(&format!("http://{}/preprepare", url_clone))
-                    .json(&pre_prepare_msg_clone)
-                    .send()
-                    .await
-                {
-                    Ok(response) => {
-                        println!("  -- Response from server {}: {:?}", id_clone, response.status());
-                    }
-                    Err(e) => {
-                        eprintln!(" -- Error sending request to node {}: {}", id_clone, e);
-                    }
-                }
-            })
-        }).collect();
-        {
-            let mut current_stage_guard = server_data
-                .node
-                .current_state
-                .current_stage
-                .lock()
-                .unwrap();
-            if *current_stage_guard == crate::consensus::pbft::Stage::PrePrepare {
-                *current_stage_guard = crate::consensus::pbft::Stage::Prepare;
-            }
-        }
-        println!("🌟🌟[{} Primary Node{}] Transitioned to Prepare stage!", emoji, server_data.node.id);
-        // Run all requests concurrently
-        join_all(requests).await;
-        // set primary node to the next stage
-    }
-    HttpResponse::Ok().json(response_body)
-}
-#[post("/preprepare")]
-async fn handle_pre_prepare(pre_prepare_msg: web::Json<crate::consensus::message::PrePrepareMsg>, server_data: Data<Server>) -> impl Responder {
-    let response_body = json!({"status": "ok"});
-    let mut emoji = "😃";
-    if server_data.node.is_faulty {
-        emoji = "😈";
-    }
-    println!("[{} Node{}] Received PrePrepareMsg: {:?}", emoji, server_data.node.id, pre_prepare_msg);
-    if server_data.node.is_faulty {
-        return HttpResponse::Ok().json(response_body);
-    }
-    let mut request_msg = RequestMsg { // request message corresponding to the pre-prepare message
-        time_stamp: 0,
-        client_id: 0,
-        operation: String::new(),
-        sequence_id: 0,
-        digest: "".to_string(),
-    };
-    let digest = pre_prepare_msg.digest.clone();
-    for msg in server_data.node.msg_buffer.request_msgs.lock().unwrap().iter() {
-        if digest == msg.digest {
-            request_msg = msg.clone();
-            break;
-        }
-    }
-    if request_msg.digest == "" {
-        eprintln!("[{} Node{}] Request message not found for PrePrepareMsg", emoji, server_data.node.id);
-        return HttpResponse::Ok().json(response_body);
+    // Node.id -> whether the replica has heard from it lately
+    pub(crate) fn peer_status(&self) -> BTreeMap<u32, PeerStatus> {
+        self.peer_status.borrow().clone()
     }
-    let verify_result = crate::network::utils::verify_msg(&pre_prepare_msg, server_data.node.view.id, request_msg.digest.clone());
-    if !verify_result {
-        eprintln!("[{} Node{}] PrePrepareMsg verification failed", emoji, server_data.node.id);
-        HttpResponse::Ok().json(response_body)
-    } else {
-        let view_id = pre_prepare_msg.view_id;
-        let sequence_id = pre_prepare_msg.sequence_id;
-        let digest = pre_prepare_msg.digest.clone();
-        server_data.node.msg_buffer.preprepare_msgs.lock().unwrap().push(pre_prepare_msg.into_inner());
-        // If node i is accepting the pre-prepare message, it transitions to the Prepare stage
-        // by multicasting a prepare message to all other nodes
-        {
-            let mut current_stage_guard = server_data
-                .node
-                .current_state
-                .current_stage
-                .lock()
-                .unwrap();
-            if *current_stage_guard == crate::consensus::pbft::Stage::PrePrepare {
-                *current_stage_guard = crate::consensus::pbft::Stage::Prepare;
-            }
-        }
-        println!("🌟🌟[{} Node{}] Transitioned to Prepare stage!", emoji, server_data.node.id);
-
-        let prepare_msg = crate::consensus::message::VoteMsg {
-            view_id,
-            sequence_id,
-            digest,
-            node_id: server_data.node.id,
-            msg_type: crate::consensus::message::MsgType::PrepareMsg,
-        };
 
-        server_data.node.msg_buffer.prepare_msgs.lock().unwrap().push(prepare_msg.clone()); // save the prepare message
-        let client = reqwest::Client::new();
-        let requests: Vec<_> = server_data.node.node_table.iter().filter_map(|(id, url)| {
-            if *id == server_data.node.id {
-                return None; // Skip self
-            }
-
-            let prepare_msg_clone = prepare_msg.clone();
-            let client_clone = client.clone();
-            let url_clone = url.clone();
-            let id_clone = *id;
-            let emoji_clone = emoji;
-            let server_data_clone = server_data.clone();
-            // Return a future representing the request
-            Some(async move {
-                println!(
-                    "[{} Node{}] Sending PrepareMsg to node {}: {:?}",
-                    emoji_clone, server_data_clone.node.id, id_clone, prepare_msg_clone
-                );
-                match client_clone.post(&format!("http://{}/prepare", url_clone))
-                    .json(&prepare_msg_clone)
-                    .send()
-                    .await
-                {
-                    Ok(response) => {
-                        println!("  -- Response from server {}: {:?}", id_clone, response.status());
-                    }
-                    Err(e) => {
-                        eprintln!(" -- Error sending request to node {}: {}", id_clone, e);
-                    }
-                }
-            })
-        }).collect();
-
-        // Run all requests concurrently
-        join_all(requests).await;
-        HttpResponse::Ok().json(response_body)
+    // The state of the replica once it has stopped, to start a new server from it.
+    pub(crate) fn take_node(&self) -> Option<Node> {
+        self.node.lock().unwrap().take()
     }
-}
 
-#[post("/prepare")]
-async fn handle_prepare(prepare_msg: web::Json<crate::consensus::message::VoteMsg>, server_data: Data<Server>) -> impl Responder {
-    let response_body = json!({"status": "ok"});
-    let mut emoji = "😃";
-    if server_data.node.is_faulty {
-        emoji = "😈";
-    }
-    println!("[{} Node{}] Received PrepareMsg: {:?}", emoji, server_data.node.id, prepare_msg);
-    if server_data.node.is_faulty {
-        return HttpResponse::Ok().json(response_body);
-    }
-    let n = server_data.node.node_table.len();
-    let f = (n - 1) / 3;
-    let prepare_msg = prepare_msg.into_inner();
-    let mut preprepare_msg = crate::consensus::message::PrePrepareMsg {
-        view_id: prepare_msg.view_id,
-        sequence_id: prepare_msg.sequence_id,
-        digest: prepare_msg.digest.clone(),
-        request_msg: RequestMsg {
-            time_stamp: 0,
-            client_id: 0,
-            operation: String::new(),
-            sequence_id: 0,
-            digest: "".to_string(),
-        },
-    };
-    for msg in server_data.node.msg_buffer.preprepare_msgs.lock().unwrap().iter() {
-        if msg.digest == prepare_msg.digest && msg.view_id == prepare_msg.view_id {
-            preprepare_msg = msg.clone();
-            break;
+    // Waits until the replica has stopped, whether it runs on its own thread or as a task of a shared runtime.
+    pub(crate) fn join(&self) {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            handle.join().unwrap();
         }
-    }
-    if preprepare_msg.digest == "" {
-        eprintln!("[{} Node{}] PrePrepareMsg not found for PrepareMsg", emoji, server_data.node.id);
-        return HttpResponse::Ok().json(response_body);
-    } else {
-        server_data.node.msg_buffer.prepare_msgs.lock().unwrap().push(prepare_msg.clone()); // save the prepare message
-        let mut cnt = 0;
-        for msg in server_data.node.msg_buffer.prepare_msgs.lock().unwrap().iter() {
-            if msg.digest == prepare_msg.digest && msg.view_id == prepare_msg.view_id {
-                cnt += 1;
+        if let Some(task) = self.task.lock().unwrap().take() {
+            // only the runtime running the task can be blocked on, so this must not be called from it
+            if let Err(e) = futures::executor::block_on(task) {
+                eprintln!("Replica {} failed: {}", self.id, e);
             }
         }
-        if cnt == 2 * f  {
-            {
-                let mut current_stage_guard = server_data
-                    .node
-                    .current_state
-                    .current_stage
-                    .lock()
-                    .unwrap();
-                if *current_stage_guard == crate::consensus::pbft::Stage::Prepare {
-                    *current_stage_guard = crate::consensus::pbft::Stage::Commit;
-                }
-            }
-            println!(
-                "🌟🌟🌟[{} Node{}] Transitioned to Commit stage!",
-                emoji, server_data.node.id
-            );
-            let commit_msg = crate::consensus::message::VoteMsg {
-                view_id: prepare_msg.view_id,
-                sequence_id: prepare_msg.sequence_id,
-                digest: prepare_msg.digest.clone(),
-                node_id: server_data.node.id,
-                msg_type: crate::consensus::message::MsgType::CommitMsg,
-            };
-            server_data.node.msg_buffer.commit_msgs.lock().unwrap().push(commit_msg.clone());
-            let client = reqwest::Client::new();
-            let requests: Vec<_> = server_data.node.node_table.iter().filter_map(|(id, url)| {
-                if *id == server_data.node.id {
-                    return None; // Skip self
-                }
-
-                let commit_msg_clone = commit_msg.clone();
-                let client_clone = client.clone();
-                let url_clone = url.clone();
-                let id_clone = *id;
-                let emoji_clone = emoji;
-                let server_data_clone = server_data.clone();
-                // Return a future representing the request
-                Some(async move {
-                    println!(
-                        "[{} Node{}] Sending CommitMsg to node {}: {:?}",
-                        emoji_clone, server_data_clone.node.id, id_clone, commit_msg_clone
-                    );
-                    match client_clone.post(&format!("http://{}/commit", url_clone))
-                        .json(&commit_msg_clone)
-                        .send()
-                        .await
-                    {
-                        Ok(response) => {
-                            println!("  -- Response from server {}: {:?}", id_clone, response.status());
-                        }
-                        Err(e) => {
-                            eprintln!(" -- Error sending request to node {}: {}", id_clone, e);
-                        }
-                    }
-                })
-            }).collect();
-            // Run all requests concurrently
-            join_all(requests).await;
-        }
     }
-    HttpResponse::Ok().json(response_body)
-}
 
-#[post("/commit")]
-async fn handle_commit(commit_msg: web::Json<crate::consensus::message::VoteMsg>, server_data: Data<Server>) -> impl Responder {
-    let response_body = json!({"status": "ok"});
-    let mut emoji = "😃";
-    if server_data.node.is_faulty {
-        emoji = "😈";
-    }
-    println!("[{} Node{}] Received CommitMsg: {:?}", emoji, server_data.node.id, commit_msg);
-    if server_data.node.is_faulty {
-        return HttpResponse::Ok().json(response_body);
-    }
-    let n = server_data.node.node_table.len();
-    let f = (n - 1) / 3;
-    let commit_msg = commit_msg.into_inner();
-    server_data.node.msg_buffer.commit_msgs.lock().unwrap().push(commit_msg.clone());
-    let mut cnt = 0;
-    for msg in server_data.node.msg_buffer.commit_msgs.lock().unwrap().iter() {
-        if msg.digest == commit_msg.digest && msg.view_id == commit_msg.view_id {
-            cnt += 1;
+    // Same as `join`, from the runtime of the replica.
+    async fn stopped(&self) {
+        let task = self.task.lock().unwrap().take();
+        if let Some(Err(e)) = OptionFuture::from(task).await {
+            eprintln!("Replica {} failed: {}", self.id, e);
         }
     }
-    if cnt == 2 * f + 1 {
-        // println!("[{} Node{}] Request committed!", emoji, server_data.node.id);
-        let mut request_msg = RequestMsg {
-            time_stamp: 0,
-            client_id: 0,
-            operation: String::new(),
-            sequence_id: 0,
-            digest: "".to_string(),
-        };
-        for msg in server_data.node.msg_buffer.request_msgs.lock().unwrap().iter() {
-            if msg.digest == commit_msg.digest {
-                request_msg = msg.clone();
-                break;
-            }
-        }
-        if request_msg.digest != "" {
-            let client = reqwest::Client::new();
-            let reply_msg = crate::consensus::message::ReplyMsg {
-                time_stamp: request_msg.time_stamp,
-                view_id: commit_msg.view_id,
-                node_id: server_data.node.id,
-                client_id: request_msg.client_id,
-                result: request_msg.operation.clone(),
-            };
-            // post request to 127.0.0.1:9000
-            match client.post("http://127.0.0.1:9000/reply")
-                .json(&reply_msg)
-                .send()
-                .await
-            {
-                Ok(response) => {
-                    println!("[{} Node{}] Response from client server: {:?}", emoji, server_data.node.id, response.status());
-                }
-                Err(e) => {
-                    eprintln!("[{} Node{}] Error sending request to client server: {}", emoji, server_data.node.id, e);
-                }
-            }
-        }
-    }
-    HttpResponse::Ok().json(response_body)
 }
 
-fn start_server(port: u16, server_data: Arc<Server>) -> io::Result<()> {
+fn start_server(server_data: Arc<Server>) -> io::Result<()> {
     actix_web::rt::System::new().block_on(async move {
+        server_data.spawn()?;
+        let Some(listener) = server_data.listener.clone() else {
+            // the transport receives messages by itself (every transport but HTTP)
+            server_data.stopped().await;
+            return Ok(());
+        };
+        // replicas accept connections from the other replicas and from the client
+        let tls_config = match &listener.tls {
+            Some(tls) => {
+                let mut allowed_peers: Vec<String> = server_data.replica_ids.iter().map(|id| TlsConfig::node_identity(*id)).collect();
+                allowed_peers.push(TlsConfig::client_identity());
+                Some(tls.server_config(&TlsConfig::node_identity(server_data.id), allowed_peers)?)
+            }
+            None => None,
+        };
+        let transport = listener.transport.clone();
         let server = HttpServer::new(move || {
+            let transport = transport.clone();
             App::new()
-                .app_data(Data::from(server_data.clone()))
-                .service(handle_req)
-                .service(handle_pre_prepare)
-                .service(handle_prepare)
-                .service(handle_commit)
-        })
-            .bind(("127.0.0.1", port))?;
+                .configure(move |cfg| transport.configure_inbound(cfg, REPLICA_PATHS))
+        });
+        let server = match tls_config {
+            Some(tls_config) => server.bind_rustls_0_23(&listener.address, tls_config)?,
+            None => server.bind(&listener.address)?,
+        };
 
-        println!("Server started on port {}", port);
+        println!("Server started on {}", listener.address);
 
-        server.run().await
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+        server_data.stopped().await;
+        server_handle.stop(true).await;
+        Ok(())
     })
-}
\ No newline at end of file
+}
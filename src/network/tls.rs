@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+use crate::network::transport::Endpoint;
+
+// Mutual TLS between replicas and the client.
+// All certificates are read from one directory:
+//   ca.pem                  the CA that issued every certificate of the cluster
+//   node<i>.pem, node<i>.key  certificate and key of replica i, issued for the DNS name "node<i>"
+//   client.pem, client.key  certificate and key of the client, issued for the DNS name "client"
+// Certificates of listening parties must also be issued for the host they are reached at (e.g. "localhost").
+#[derive(Clone)]
+pub(crate) struct TlsConfig {
+    identities: Identities,
+    roots: Arc<RootCertStore>,
+}
+
+#[derive(Clone)]
+enum Identities {
+    Dir(PathBuf), // <identity>.pem and <identity>.key in this directory
+    Generated(Arc<HashMap<String, (CertificateDer<'static>, PrivatePkcs8KeyDer<'static>)>>),
+}
+
+impl TlsConfig {
+    pub(crate) fn from_dir(dir: &Path) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&dir.join("ca.pem"))? {
+            roots.add(cert).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(Self {
+            identities: Identities::Dir(dir.to_path_buf()),
+            roots: Arc::new(roots),
+        })
+    }
+
+    // A throwaway CA with a certificate for each of `identities`, kept in memory.
+    // For transports that always use TLS when the whole cluster runs in one process without a `tls_dir`.
+    pub(crate) fn generate(identities: impl IntoIterator<Item = String>) -> io::Result<Self> {
+        let ca_key = rcgen::KeyPair::generate().map_err(io::Error::other)?;
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).map_err(io::Error::other)?;
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(rcgen::DnType::CommonName, "pbft-rust ephemeral CA");
+        let ca_cert = ca_params.self_signed(&ca_key).map_err(io::Error::other)?;
+        let mut roots = RootCertStore::empty();
+        roots.add(ca_cert.der().clone()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut generated = HashMap::new();
+        for identity in identities {
+            let key = rcgen::KeyPair::generate().map_err(io::Error::other)?;
+            let mut params = rcgen::CertificateParams::new(vec![identity.clone(), "localhost".to_string(), "127.0.0.1".to_string()])
+                .map_err(io::Error::other)?;
+            params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth, rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+            let cert = params.signed_by(&key, &ca_cert, &ca_key).map_err(io::Error::other)?;
+            generated.insert(identity, (cert.der().clone(), PrivatePkcs8KeyDer::from(key.serialize_der())));
+        }
+        Ok(Self {
+            identities: Identities::Generated(Arc::new(generated)),
+            roots: Arc::new(roots),
+        })
+    }
+
+    pub(crate) fn node_identity(node_id: u32) -> String {
+        format!("node{}", node_id)
+    }
+
+    pub(crate) fn client_identity() -> String {
+        "client".to_string()
+    }
+
+    // Identity of the certificate of a node, every client uses the certificate of the client
+    pub(crate) fn identity(endpoint: Endpoint) -> String {
+        match endpoint {
+            Endpoint::Replica(id) => Self::node_identity(id),
+            Endpoint::Client(_) => Self::client_identity(),
+        }
+    }
+
+    // Server side config for `identity`. Peers must present a certificate issued by the CA
+    // for one of the `allowed_peers` identities, any other connection is rejected during the handshake.
+    pub(crate) fn server_config(&self, identity: &str, allowed_peers: Vec<String>) -> io::Result<rustls::ServerConfig> {
+        let (certs, key) = self.load_identity(identity)?;
+        let inner = WebPkiClientVerifier::builder_with_provider(self.roots.clone(), provider())
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut allowed = Vec::with_capacity(allowed_peers.len());
+        for peer in allowed_peers {
+            let name = ServerName::try_from(peer).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            allowed.push(name);
+        }
+        rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .with_client_cert_verifier(Arc::new(PeerIdentityVerifier { inner, allowed }))
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Client side config that authenticates as `identity` and only trusts servers with a certificate issued by the CA.
+    pub(crate) fn client_config(&self, identity: &str) -> io::Result<rustls::ClientConfig> {
+        let (certs, key) = self.load_identity(identity)?;
+        rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .with_root_certificates(self.roots.clone())
+            .with_client_auth_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Client side config that authenticates as `identity` and only trusts a certificate issued by the CA for `peer`,
+    // whatever host the peer is reached at. Otherwise any node of the cluster could answer for another one,
+    // since all of them have a certificate for "localhost". QUIC gets the same check from its server name.
+    pub(crate) fn pinned_client_config(&self, identity: &str, peer: &str) -> io::Result<rustls::ClientConfig> {
+        let (certs, key) = self.load_identity(identity)?;
+        let inner = WebPkiServerVerifier::builder_with_provider(self.roots.clone(), provider())
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let peer = ServerName::try_from(peer.to_string()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedServerVerifier { inner, peer }))
+            .with_client_auth_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // HTTPS client of `identity` for the connections to `peer`, see `pinned_client_config`
+    pub(crate) fn http_client(&self, identity: &str, peer: &str) -> io::Result<reqwest::Client> {
+        reqwest::Client::builder()
+            .use_preconfigured_tls(self.pinned_client_config(identity, peer)?)
+            .build()
+            .map_err(io::Error::other)
+    }
+
+    fn load_identity(&self, identity: &str) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        match &self.identities {
+            Identities::Dir(dir) => {
+                let certs = load_certs(&dir.join(format!("{}.pem", identity)))?;
+                let key_path = dir.join(format!("{}.key", identity));
+                let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&key_path)?))?
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no private key in {}", key_path.display())))?;
+                Ok((certs, key))
+            }
+            Identities::Generated(generated) => {
+                let (cert, key) = generated.get(identity)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no certificate for {}", identity)))?;
+                Ok((vec![cert.clone()], PrivateKeyDer::Pkcs8(key.clone_key())))
+            }
+        }
+    }
+}
+
+// Scheme of the URLs of replicas and the client
+pub(crate) fn scheme(tls: &Option<TlsConfig>) -> &'static str {
+    if tls.is_some() { "https" } else { "http" }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+// Verifies the client certificate chain against the CA, then checks that the certificate
+// was issued for one of the configured identities.
+#[derive(Debug)]
+struct PeerIdentityVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    allowed: Vec<ServerName<'static>>,
+}
+
+impl ClientCertVerifier for PeerIdentityVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.inner.verify_client_cert(end_entity, intermediates, now)?;
+        let cert = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if self.allowed.iter().any(|name| cert.verify_is_valid_for_subject_name(name).is_ok()) {
+            Ok(ClientCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// Verifies the server certificate chain against the CA for the identity of the peer instead of the host in the URL.
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    peer: ServerName<'static>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(end_entity, intermediates, &self.peer, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConnection, ServerConnection};
+
+    fn cluster_tls() -> TlsConfig {
+        TlsConfig::generate(["node0", "node1", "client"].map(str::to_string)).unwrap()
+    }
+
+    // Runs a TLS handshake in memory, the client reaches the server at "localhost"
+    fn handshake(client_config: rustls::ClientConfig, server_config: rustls::ServerConfig) -> Result<(), rustls::Error> {
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = ClientConnection::new(Arc::new(client_config), server_name)?;
+        let mut server = ServerConnection::new(Arc::new(server_config))?;
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut bytes = Vec::new();
+            client.write_tls(&mut bytes).unwrap();
+            server.read_tls(&mut bytes.as_slice()).unwrap();
+            server.process_new_packets()?;
+            let mut bytes = Vec::new();
+            server.write_tls(&mut bytes).unwrap();
+            client.read_tls(&mut bytes.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    fn all_peers() -> Vec<String> {
+        vec!["node0".to_string(), "node1".to_string(), "client".to_string()]
+    }
+
+    #[test]
+    fn pinned_peer_is_accepted() {
+        let tls = cluster_tls();
+        let client_config = tls.pinned_client_config("node0", "node1").unwrap();
+        let server_config = tls.server_config("node1", all_peers()).unwrap();
+        assert!(handshake(client_config, server_config).is_ok());
+    }
+
+    #[test]
+    fn other_node_at_the_same_host_is_rejected() {
+        // node0 answers at the address of node1, both have a certificate for "localhost"
+        let tls = cluster_tls();
+        let client_config = tls.pinned_client_config("client", "node1").unwrap();
+        let server_config = tls.server_config("node0", all_peers()).unwrap();
+        assert!(matches!(
+            handshake(client_config, server_config),
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForNameContext { .. }))
+        ));
+    }
+
+    #[test]
+    fn certificate_of_another_ca_is_rejected() {
+        let client_config = cluster_tls().pinned_client_config("node0", "node1").unwrap();
+        let server_config = cluster_tls().server_config("node1", all_peers()).unwrap();
+        assert!(handshake(client_config, server_config).is_err());
+    }
+
+    #[test]
+    fn peer_not_allowed_by_the_server_is_rejected() {
+        let tls = cluster_tls();
+        let client_config = tls.pinned_client_config("client", "node1").unwrap();
+        let server_config = tls.server_config("node1", vec!["node0".to_string()]).unwrap();
+        assert!(handshake(client_config, server_config).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+use crate::consensus::message::{Message, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::network::transport::peers::DELIVERY_TIMEOUT;
+
+pub(crate) mod grpc;
+pub(crate) mod http;
+pub(crate) mod memory;
+pub(crate) mod peers;
+pub(crate) mod quic;
+pub(crate) mod tcp;
+pub(crate) mod unix;
+
+// Address of a node on a transport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Endpoint {
+    Replica(u32),
+    Client(u32),
+}
+
+// Messages received by a node, in arrival order.
+// Every transport hands out this stream together with the transport itself.
+pub(crate) type Inbound = UnboundedReceiver<Message>;
+
+// Tells whether a message that could not be delivered yet is no longer needed, see `PeerManager`.
+pub(crate) type IsObsolete = Arc<dyn Fn(&Message) -> bool + Send + Sync>;
+
+// How nodes exchange messages. The consensus logic only talks to this trait,
+// so it does not depend on how (or whether) messages cross the network.
+pub(crate) trait Transport: Send + Sync {
+    // Starts the background work of the transport, such as sender tasks and listeners.
+    // Called once from the runtime of the node, before any message is sent.
+    fn start(&self, _is_obsolete: IsObsolete) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Messages sent and not yet acknowledged by their receiver, see `flush`.
+    fn queued(&self) -> usize {
+        0
+    }
+
+    // Stops the background work of the transport and closes its sockets, messages still queued are dropped.
+    // Called once the node has stopped, see `Server::shutdown`.
+    fn stop(&self) {}
+
+    // Sends `msg` to a single node. Only queues the message, delivery happens in the background.
+    fn send(&self, to: Endpoint, msg: Message);
+
+    // Sends `msg` to every replica except this node.
+    fn broadcast(&self, msg: Message);
+
+    // Ids of the clients reachable on this transport.
+    fn clients(&self) -> Vec<u32>;
+
+    // Makes a client that is not in the configuration reachable at `address`, as given in a request this replica accepts
+    // and the client has signed with `client_key`. `is_ordered` tells whether the primary has ordered the request, by signing
+    // its pre-prepare: only then may the address replace the one the client has registered before, e.g. after a restart
+    // on another port, and only then may the client take the place of another one (see `ClientAddresses`).
+    fn register_client(&self, _id: u32, _address: &str, _client_key: &str, _is_ordered: bool) {}
+}
+
+// The address `endpoint` listens on, among the addresses of the replicas and clients of the configuration.
+pub(crate) fn own_address(endpoint: Endpoint, replicas: &HashMap<u32, String>, clients: &HashMap<u32, String>) -> io::Result<String> {
+    let address = match endpoint {
+        Endpoint::Replica(id) => replicas.get(&id),
+        Endpoint::Client(id) => clients.get(&id),
+    };
+    address.cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no address for {:?}", endpoint)))
+}
+
+// The nodes of the configuration that `endpoint` sends to: every replica and client but itself.
+pub(crate) fn peers_of(endpoint: Endpoint, replicas: &HashMap<u32, String>, clients: &HashMap<u32, String>) -> Vec<Endpoint> {
+    replicas.keys().map(|id| Endpoint::Replica(*id))
+        .chain(clients.keys().map(|id| Endpoint::Client(*id)))
+        .filter(|peer| *peer != endpoint)
+        .collect()
+}
+
+// Names of the headers (http) and metadata (grpc) announcing the protocol version of the sender, see `Versions`.
+// The other transports announce it when they connect.
+pub(crate) const VERSION_HEADER: &str = "pbft-version";
+pub(crate) const MIN_VERSION_HEADER: &str = "pbft-min-version";
+
+// The protocol versions of a node: the one it speaks, and the oldest one it accepts from peers (see `PROTOCOL_VERSION`).
+// Only tests run nodes of other versions than `Versions::CURRENT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Versions {
+    pub(crate) version: u16,
+    pub(crate) min_version: u16,
+}
+
+impl Versions {
+    pub(crate) const CURRENT: Self = Self { version: PROTOCOL_VERSION, min_version: MIN_PROTOCOL_VERSION };
+
+    // Checks that a peer of versions `peer` can talk to this node: nodes are compatible when each accepts the version of the other.
+    // Returns the version of the messages between them, the older of both, so that the newer node adapts to the older one.
+    pub(crate) fn negotiate(&self, peer: Versions) -> io::Result<u16> {
+        if peer.version >= self.min_version && peer.min_version <= self.version {
+            return Ok(self.version.min(peer.version));
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "incompatible protocol version: the peer speaks version {} and accepts versions from {} on, this node speaks version {} and accepts versions from {} on",
+            peer.version, peer.min_version, self.version, self.min_version,
+        )))
+    }
+
+    // The versions announced in `VERSION_HEADER` and `MIN_VERSION_HEADER`.
+    pub(crate) fn from_headers(version: Option<&str>, min_version: Option<&str>) -> io::Result<Self> {
+        let (Some(version), Some(min_version)) = (version, min_version) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the peer did not announce its protocol version, it is older than version 1"));
+        };
+        Ok(Self { version: parse_version(version)?, min_version: parse_version(min_version)? })
+    }
+}
+
+pub(crate) fn parse_version(value: &str) -> io::Result<u16> {
+    value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid protocol version {:?}", value)))
+}
+
+// Clients outside of the configuration a transport keeps the address of, see `ClientAddresses`
+const MAX_REGISTERED_CLIENTS: usize = 1024;
+
+// Addresses of the clients of a transport: those of the configuration, and those registered later on.
+// Clients of the configuration keep their address. A registered client is bound to the key it has first signed
+// its requests with: it may move to another address, but only with a request signed with that key and ordered
+// by the primary, so that nobody else can take its replies. At most `MAX_REGISTERED_CLIENTS` clients are
+// registered at once. Beyond that, the one heard from least recently makes room, but only for a client whose
+// request the primary has ordered, so that a flood of requests nobody has accepted yet cannot push clients out.
+// Shared with the connections of the transport, which look the address of a client up when they connect (see `PeerAddress`).
+#[derive(Clone)]
+pub(crate) struct ClientAddresses(Arc<RwLock<Clients>>);
+
+struct Clients {
+    addresses: HashMap<u32, String>, // client id -> url:port (or socket path)
+    configured: HashSet<u32>,
+    keys: HashMap<u32, String>, // client id -> hex encoded key, for the registered clients
+    registered: VecDeque<u32>, // the other clients, the one heard from most recently last
+}
+
+// What `ClientAddresses::register` did, the transport sets up a new client and forgets an evicted one.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Registration {
+    New { evicted: Option<u32> },
+    Known, // the address may have changed, the connections reconnect by themselves
+    Refused, // signed with another key than the one of the client, or no room for a new client
+}
+
+impl ClientAddresses {
+    pub(crate) fn new(addresses: HashMap<u32, String>) -> Self {
+        let configured = addresses.keys().copied().collect();
+        Self(Arc::new(RwLock::new(Clients { addresses, configured, keys: HashMap::new(), registered: VecDeque::new() })))
+    }
+
+    pub(crate) fn get(&self, id: u32) -> Option<String> {
+        self.0.read().unwrap().addresses.get(&id).cloned()
+    }
+
+    pub(crate) fn ids(&self) -> Vec<u32> {
+        self.0.read().unwrap().addresses.keys().copied().collect()
+    }
+
+    // `client_key` is the key the request giving `address` is signed with, `may_move` allows a registered client
+    // to change its address and a new client to evict another one, see `Transport::register_client`.
+    pub(crate) fn register(&self, id: u32, address: &str, client_key: &str, may_move: bool) -> Registration {
+        let mut clients = self.0.write().unwrap();
+        let clients = &mut *clients;
+        if clients.configured.contains(&id) {
+            if clients.addresses[&id] != address {
+                eprintln!(" -- Ignoring address {} of client {}, its replies go to {} as configured", address, id, clients.addresses[&id]);
+            }
+            return Registration::Known;
+        }
+        if let Some(known) = clients.addresses.get_mut(&id) {
+            if clients.keys[&id] != client_key {
+                eprintln!(" -- Ignoring address {} of client {}, the request is signed with another key than its own", address, id);
+                return Registration::Refused;
+            }
+            if *known != address && may_move {
+                println!("[📇 Transport] Client {} moved to {}", id, address);
+                *known = address.to_string();
+            } else if *known != address {
+                eprintln!(" -- Ignoring address {} of client {} until a request from there is ordered, its replies go to {}", address, id, known);
+            }
+            clients.registered.retain(|registered| *registered != id);
+            clients.registered.push_back(id);
+            return Registration::Known;
+        }
+        let evicted = if clients.registered.len() >= MAX_REGISTERED_CLIENTS {
+            if !may_move {
+                eprintln!(" -- Too many clients, ignoring address {} of client {} until a request from there is ordered", address, id);
+                return Registration::Refused;
+            }
+            clients.registered.pop_front()
+        } else {
+            None
+        };
+        if let Some(evicted) = evicted {
+            clients.addresses.remove(&evicted);
+            clients.keys.remove(&evicted);
+            eprintln!(" -- Too many clients, forgetting the address of client {}", evicted);
+        }
+        println!("[📇 Transport] Client {} receives its replies at {}", id, address);
+        clients.addresses.insert(id, address.to_string());
+        clients.keys.insert(id, client_key.to_string());
+        clients.registered.push_back(id);
+        Registration::New { evicted }
+    }
+
+    // Where the connections to `peer` find it
+    pub(crate) fn peer_address(&self, replicas: &HashMap<u32, String>, peer: Endpoint) -> PeerAddress {
+        match peer {
+            Endpoint::Replica(id) => PeerAddress::Fixed(replicas[&id].clone()),
+            Endpoint::Client(id) => PeerAddress::Client(self.clone(), id),
+        }
+    }
+}
+
+// The address of a peer as its connections see it: replicas stay where they are configured,
+// clients are looked up on every attempt, since a registered client may move (see `ClientAddresses`).
+#[derive(Clone)]
+pub(crate) enum PeerAddress {
+    Fixed(String),
+    Client(ClientAddresses, u32),
+}
+
+impl PeerAddress {
+    pub(crate) fn current(&self) -> io::Result<String> {
+        match self {
+            PeerAddress::Fixed(address) => Ok(address.clone()),
+            PeerAddress::Client(clients, id) => clients.get(*id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("client {} is no longer registered", id))),
+        }
+    }
+}
+
+// Waits until the messages queued on `transport` are delivered, before it is stopped.
+// Peers that have not acknowledged them after `DELIVERY_TIMEOUT` are not waited for any longer.
+pub(crate) async fn flush(transport: &dyn Transport) {
+    let deadline = Instant::now() + DELIVERY_TIMEOUT;
+    while transport.queued() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+// Background tasks of a transport, such as listeners and the connections they accepted.
+// They are aborted when the transport stops, which closes their sockets.
+#[derive(Clone)]
+pub(crate) struct Tasks {
+    handles: Arc<Mutex<Option<Vec<AbortHandle>>>>, // None once aborted
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Self { handles: Arc::new(Mutex::new(Some(Vec::new()))) }
+    }
+}
+
+impl Tasks {
+    // Spawns `task` on the current runtime, unless the tasks have already been aborted.
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut handles = self.handles.lock().unwrap();
+        let Some(handles) = handles.as_mut() else {
+            return;
+        };
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(tokio::spawn(task).abort_handle());
+    }
+
+    pub(crate) fn abort(&self) {
+        for handle in self.handles.lock().unwrap().take().unwrap_or_default() {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "key of the client";
+
+    fn clients() -> ClientAddresses {
+        ClientAddresses::new(HashMap::from([(0, "127.0.0.1:9001".to_string())]))
+    }
+
+    #[test]
+    fn configured_clients_keep_their_address() {
+        let clients = clients();
+        assert_eq!(clients.register(0, "127.0.0.1:6666", KEY, true), Registration::Known);
+        assert_eq!(clients.get(0).unwrap(), "127.0.0.1:9001");
+    }
+
+    #[test]
+    fn registered_clients_only_move_with_ordered_requests() {
+        let clients = clients();
+        assert_eq!(clients.register(7, "127.0.0.1:9107", KEY, false), Registration::New { evicted: None });
+        assert_eq!(clients.register(7, "127.0.0.1:6666", KEY, false), Registration::Known);
+        assert_eq!(clients.get(7).unwrap(), "127.0.0.1:9107");
+        // e.g. restarted on another port
+        assert_eq!(clients.register(7, "127.0.0.1:9207", KEY, true), Registration::Known);
+        assert_eq!(clients.get(7).unwrap(), "127.0.0.1:9207");
+        let address = clients.peer_address(&HashMap::new(), Endpoint::Client(7));
+        assert_eq!(address.current().unwrap(), "127.0.0.1:9207");
+    }
+
+    #[test]
+    fn only_the_key_of_a_client_moves_it() {
+        let clients = clients();
+        assert_eq!(clients.register(7, "127.0.0.1:9107", KEY, false), Registration::New { evicted: None });
+        assert_eq!(clients.register(7, "127.0.0.1:6666", "another key", true), Registration::Refused);
+        assert_eq!(clients.get(7).unwrap(), "127.0.0.1:9107");
+        assert_eq!(clients.register(7, "127.0.0.1:9207", KEY, true), Registration::Known);
+        assert_eq!(clients.get(7).unwrap(), "127.0.0.1:9207");
+    }
+
+    #[test]
+    fn the_client_heard_from_least_recently_makes_room_for_an_ordered_request() {
+        let clients = clients();
+        for id in 1..=MAX_REGISTERED_CLIENTS as u32 {
+            assert_eq!(clients.register(id, &format!("client{}", id), KEY, false), Registration::New { evicted: None });
+        }
+        clients.register(1, "client1", KEY, false);
+        // requests nobody has ordered yet do not push the others out
+        assert_eq!(clients.register(5000, "client5000", KEY, false), Registration::Refused);
+        assert!(clients.get(5000).is_none());
+        assert!(clients.get(2).is_some());
+        assert_eq!(clients.register(5000, "client5000", KEY, true), Registration::New { evicted: Some(2) });
+        let address = clients.peer_address(&HashMap::new(), Endpoint::Client(2));
+        assert_eq!(address.current().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(clients.get(1).is_some());
+        assert!(clients.get(0).is_some());
+    }
+}
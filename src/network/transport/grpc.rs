@@ -0,0 +1,700 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::Notify;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::codec::CompressionEncoding;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Channel, Server};
+use tonic::{Code, Request, Response, Status};
+use crate::consensus::message::{
+    AggregateSignature, BusyMsg, CommitCertificate, CommittedEntry, HeartbeatMsg, Message, MsgType, NewKeyMsg, PrePrepareMsg,
+    ReplyMsg, RequestMsg, SignerBitmap, StateMsg, StateRequestMsg, VoteMsg, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::network::transport::peers::{Connection, PeerManager, PeerTransport, DELIVERY_TIMEOUT};
+use crate::network::transport::tcp::MAX_FRAME_LEN;
+use crate::network::transport::{
+    own_address, peers_of, ClientAddresses, Endpoint, Inbound, PeerAddress, Versions, MIN_VERSION_HEADER, VERSION_HEADER,
+};
+
+// Generated from proto/pbft.proto by build.rs
+pub(crate) mod proto {
+    tonic::include_proto!("pbft");
+}
+
+use proto::client_client::ClientClient;
+use proto::client_server::ClientServer;
+use proto::replica_client::ReplicaClient;
+use proto::replica_server::ReplicaServer;
+
+// gRPC with the protobuf schema in proto/pbft.proto, one call per message type.
+// Replicas serve the `Replica` service and clients the `Client` service on their address,
+// so nodes written in other languages only need the schema to take part.
+// The response of a call acknowledges the message, senders keep one HTTP/2 channel per peer (see `PeerManager`).
+// Every call carries the protocol version of its sender in metadata, receivers reject it if they are not compatible.
+// Unlike the frames of the other transports, the messages need no version to decode: protobuf keeps
+// messages of older and newer schemas readable by their field numbers, new fields are left out or ignored.
+// The calls are not encrypted, so nodes only listen on loopback addresses (see `ClusterConfig::validate`).
+// Every node accepts calls compressed with zstd, nodes with a compression threshold compress the larger messages.
+// A peer that does not accept them rejects the call with the encodings it does accept (`grpc-accept-encoding`),
+// after which the connection sends it uncompressed messages, starting with the retransmission of that one.
+pub(crate) struct GrpcTransport {
+    endpoint: Endpoint,
+    address: String, // url:port this node listens on
+    replicas: HashMap<u32, String>, // Node.id -> url:port
+    clients: ClientAddresses, // client id -> url:port
+    peers: PeerManager,
+    inbound_tx: UnboundedSender<Message>,
+    stopping: Arc<Notify>, // shuts the gRPC server down
+    compress_above: Option<usize>, // messages larger than this many bytes are compressed, if the peer accepts it
+}
+
+impl GrpcTransport {
+    pub(crate) fn new(
+        endpoint: Endpoint,
+        replicas: HashMap<u32, String>,
+        clients: HashMap<u32, String>,
+        compress_above: Option<usize>,
+    ) -> io::Result<(Self, Inbound)> {
+        let address = own_address(endpoint, &replicas, &clients)?;
+        let (inbound_tx, inbound) = unbounded_channel();
+        let transport = Self {
+            endpoint,
+            address,
+            peers: PeerManager::new(peers_of(endpoint, &replicas, &clients)),
+            replicas,
+            clients: ClientAddresses::new(clients),
+            inbound_tx,
+            stopping: Arc::new(Notify::new()),
+            compress_above,
+        };
+        Ok((transport, inbound))
+    }
+
+}
+
+impl PeerTransport for GrpcTransport {
+    type Connection = GrpcConnection;
+
+    fn peers(&self) -> &PeerManager {
+        &self.peers
+    }
+
+    fn client_addresses(&self) -> &ClientAddresses {
+        &self.clients
+    }
+
+    fn connect(&self, peer: Endpoint) -> impl Fn() -> Self::Connection + '_ {
+        move || GrpcConnection {
+            peer,
+            address: self.clients.peer_address(&self.replicas, peer),
+            channel: None,
+            compress_above: self.compress_above,
+            accepts_zstd: true,
+        }
+    }
+
+    fn listen(&self) -> io::Result<()> {
+        // bind here rather than in the server task, so that an address in use fails the start of the node
+        let listener = std::net::TcpListener::bind(&self.address)?;
+        listener.set_nonblocking(true)?;
+        let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+        let service = InboundService { inbound_tx: self.inbound_tx.clone() };
+        // tonic accepts 4 MiB by default, messages may be as large as the frames of `tcp`
+        let server = match self.endpoint {
+            Endpoint::Replica(_) => Server::builder().add_service(InterceptedService::new(
+                ReplicaServer::new(service).accept_compressed(CompressionEncoding::Zstd).max_decoding_message_size(MAX_FRAME_LEN as usize),
+                check_version_metadata,
+            )),
+            Endpoint::Client(_) => Server::builder().add_service(InterceptedService::new(
+                ClientServer::new(service).accept_compressed(CompressionEncoding::Zstd).max_decoding_message_size(MAX_FRAME_LEN as usize),
+                check_version_metadata,
+            )),
+        };
+        let stopping = self.stopping.clone();
+        tokio::spawn(async move {
+            // graceful, the server tells connected peers to go away and closes the connections
+            if let Err(e) = server.serve_with_incoming_shutdown(incoming, stopping.notified()).await {
+                eprintln!(" -- gRPC server stopped: {}", e);
+            }
+        });
+        // the channels are made by the connections, but a configured address that is not a valid URI fails the start
+        for address in self.replicas.values() {
+            channel(address)?;
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.stopping.notify_one();
+    }
+}
+
+// Connects on the first call, see `GrpcConnection`.
+fn channel(address: &str) -> io::Result<Channel> {
+    let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", address))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .connect_timeout(DELIVERY_TIMEOUT)
+        .timeout(DELIVERY_TIMEOUT)
+        .connect_lazy();
+    Ok(channel)
+}
+
+// The channel connects on the first call and reconnects by itself after errors.
+// It is made on the first message, and again once the peer has moved.
+pub(crate) struct GrpcConnection {
+    peer: Endpoint,
+    address: PeerAddress, // url:port of the peer
+    channel: Option<(String, Channel)>, // and the address it connects to
+    compress_above: Option<usize>,
+    accepts_zstd: bool, // until the peer has rejected a compressed call
+}
+
+impl GrpcConnection {
+    fn channel(&mut self) -> io::Result<Channel> {
+        let address = self.address.current()?;
+        if let Some((_, channel)) = self.channel.as_ref().filter(|(connected_to, _)| *connected_to == address) {
+            return Ok(channel.clone());
+        }
+        let channel = channel(&address)?;
+        self.channel = Some((address, channel.clone()));
+        self.accepts_zstd = true;
+        Ok(channel)
+    }
+
+    fn replica(channel: Channel, compress: bool) -> ReplicaClient<Channel> {
+        let client = ReplicaClient::new(channel);
+        if compress { client.send_compressed(CompressionEncoding::Zstd) } else { client }
+    }
+
+    fn client(channel: Channel, compress: bool) -> ClientClient<Channel> {
+        let client = ClientClient::new(channel);
+        if compress { client.send_compressed(CompressionEncoding::Zstd) } else { client }
+    }
+}
+
+impl Connection for GrpcConnection {
+    fn deliver<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let channel = self.channel()?;
+            let compress = self.accepts_zstd && self.compress_above
+                .is_some_and(|threshold| bincode::serialized_size(msg).is_ok_and(|size| size as usize > threshold));
+            let result = match (self.peer, msg) {
+                (Endpoint::Replica(_), Message::Request(msg)) => Self::replica(channel, compress).request(versioned(proto::RequestMsg::from(msg))).await,
+                (Endpoint::Replica(_), Message::PrePrepare(msg)) => Self::replica(channel, compress).pre_prepare(versioned(proto::PrePrepareMsg::from(msg))).await,
+                (Endpoint::Replica(_), Message::Prepare(msg)) => Self::replica(channel, compress).prepare(versioned(proto::VoteMsg::from(msg))).await,
+                (Endpoint::Replica(_), Message::Commit(msg)) => Self::replica(channel, compress).commit(versioned(proto::VoteMsg::from(msg))).await,
+                (Endpoint::Replica(_), Message::NewKey(msg)) => Self::replica(channel, compress).new_key(versioned(proto::NewKeyMsg::from(msg))).await,
+                (Endpoint::Replica(_), Message::StateRequest(msg)) => Self::replica(channel, compress).state_request(versioned(proto::StateRequestMsg::from(msg))).await,
+                (Endpoint::Replica(_), Message::State(msg)) => Self::replica(channel, compress).state(versioned(proto::StateMsg::from(msg))).await,
+                (Endpoint::Replica(_), Message::Heartbeat(msg)) => Self::replica(channel, compress).heartbeat(versioned(proto::HeartbeatMsg::from(msg))).await,
+                (Endpoint::Client(_), Message::Reply(msg)) => Self::client(channel, compress).reply(versioned(proto::ReplyMsg::from(msg))).await,
+                (Endpoint::Client(_), Message::Busy(msg)) => Self::client(channel, compress).busy(versioned(proto::BusyMsg::from(msg))).await,
+                (Endpoint::Client(_), Message::NewKey(msg)) => Self::client(channel, compress).new_key(versioned(proto::NewKeyMsg::from(msg))).await,
+                (peer, msg) => {
+                    // retransmitting would not help, the service of the peer has no call for it
+                    eprintln!(" -- {:?} does not receive {:?}, dropping it", peer, msg);
+                    return Ok(());
+                }
+            };
+            match result {
+                Ok(_) => Ok(()),
+                Err(status) if compress && status.code() == Code::Unimplemented && status.metadata().contains_key("grpc-accept-encoding") => {
+                    self.accepts_zstd = false;
+                    Err(io::Error::other(status)) // sent again uncompressed
+                }
+                Err(status) => Err(status_error(status)),
+            }
+        })
+    }
+}
+
+// Calls the peer has rejected, such as for an incompatible protocol version or a message too large (`OutOfRange` in tonic),
+// are reported as `InvalidData` so that they are not retransmitted, see `peers::is_permanent`.
+// `ResourceExhausted` is retransmitted like other transient errors, it tells that the peer is overloaded for now.
+fn status_error(status: Status) -> io::Error {
+    let kind = match status.code() {
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange | Code::Unimplemented => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, status)
+}
+
+// Announces the protocol version of this node on a call, see `check_version_metadata`.
+fn versioned<T>(msg: T) -> Request<T> {
+    let mut request = Request::new(msg);
+    request.metadata_mut().insert(VERSION_HEADER, PROTOCOL_VERSION.into());
+    request.metadata_mut().insert(MIN_VERSION_HEADER, MIN_PROTOCOL_VERSION.into());
+    request
+}
+
+// Rejects the calls of peers whose protocol version is not compatible with the version of this node.
+#[allow(clippy::result_large_err)] // the signature tonic expects of an interceptor
+fn check_version_metadata(request: Request<()>) -> Result<Request<()>, Status> {
+    let metadata = |name| request.metadata().get(name).and_then(|value| value.to_str().ok());
+    let versions = Versions::from_headers(metadata(VERSION_HEADER), metadata(MIN_VERSION_HEADER));
+    if let Err(e) = versions.and_then(|versions| Versions::CURRENT.negotiate(versions)) {
+        eprintln!(" -- Rejected message: {}", e);
+        return Err(Status::failed_precondition(e.to_string()));
+    }
+    Ok(request)
+}
+
+// Puts the messages of incoming calls on the inbound stream, serves both the `Replica` and the `Client` service.
+struct InboundService {
+    inbound_tx: UnboundedSender<Message>,
+}
+
+impl InboundService {
+    fn receive(&self, msg: Message) -> Response<proto::Ack> {
+        let _ = self.inbound_tx.send(msg); // the node is shutting down if nobody is receiving
+        Response::new(proto::Ack {})
+    }
+}
+
+#[tonic::async_trait]
+impl proto::replica_server::Replica for InboundService {
+    async fn request(&self, request: Request<proto::RequestMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::Request(request.into_inner().into())))
+    }
+
+    async fn pre_prepare(&self, request: Request<proto::PrePrepareMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::PrePrepare(request.into_inner().try_into()?)))
+    }
+
+    async fn prepare(&self, request: Request<proto::VoteMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::Prepare(request.into_inner().into())))
+    }
+
+    async fn commit(&self, request: Request<proto::VoteMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::Commit(request.into_inner().into())))
+    }
+
+    async fn new_key(&self, request: Request<proto::NewKeyMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::NewKey(request.into_inner().into())))
+    }
+
+    async fn state_request(&self, request: Request<proto::StateRequestMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::StateRequest(request.into_inner().into())))
+    }
+
+    async fn state(&self, request: Request<proto::StateMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::State(request.into_inner().into())))
+    }
+
+    async fn heartbeat(&self, request: Request<proto::HeartbeatMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::Heartbeat(request.into_inner().into())))
+    }
+}
+
+#[tonic::async_trait]
+impl proto::client_server::Client for InboundService {
+    async fn reply(&self, request: Request<proto::ReplyMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::Reply(request.into_inner().into())))
+    }
+
+    async fn busy(&self, request: Request<proto::BusyMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::Busy(request.into_inner().try_into()?)))
+    }
+
+    async fn new_key(&self, request: Request<proto::NewKeyMsg>) -> Result<Response<proto::Ack>, Status> {
+        Ok(self.receive(Message::NewKey(request.into_inner().into())))
+    }
+}
+
+// Conversions between the messages of the protocol and their protobuf form.
+// Digests of requests are not sent, replicas compute them from the request itself.
+
+impl From<&RequestMsg> for proto::RequestMsg {
+    fn from(msg: &RequestMsg) -> Self {
+        Self {
+            operation: msg.operation.clone(),
+            time_stamp: msg.time_stamp,
+            client_id: msg.client_id,
+            sequence_id: msg.sequence_id,
+            reply_address: msg.reply_address.clone(),
+            client_key: msg.client_key.clone(),
+            client_signature: msg.client_signature.clone(),
+        }
+    }
+}
+
+impl From<proto::RequestMsg> for RequestMsg {
+    fn from(msg: proto::RequestMsg) -> Self {
+        Self {
+            operation: msg.operation,
+            time_stamp: msg.time_stamp,
+            client_id: msg.client_id,
+            sequence_id: msg.sequence_id,
+            digest: String::new(),
+            reply_address: msg.reply_address,
+            client_key: msg.client_key,
+            client_signature: msg.client_signature,
+        }
+    }
+}
+
+impl From<&PrePrepareMsg> for proto::PrePrepareMsg {
+    fn from(msg: &PrePrepareMsg) -> Self {
+        Self {
+            view_id: msg.view_id,
+            sequence_id: msg.sequence_id,
+            digest: msg.digest.clone(),
+            request_msg: Some((&msg.request_msg).into()),
+            signature: msg.signature.clone(),
+        }
+    }
+}
+
+impl TryFrom<proto::PrePrepareMsg> for PrePrepareMsg {
+    type Error = Status;
+
+    fn try_from(msg: proto::PrePrepareMsg) -> Result<Self, Status> {
+        let request_msg = msg.request_msg.ok_or_else(|| Status::invalid_argument("pre-prepare without request"))?;
+        Ok(Self {
+            view_id: msg.view_id,
+            sequence_id: msg.sequence_id,
+            digest: msg.digest,
+            request_msg: request_msg.into(),
+            signature: msg.signature,
+        })
+    }
+}
+
+impl From<&VoteMsg> for proto::VoteMsg {
+    fn from(msg: &VoteMsg) -> Self {
+        let msg_type = match msg.msg_type {
+            MsgType::PrepareMsg => proto::VoteType::Prepare,
+            MsgType::CommitMsg => proto::VoteType::Commit,
+        };
+        Self {
+            view_id: msg.view_id,
+            sequence_id: msg.sequence_id,
+            digest: msg.digest.clone(),
+            node_id: msg.node_id,
+            msg_type: msg_type.into(),
+            signature: msg.signature.clone(),
+        }
+    }
+}
+
+impl From<proto::VoteMsg> for VoteMsg {
+    fn from(msg: proto::VoteMsg) -> Self {
+        // unknown vote types read as prepares, their signature does not verify as one
+        let msg_type = match msg.msg_type() {
+            proto::VoteType::Prepare => MsgType::PrepareMsg,
+            proto::VoteType::Commit => MsgType::CommitMsg,
+        };
+        Self {
+            view_id: msg.view_id,
+            sequence_id: msg.sequence_id,
+            digest: msg.digest,
+            node_id: msg.node_id,
+            msg_type,
+            signature: msg.signature,
+        }
+    }
+}
+
+impl From<&ReplyMsg> for proto::ReplyMsg {
+    fn from(msg: &ReplyMsg) -> Self {
+        Self {
+            time_stamp: msg.time_stamp,
+            view_id: msg.view_id,
+            node_id: msg.node_id,
+            client_id: msg.client_id,
+            result: msg.result.clone(),
+            certificate: msg.certificate.as_ref().map(|certificate| proto::CommitCertificate {
+                view_id: certificate.view_id,
+                sequence_id: certificate.sequence_id,
+                digest: certificate.digest.clone(),
+                commits: certificate.commits.iter().map(proto::VoteMsg::from).collect(),
+                aggregate: certificate.aggregate.as_ref().map(|aggregate| proto::AggregateSignature {
+                    signers: aggregate.signers.as_bytes().to_vec(),
+                    signature: aggregate.signature.clone(),
+                }),
+            }),
+            queue_depth: msg.queue_depth,
+        }
+    }
+}
+
+impl From<proto::ReplyMsg> for ReplyMsg {
+    fn from(msg: proto::ReplyMsg) -> Self {
+        Self {
+            time_stamp: msg.time_stamp,
+            view_id: msg.view_id,
+            node_id: msg.node_id,
+            client_id: msg.client_id,
+            result: msg.result,
+            certificate: msg.certificate.map(|certificate| CommitCertificate {
+                view_id: certificate.view_id,
+                sequence_id: certificate.sequence_id,
+                digest: certificate.digest,
+                commits: certificate.commits.into_iter().map(VoteMsg::from).collect(),
+                aggregate: certificate.aggregate.map(|aggregate| AggregateSignature {
+                    signers: SignerBitmap::from_bytes(aggregate.signers),
+                    signature: aggregate.signature,
+                }),
+            }),
+            queue_depth: msg.queue_depth,
+        }
+    }
+}
+
+impl From<&BusyMsg> for proto::BusyMsg {
+    fn from(msg: &BusyMsg) -> Self {
+        Self {
+            view_id: msg.view_id,
+            node_id: msg.node_id,
+            request_msg: Some((&msg.request_msg).into()),
+            retry_after_ms: msg.retry_after_ms,
+            queue_depth: msg.queue_depth,
+            signature: msg.signature.clone(),
+        }
+    }
+}
+
+impl TryFrom<proto::BusyMsg> for BusyMsg {
+    type Error = Status;
+
+    fn try_from(msg: proto::BusyMsg) -> Result<Self, Status> {
+        let request_msg = msg.request_msg.ok_or_else(|| Status::invalid_argument("busy message without request"))?;
+        Ok(Self {
+            view_id: msg.view_id,
+            node_id: msg.node_id,
+            request_msg: request_msg.into(),
+            retry_after_ms: msg.retry_after_ms,
+            queue_depth: msg.queue_depth,
+            signature: msg.signature,
+        })
+    }
+}
+
+impl From<&NewKeyMsg> for proto::NewKeyMsg {
+    fn from(msg: &NewKeyMsg) -> Self {
+        Self {
+            node_id: msg.node_id,
+            epoch: msg.epoch,
+            public_key: msg.public_key.clone(),
+            signature: msg.signature.clone(),
+            proof_of_possession: msg.proof_of_possession.clone(),
+        }
+    }
+}
+
+impl From<proto::NewKeyMsg> for NewKeyMsg {
+    fn from(msg: proto::NewKeyMsg) -> Self {
+        Self {
+            node_id: msg.node_id,
+            epoch: msg.epoch,
+            public_key: msg.public_key,
+            signature: msg.signature,
+            proof_of_possession: msg.proof_of_possession,
+        }
+    }
+}
+
+impl From<&HeartbeatMsg> for proto::HeartbeatMsg {
+    fn from(msg: &HeartbeatMsg) -> Self {
+        Self {
+            node_id: msg.node_id,
+            view_id: msg.view_id,
+            time_stamp: msg.time_stamp,
+            signature: msg.signature.clone(),
+        }
+    }
+}
+
+impl From<proto::HeartbeatMsg> for HeartbeatMsg {
+    fn from(msg: proto::HeartbeatMsg) -> Self {
+        Self {
+            node_id: msg.node_id,
+            view_id: msg.view_id,
+            time_stamp: msg.time_stamp,
+            signature: msg.signature,
+        }
+    }
+}
+
+impl From<&StateRequestMsg> for proto::StateRequestMsg {
+    fn from(msg: &StateRequestMsg) -> Self {
+        Self { node_id: msg.node_id }
+    }
+}
+
+impl From<proto::StateRequestMsg> for StateRequestMsg {
+    fn from(msg: proto::StateRequestMsg) -> Self {
+        Self { node_id: msg.node_id }
+    }
+}
+
+impl From<&StateMsg> for proto::StateMsg {
+    fn from(msg: &StateMsg) -> Self {
+        Self {
+            node_id: msg.node_id,
+            state_digest: msg.state_digest.clone(),
+            log: msg.log.iter()
+                .map(|entry| proto::CommittedEntry {
+                    view_id: entry.view_id,
+                    sequence_id: entry.sequence_id,
+                    digest: entry.digest.clone(),
+                })
+                .collect(),
+            signature: msg.signature.clone(),
+        }
+    }
+}
+
+impl From<proto::StateMsg> for StateMsg {
+    fn from(msg: proto::StateMsg) -> Self {
+        Self {
+            node_id: msg.node_id,
+            state_digest: msg.state_digest,
+            log: msg.log.into_iter()
+                .map(|entry| CommittedEntry {
+                    view_id: entry.view_id,
+                    sequence_id: entry.sequence_id,
+                    digest: entry.digest,
+                })
+                .collect(),
+            signature: msg.signature,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+    use crate::network::transport::Transport;
+
+    fn loopback_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn request(operation: String) -> RequestMsg {
+        RequestMsg { operation, time_stamp: 1, client_id: 0, sequence_id: 2, digest: String::new(), reply_address: Some("127.0.0.1:9101".to_string()),
+            client_key: Some("00".repeat(32)), client_signature: Some("01".repeat(64)) }
+    }
+
+    fn vote(node_id: u32, msg_type: MsgType) -> VoteMsg {
+        VoteMsg { view_id: 9999, sequence_id: 3, digest: "d".to_string(), node_id, msg_type, signature: format!("s{}", node_id) }
+    }
+
+    async fn receive(inbound: &mut Inbound) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), inbound.recv()).await
+            .expect("no message within 5 seconds")
+            .expect("the transport has stopped")
+    }
+
+    #[test]
+    fn messages_keep_their_fields_in_the_protobuf_form() {
+        let same = |msg: &dyn std::fmt::Debug, converted: &dyn std::fmt::Debug| assert_eq!(format!("{:?}", msg), format!("{:?}", converted));
+
+        let request_msg = request("op".to_string());
+        same(&request_msg, &RequestMsg::from(proto::RequestMsg::from(&request_msg)));
+        let pre_prepare_msg = PrePrepareMsg { view_id: 9999, sequence_id: 3, digest: "d".to_string(), request_msg: request_msg.clone(), signature: "s".to_string() };
+        same(&pre_prepare_msg, &PrePrepareMsg::try_from(proto::PrePrepareMsg::from(&pre_prepare_msg)).unwrap());
+        for msg_type in [MsgType::PrepareMsg, MsgType::CommitMsg] {
+            let vote_msg = vote(1, msg_type);
+            same(&vote_msg, &VoteMsg::from(proto::VoteMsg::from(&vote_msg)));
+        }
+        let reply_msg = ReplyMsg {
+            time_stamp: 1,
+            view_id: 9999,
+            node_id: 2,
+            client_id: 0,
+            result: "op".to_string(),
+            certificate: Some(CommitCertificate {
+                view_id: 9999,
+                sequence_id: 3,
+                digest: "d".to_string(),
+                commits: vec![vote(0, MsgType::CommitMsg), vote(3, MsgType::CommitMsg)],
+                aggregate: Some(AggregateSignature { signers: [0, 2, 3].into_iter().collect(), signature: "a".to_string() }),
+            }),
+            queue_depth: Some(4),
+        };
+        same(&reply_msg, &ReplyMsg::from(proto::ReplyMsg::from(&reply_msg)));
+        let busy_msg = BusyMsg { view_id: 9999, node_id: 0, request_msg, retry_after_ms: 100, queue_depth: 8, signature: "s".to_string() };
+        same(&busy_msg, &BusyMsg::try_from(proto::BusyMsg::from(&busy_msg)).unwrap());
+        let new_key_msg = NewKeyMsg { node_id: 1, epoch: 5, public_key: "k".to_string(), signature: "s".to_string(), proof_of_possession: "p".to_string() };
+        same(&new_key_msg, &NewKeyMsg::from(proto::NewKeyMsg::from(&new_key_msg)));
+        let heartbeat_msg = HeartbeatMsg { node_id: 1, view_id: 9999, time_stamp: 6, signature: "s".to_string() };
+        same(&heartbeat_msg, &HeartbeatMsg::from(proto::HeartbeatMsg::from(&heartbeat_msg)));
+        let state_request_msg = StateRequestMsg { node_id: 1 };
+        same(&state_request_msg, &StateRequestMsg::from(proto::StateRequestMsg::from(&state_request_msg)));
+        let state_msg = StateMsg {
+            node_id: 1,
+            state_digest: "d".to_string(),
+            log: vec![CommittedEntry { view_id: 9999, sequence_id: 3, digest: "e".to_string() }],
+            signature: "s".to_string(),
+        };
+        same(&state_msg, &StateMsg::from(proto::StateMsg::from(&state_msg)));
+
+        // the request of a pre-prepare or busy message is required
+        let status = PrePrepareMsg::try_from(proto::PrePrepareMsg { request_msg: None, ..proto::PrePrepareMsg::from(&pre_prepare_msg) }).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = BusyMsg::try_from(proto::BusyMsg { request_msg: None, ..proto::BusyMsg::from(&busy_msg) }).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn only_rejected_calls_are_given_up() {
+        for code in [Code::InvalidArgument, Code::FailedPrecondition, Code::OutOfRange, Code::Unimplemented] {
+            assert_eq!(status_error(Status::new(code, "")).kind(), io::ErrorKind::InvalidData);
+        }
+        for code in [Code::ResourceExhausted, Code::Unavailable, Code::DeadlineExceeded, Code::Unknown] {
+            assert_eq!(status_error(Status::new(code, "")).kind(), io::ErrorKind::Other);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_request_and_its_reply_travel_between_client_and_replica() {
+        let replicas = HashMap::from([(0, loopback_address())]);
+        let clients = HashMap::from([(0, loopback_address())]);
+        let (replica, mut replica_inbound) = GrpcTransport::new(Endpoint::Replica(0), replicas.clone(), clients.clone(), Some(1024)).unwrap();
+        let (client, mut client_inbound) = GrpcTransport::new(Endpoint::Client(0), replicas, clients, Some(1024)).unwrap();
+        replica.start(Arc::new(|_| false)).unwrap();
+        client.start(Arc::new(|_| false)).unwrap();
+
+        // larger than the compression threshold
+        let operation = "x".repeat(64 * 1024);
+        client.send(Endpoint::Replica(0), Message::Request(request(operation.clone())));
+        assert!(matches!(receive(&mut replica_inbound).await, Message::Request(msg) if msg.operation == operation));
+        let reply_msg = ReplyMsg { time_stamp: 1, view_id: 9999, node_id: 0, client_id: 0, result: operation.clone(), certificate: None, queue_depth: None };
+        replica.send(Endpoint::Client(0), Message::Reply(reply_msg));
+        assert!(matches!(receive(&mut client_inbound).await, Message::Reply(msg) if msg.result == operation));
+        replica.stop();
+        client.stop();
+    }
+
+    #[tokio::test]
+    async fn compression_stops_towards_a_peer_that_rejects_it() {
+        // a replica that only accepts uncompressed calls
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (inbound_tx, mut inbound) = unbounded_channel();
+        let service = ReplicaServer::new(InboundService { inbound_tx });
+        tokio::spawn(Server::builder().add_service(service).serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let replicas = HashMap::from([(0, address)]);
+        let mut connection = GrpcConnection {
+            peer: Endpoint::Replica(0),
+            address: ClientAddresses::new(HashMap::new()).peer_address(&replicas, Endpoint::Replica(0)),
+            channel: None,
+            compress_above: Some(0),
+            accepts_zstd: true,
+        };
+        let msg = Message::Request(request("x".repeat(1024)));
+        // retransmitted, since only the compression was rejected
+        let e = connection.deliver(&msg).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert!(!connection.accepts_zstd);
+        connection.deliver(&msg).await.unwrap();
+        assert!(matches!(receive(&mut inbound).await, Message::Request(msg) if msg.operation.len() == 1024));
+    }
+}
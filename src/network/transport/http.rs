@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes, Data};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures::future::BoxFuture;
+use serde_json::json;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::consensus::message::{Message, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::network::tls::{scheme, TlsConfig};
+use crate::network::transport::peers::{Connection, PeerManager, PeerTransport, DELIVERY_TIMEOUT};
+use crate::network::transport::tcp::{MAX_FRAME_LEN, ZSTD_LEVEL};
+use crate::network::transport::{
+    parse_version, peers_of, ClientAddresses, Endpoint, Inbound, PeerAddress, Versions, MIN_VERSION_HEADER, VERSION_HEADER,
+};
+
+// Paths on which replicas receive messages.
+pub(crate) const REPLICA_PATHS: &[&str] = &["req", "preprepare", "prepare", "commit", "newkey", "staterequest", "state", "heartbeat"];
+// Paths on which the client receives messages, its "/req" is the API for users.
+pub(crate) const CLIENT_PATHS: &[&str] = &["reply", "busy", "newkey"];
+// Names the protocol version a body is encoded in.
+const MESSAGE_VERSION_HEADER: &str = "pbft-message-version";
+
+// JSON over HTTP POST, one path per message type (see `path`).
+// Outgoing messages are posted with reqwest over a kept-alive connection per peer (see `PeerManager`),
+// incoming ones are received by actix-web routes that the owner of the transport mounts
+// with `configure_inbound`, and are then put on the inbound stream.
+// Every message carries the protocol version of its sender in headers, receivers reject it with 400 if they are not compatible.
+// Every response carries the version of the receiver, after which the sender encodes its bodies in the older
+// of both versions. Until then it encodes them in its own version, which an older receiver rejects with 400,
+// and the message is sent again in the version of the receiver.
+// Receivers answer with `Accept-Encoding: zstd`, actix-web decompresses such bodies by itself.
+// Bodies may be as large as the frames of `tcp` once decompressed, instead of the 256 KiB actix-web allows by default.
+// Senders with a compression threshold post the larger bodies with `Content-Encoding: zstd` once a peer has answered so.
+pub(crate) struct HttpTransport {
+    replicas: HashMap<u32, String>, // Node.id -> url:port
+    clients: ClientAddresses, // client id -> url:port
+    scheme: &'static str,
+    http_clients: HashMap<String, reqwest::Client>, // identity of the peer -> client that only trusts its certificate
+    peers: PeerManager,
+    inbound_tx: UnboundedSender<Message>,
+    compress_above: Option<usize>, // bodies larger than this many bytes are compressed, if the peer accepts it
+}
+
+impl HttpTransport {
+    pub(crate) fn new(
+        endpoint: Endpoint,
+        replicas: HashMap<u32, String>,
+        clients: HashMap<u32, String>,
+        tls: &Option<TlsConfig>,
+        compress_above: Option<usize>,
+    ) -> io::Result<(Self, Inbound)> {
+        let identity = TlsConfig::identity(endpoint);
+        let mut http_clients = HashMap::new();
+        let shared = reqwest::Client::new();
+        for peer in replicas.keys().map(|id| TlsConfig::node_identity(*id)).chain([TlsConfig::client_identity()]) {
+            let http_client = match tls {
+                Some(tls) => tls.http_client(&identity, &peer)?,
+                None => shared.clone(),
+            };
+            http_clients.insert(peer, http_client);
+        }
+        let (inbound_tx, inbound) = unbounded_channel();
+        let transport = Self {
+            peers: PeerManager::new(peers_of(endpoint, &replicas, &clients)),
+            replicas,
+            clients: ClientAddresses::new(clients),
+            scheme: scheme(tls),
+            http_clients,
+            inbound_tx,
+            compress_above,
+        };
+        Ok((transport, inbound))
+    }
+
+    // Mounts one route per path in `paths`, messages posted there are put on the inbound stream.
+    pub(crate) fn configure_inbound(&self, cfg: &mut web::ServiceConfig, paths: &[&str]) {
+        for path in paths {
+            cfg.service(
+                web::resource(format!("/{}", path))
+                    .app_data(Data::new(self.inbound_tx.clone()))
+                    .app_data(web::PayloadConfig::new(MAX_FRAME_LEN as usize))
+                    .route(web::post().to(receive)),
+            );
+        }
+    }
+
+    fn connection(&self, peer: Endpoint) -> HttpConnection {
+        HttpConnection {
+            http_client: self.http_clients[&TlsConfig::identity(peer)].clone(),
+            scheme: self.scheme,
+            address: self.clients.peer_address(&self.replicas, peer),
+            posted_to: String::new(),
+            version: PROTOCOL_VERSION,
+            compress_above: self.compress_above,
+            accepts_zstd: false,
+        }
+    }
+}
+
+// The listener is an HTTP server of the node, which starts and stops it by itself
+impl PeerTransport for HttpTransport {
+    type Connection = HttpConnection;
+
+    fn peers(&self) -> &PeerManager {
+        &self.peers
+    }
+
+    fn client_addresses(&self) -> &ClientAddresses {
+        &self.clients
+    }
+
+    fn connect(&self, peer: Endpoint) -> impl Fn() -> Self::Connection + '_ {
+        move || self.connection(peer)
+    }
+}
+
+// reqwest keeps the connection to the peer alive between messages,
+// and the sender task posts one message at a time, so a single connection is used.
+// A successful response acknowledges the message.
+pub(crate) struct HttpConnection {
+    http_client: reqwest::Client,
+    scheme: &'static str,
+    address: PeerAddress, // url:port of the peer
+    posted_to: String, // the address of the peer on the last message, the peer may have moved since
+    version: u16, // of the bodies, learned from the responses of the peer
+    compress_above: Option<usize>,
+    accepts_zstd: bool, // learned from the responses of the peer, so the first body is never compressed
+}
+
+impl Connection for HttpConnection {
+    fn deliver<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let address = self.address.current()?;
+            if address != self.posted_to {
+                self.accepts_zstd = false; // another peer may not
+                self.version = PROTOCOL_VERSION;
+                self.posted_to = address.clone();
+            }
+            let url = format!("{}://{}/{}", self.scheme, address, path(msg));
+            let body = encode(msg)?;
+            let mut request = self.http_client.post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(VERSION_HEADER, PROTOCOL_VERSION)
+                .header(MIN_VERSION_HEADER, MIN_PROTOCOL_VERSION)
+                .header(MESSAGE_VERSION_HEADER, self.version);
+            request = match self.compress_above {
+                Some(threshold) if self.accepts_zstd && body.len() > threshold => request
+                    .header(reqwest::header::CONTENT_ENCODING, "zstd")
+                    .body(zstd::bulk::compress(&body, ZSTD_LEVEL)?),
+                _ => request.body(body),
+            };
+            let response = request.timeout(DELIVERY_TIMEOUT).send().await.map_err(io::Error::other)?;
+            let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+            let encoded_in = self.version;
+            if let Ok(version) = Versions::from_headers(header(VERSION_HEADER), header(MIN_VERSION_HEADER))
+                .and_then(|peer| Versions::CURRENT.negotiate(peer))
+            {
+                self.version = version;
+            }
+            if !response.status().is_success() {
+                // the body tells why, such as an incompatible protocol version
+                // client errors mean the peer rejected this message, except for a timeout or a rate limit,
+                // or a body in a version newer than the peer, which is sent again in its version
+                let status = response.status();
+                let kind = match status {
+                    _ if self.version != encoded_in => io::ErrorKind::Other,
+                    reqwest::StatusCode::REQUEST_TIMEOUT | reqwest::StatusCode::TOO_MANY_REQUESTS => io::ErrorKind::Other,
+                    status if status.is_client_error() => io::ErrorKind::InvalidData,
+                    _ => io::ErrorKind::Other,
+                };
+                let body = response.text().await.unwrap_or_default();
+                return Err(io::Error::new(kind, format!("{} from {}: {}", status, url, body)));
+            }
+            self.accepts_zstd = response.headers().get(reqwest::header::ACCEPT_ENCODING)
+                .and_then(|encodings| encodings.to_str().ok())
+                .is_some_and(|encodings| encodings.split(',').any(|encoding| encoding.trim() == "zstd"));
+            Ok(())
+        })
+    }
+}
+
+fn path(msg: &Message) -> &'static str {
+    match msg {
+        Message::Request(_) => "req",
+        Message::PrePrepare(_) => "preprepare",
+        Message::Prepare(_) => "prepare",
+        Message::Commit(_) => "commit",
+        Message::Reply(_) => "reply",
+        Message::Busy(_) => "busy",
+        Message::NewKey(_) => "newkey",
+        Message::StateRequest(_) => "staterequest",
+        Message::State(_) => "state",
+        Message::Heartbeat(_) => "heartbeat",
+    }
+}
+
+// Bodies do not depend on the version they are announced in yet, see `tcp::encode_frame`.
+fn encode(msg: &Message) -> serde_json::Result<Vec<u8>> {
+    match msg {
+        Message::Request(msg) => serde_json::to_vec(msg),
+        Message::PrePrepare(msg) => serde_json::to_vec(msg),
+        Message::Prepare(msg) | Message::Commit(msg) => serde_json::to_vec(msg),
+        Message::Reply(msg) => serde_json::to_vec(msg),
+        Message::Busy(msg) => serde_json::to_vec(msg),
+        Message::NewKey(msg) => serde_json::to_vec(msg),
+        Message::StateRequest(msg) => serde_json::to_vec(msg),
+        Message::State(msg) => serde_json::to_vec(msg),
+        Message::Heartbeat(msg) => serde_json::to_vec(msg),
+    }
+}
+
+fn parse(path: &str, body: &[u8]) -> serde_json::Result<Message> {
+    Ok(match path {
+        "req" => Message::Request(serde_json::from_slice(body)?),
+        "preprepare" => Message::PrePrepare(serde_json::from_slice(body)?),
+        "prepare" => Message::Prepare(serde_json::from_slice(body)?),
+        "commit" => Message::Commit(serde_json::from_slice(body)?),
+        "reply" => Message::Reply(serde_json::from_slice(body)?),
+        "busy" => Message::Busy(serde_json::from_slice(body)?),
+        "newkey" => Message::NewKey(serde_json::from_slice(body)?),
+        "staterequest" => Message::StateRequest(serde_json::from_slice(body)?),
+        "state" => Message::State(serde_json::from_slice(body)?),
+        "heartbeat" => Message::Heartbeat(serde_json::from_slice(body)?),
+        _ => unreachable!("no route is mounted for /{}", path),
+    })
+}
+
+async fn receive(request: HttpRequest, body: Bytes, inbound_tx: Data<UnboundedSender<Message>>) -> HttpResponse {
+    let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok());
+    let compatible = Versions::from_headers(header(VERSION_HEADER), header(MIN_VERSION_HEADER))
+        .and_then(|peer| Versions::CURRENT.negotiate(peer))
+        .and_then(|_| message_version(header(MESSAGE_VERSION_HEADER)));
+    if let Err(e) = compatible {
+        eprintln!(" -- Rejected message: {}", e);
+        return versioned(HttpResponse::BadRequest()).json(json!({"status": "error", "error": e.to_string()}));
+    }
+    match parse(request.path().trim_start_matches('/'), &body) {
+        Ok(msg) => {
+            let _ = inbound_tx.send(msg); // the node is shutting down if nobody is receiving
+            versioned(HttpResponse::Ok())
+                .insert_header((header::ACCEPT_ENCODING, "zstd"))
+                .json(json!({"status": "ok"}))
+        }
+        Err(e) => versioned(HttpResponse::BadRequest()).json(json!({"status": "error", "error": e.to_string()})),
+    }
+}
+
+// The version a body is encoded in, which this node must be able to decode.
+fn message_version(value: Option<&str>) -> io::Result<u16> {
+    let value = value.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "the body has no protocol version"))?;
+    let version = parse_version(value)?;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        let e = format!("the body is encoded in protocol version {}, this node speaks {}", version, PROTOCOL_VERSION);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    Ok(version)
+}
+
+// Announces the protocol versions of this node on a response, see `HttpConnection.version`.
+fn versioned(mut response: HttpResponseBuilder) -> HttpResponseBuilder {
+    response
+        .insert_header((VERSION_HEADER, PROTOCOL_VERSION.to_string()))
+        .insert_header((MIN_VERSION_HEADER, MIN_PROTOCOL_VERSION.to_string()));
+    response
+}
+
+// Where an HTTP transport receives its messages: the node runs an actix-web server on `address`
+// and mounts the routes of `transport` (see `HttpTransport::configure_inbound`).
+#[derive(Clone)]
+pub(crate) struct HttpListener {
+    pub(crate) address: String, // url:port
+    pub(crate) transport: Arc<HttpTransport>,
+    pub(crate) tls: Option<TlsConfig>,
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpServer};
+    use super::*;
+    use crate::consensus::message::RequestMsg;
+
+    #[test]
+    fn large_operations_are_received() {
+        actix_web::rt::System::new().block_on(async {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let replicas = HashMap::from([(0, address.clone())]);
+            let (transport, mut inbound) = HttpTransport::new(Endpoint::Replica(0), replicas, HashMap::new(), &None, Some(1024)).unwrap();
+            let transport = Arc::new(transport);
+            let routes = transport.clone();
+            let server = HttpServer::new(move || {
+                let routes = routes.clone();
+                App::new().configure(move |cfg| routes.configure_inbound(cfg, REPLICA_PATHS))
+            });
+            let server = server.listen(listener).unwrap().run();
+            let server_handle = server.handle();
+            actix_web::rt::spawn(server);
+
+            // well above the default limit of actix-web, once plain and once compressed
+            let operation = "x".repeat(4 * 1024 * 1024);
+            let mut connection = transport.connection(Endpoint::Replica(0));
+            for time_stamp in [1, 2] {
+                let request_msg = RequestMsg {
+                    operation: operation.clone(),
+                    time_stamp,
+                    client_id: 0,
+                    sequence_id: 0,
+                    digest: String::new(),
+                    reply_address: None,
+                    client_key: None,
+                    client_signature: None,
+                };
+                connection.deliver(&Message::Request(request_msg)).await.unwrap();
+                let Some(Message::Request(received)) = inbound.recv().await else {
+                    panic!("expected the request");
+                };
+                assert_eq!(received.operation, operation);
+                assert!(connection.accepts_zstd);
+            }
+            server_handle.stop(false).await;
+        });
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::consensus::message::Message;
+use crate::network::transport::{Endpoint, Inbound, Transport};
+
+// In-process network: every node that joins gets a channel, sending is a channel send.
+// Nothing is serialized and no ports are bound, so whole clusters can run inside one process.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<Endpoint, UnboundedSender<Message>>>>,
+}
+
+impl MemoryNetwork {
+    pub(crate) fn join(&self, endpoint: Endpoint) -> (MemoryTransport, Inbound) {
+        let (inbound_tx, inbound) = unbounded_channel();
+        self.endpoints.lock().unwrap().insert(endpoint, inbound_tx);
+        let transport = MemoryTransport {
+            endpoint,
+            network: self.clone(),
+        };
+        (transport, inbound)
+    }
+}
+
+pub(crate) struct MemoryTransport {
+    endpoint: Endpoint,
+    network: MemoryNetwork,
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, to: Endpoint, msg: Message) {
+        match self.network.endpoints.lock().unwrap().get(&to) {
+            Some(inbound_tx) => {
+                let _ = inbound_tx.send(msg); // the receiving node has stopped
+            }
+            None => eprintln!(" -- Unknown endpoint {:?}", to),
+        }
+    }
+
+    fn broadcast(&self, msg: Message) {
+        for (endpoint, inbound_tx) in self.network.endpoints.lock().unwrap().iter() {
+            if matches!(endpoint, Endpoint::Replica(_)) && *endpoint != self.endpoint {
+                let _ = inbound_tx.send(msg.clone());
+            }
+        }
+    }
+
+    fn clients(&self) -> Vec<u32> {
+        self.network.endpoints.lock().unwrap().keys()
+            .filter_map(|endpoint| match endpoint {
+                Endpoint::Client(id) => Some(*id),
+                Endpoint::Replica(_) => None,
+            })
+            .collect()
+    }
+}
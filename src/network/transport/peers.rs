@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use futures::future::BoxFuture;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio::time::Instant;
+use crate::consensus::message::Message;
+use crate::network::transport::{ClientAddresses, Endpoint, IsObsolete, Registration, Tasks, Transport};
+
+// Messages waiting for a peer, beyond that new messages to the peer are dropped
+const OUTBOUND_QUEUE_CAPACITY: usize = 1024;
+// Delay before the first retransmission, doubled after every failed attempt up to `MAX_BACKOFF`
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// How long a message may wait for its peer, in the queue and retransmitted, before it is dropped.
+// It bounds how long a peer that is down holds back each message queued behind.
+const MAX_DELIVERY_TIME: Duration = Duration::from_secs(10);
+// How long a peer may take to acknowledge a message before it is sent again
+pub(crate) const DELIVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A long-lived connection to one peer, used by a single sender task.
+pub(crate) trait Connection: Send + 'static {
+    // Delivers `msg` to the peer, reconnecting if needed.
+    // Only returns Ok once the peer has acknowledged the message.
+    fn deliver<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, io::Result<()>>;
+}
+
+// A transport that sends through a `PeerManager`. `Transport` is implemented for all of them in terms of these,
+// so that each only tells how it receives messages and connects to its peers.
+pub(crate) trait PeerTransport: Send + Sync {
+    type Connection: Connection;
+
+    fn peers(&self) -> &PeerManager;
+
+    fn client_addresses(&self) -> &ClientAddresses;
+
+    // Makes the connections to `peer`, one for each lane. Lanes may share state, such as the connection they are multiplexed on.
+    fn connect(&self, peer: Endpoint) -> impl Fn() -> Self::Connection + '_;
+
+    // Starts receiving messages, before the sender tasks are started.
+    fn listen(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Stops receiving messages and closes the sockets of the listener.
+    fn close(&self) {}
+}
+
+impl<T: PeerTransport> Transport for T {
+    fn start(&self, is_obsolete: IsObsolete) -> io::Result<()> {
+        self.listen()?;
+        self.peers().start(|peer| self.connect(peer), is_obsolete);
+        Ok(())
+    }
+
+    fn queued(&self) -> usize {
+        self.peers().queued()
+    }
+
+    fn stop(&self) {
+        self.close();
+        self.peers().stop();
+    }
+
+    fn send(&self, to: Endpoint, msg: Message) {
+        self.peers().enqueue(to, msg);
+    }
+
+    fn broadcast(&self, msg: Message) {
+        for peer in self.peers().peers() {
+            if matches!(peer, Endpoint::Replica(_)) {
+                self.peers().enqueue(peer, msg.clone());
+            }
+        }
+    }
+
+    fn clients(&self) -> Vec<u32> {
+        self.client_addresses().ids()
+    }
+
+    fn register_client(&self, id: u32, address: &str, client_key: &str, is_ordered: bool) {
+        let clients = self.client_addresses();
+        if let Registration::New { evicted } = clients.register(id, address, client_key, is_ordered) {
+            if let Some(evicted) = evicted {
+                self.peers().remove(Endpoint::Client(evicted));
+            }
+            self.peers().add(Endpoint::Client(id), self.connect(Endpoint::Client(id)));
+        }
+    }
+}
+
+// Outbound side of a transport: one bounded queue and one connection per peer,
+// drained in order by a sender task per peer. Sending only enqueues, so message handlers
+// never wait for peers, and connections are set up once instead of once per message.
+// A message is retransmitted with exponential backoff until the peer acknowledges it,
+// or until the node reports it as obsolete, so short network blips do not cost a quorum.
+// It is dropped right away if the peer has rejected it (see `is_permanent`), and `MAX_DELIVERY_TIME` after
+// it was enqueued otherwise. Messages that have waited that long behind others are dropped without being sent,
+// so a queue to a peer that is down drains, and the peer gets the recent messages once it is back.
+//
+// Transports that can multiplex a connection split the traffic to a peer into lanes (see `with_lanes`),
+// each with its own queue, sender task and connection, so a message waiting for retransmission
+// only holds back the messages of its own lane.
+//
+// The peers are the nodes of the configuration, plus the clients that replicas learn about from their requests (see `add`).
+pub(crate) struct PeerManager {
+    queues: RwLock<HashMap<(Endpoint, usize), Sender<Queued>>>, // (peer, lane) -> queue
+    receivers: Mutex<Vec<(Endpoint, Receiver<Queued>)>>, // handed to the sender tasks on `start`
+    lanes: usize,
+    lane: fn(&Message) -> usize,
+    is_obsolete: OnceLock<IsObsolete>, // set on `start`
+    senders: Tasks,
+    queued: Arc<AtomicUsize>, // messages enqueued and not yet acknowledged or obsolete
+}
+
+impl PeerManager {
+    pub(crate) fn new(peers: impl IntoIterator<Item = Endpoint>) -> Self {
+        Self::with_lanes(peers, 1, |_| 0)
+    }
+
+    // `lane` tells which of the `lanes` lanes a message is sent on.
+    pub(crate) fn with_lanes(peers: impl IntoIterator<Item = Endpoint>, lanes: usize, lane: fn(&Message) -> usize) -> Self {
+        let mut queues = HashMap::new();
+        let mut receivers = Vec::new();
+        for peer in peers {
+            for i in 0..lanes {
+                let (queue, receiver) = channel(OUTBOUND_QUEUE_CAPACITY);
+                queues.insert((peer, i), queue);
+                receivers.push((peer, receiver));
+            }
+        }
+        Self {
+            queues: RwLock::new(queues),
+            receivers: Mutex::new(receivers),
+            lanes,
+            lane,
+            is_obsolete: OnceLock::new(),
+            senders: Tasks::default(),
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // Spawns the sender task of every peer and lane, with the connections made by `connect(peer)` for its lanes.
+    // Must be called from the runtime of the node, later calls do nothing.
+    pub(crate) fn start<C: Connection, F: Fn() -> C>(&self, connect: impl Fn(Endpoint) -> F, is_obsolete: IsObsolete) {
+        let is_obsolete = self.is_obsolete.get_or_init(|| is_obsolete);
+        let mut connections = HashMap::new();
+        for (peer, receiver) in self.receivers.lock().unwrap().drain(..) {
+            let connect = connections.entry(peer).or_insert_with(|| connect(peer));
+            self.spawn_sender(peer, receiver, connect(), is_obsolete.clone());
+        }
+    }
+
+    // Adds a peer that is not in the configuration, with a connection made by `connect` for each lane.
+    // Only once started, from the runtime of the node. A peer that is known already is left as it is.
+    pub(crate) fn add<C: Connection>(&self, peer: Endpoint, connect: impl Fn() -> C) {
+        let Some(is_obsolete) = self.is_obsolete.get() else {
+            eprintln!(" -- Cannot add {:?} before the transport has started", peer);
+            return;
+        };
+        let mut queues = self.queues.write().unwrap();
+        if queues.contains_key(&(peer, 0)) {
+            return;
+        }
+        for i in 0..self.lanes {
+            let (queue, receiver) = channel(OUTBOUND_QUEUE_CAPACITY);
+            queues.insert((peer, i), queue);
+            self.spawn_sender(peer, receiver, connect(), is_obsolete.clone());
+        }
+    }
+
+    // Forgets a peer, its sender tasks stop once they have gone through the messages queued for it.
+    pub(crate) fn remove(&self, peer: Endpoint) {
+        self.queues.write().unwrap().retain(|(known, _), _| *known != peer);
+    }
+
+    fn spawn_sender(&self, peer: Endpoint, mut receiver: Receiver<Queued>, mut connection: impl Connection, is_obsolete: IsObsolete) {
+        let queued = self.queued.clone();
+        self.senders.spawn(async move {
+            while let Some(Queued { msg, enqueued_at }) = receiver.recv().await {
+                let deadline = enqueued_at + MAX_DELIVERY_TIME;
+                let mut backoff = INITIAL_BACKOFF;
+                while !is_obsolete(&msg) {
+                    if Instant::now() >= deadline {
+                        eprintln!(" -- Dropping message to {:?}, not delivered within {:?}", peer, MAX_DELIVERY_TIME);
+                        break;
+                    }
+                    let Err(e) = connection.deliver(&msg).await else {
+                        break;
+                    };
+                    if is_permanent(&e) {
+                        eprintln!(" -- Error sending message to {:?}: {}, dropping it", peer, e);
+                        break;
+                    }
+                    let delay = backoff.min(deadline.saturating_duration_since(Instant::now()));
+                    eprintln!(" -- Error sending message to {:?}: {}, retrying in {:?}", peer, e, delay);
+                    tokio::time::sleep(delay).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                queued.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    // Aborts the sender tasks, closing their connections. Messages enqueued from then on are dropped.
+    pub(crate) fn stop(&self) {
+        self.senders.abort();
+    }
+
+    pub(crate) fn enqueue(&self, to: Endpoint, msg: Message) {
+        let queues = self.queues.read().unwrap();
+        let Some(queue) = queues.get(&(to, (self.lane)(&msg))) else {
+            eprintln!(" -- Unknown endpoint {:?}", to);
+            return;
+        };
+        // counted before the sender task can take it from the queue
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let result = queue.try_send(Queued { msg, enqueued_at: Instant::now() });
+        if result.is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => eprintln!(" -- Outbound queue to {:?} is full, dropping message", to),
+            Err(TrySendError::Closed(_)) => {} // the node is shutting down
+        }
+    }
+
+    // Messages waiting for a peer or being delivered
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn peers(&self) -> Vec<Endpoint> {
+        self.queues.read().unwrap().keys().filter(|(_, lane)| *lane == 0).map(|(peer, _)| *peer).collect()
+    }
+}
+
+// A message in the queue to a peer
+struct Queued {
+    msg: Message,
+    enqueued_at: Instant,
+}
+
+// Whether a delivery failed for good: connections report with `InvalidData` or `InvalidInput`
+// that the peer has rejected the message itself, because it is malformed, too large or of an incompatible protocol version.
+// Sending it again would fail the same way, unlike after a network error or a timeout.
+pub(crate) fn is_permanent(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use super::*;
+    use crate::consensus::message::HeartbeatMsg;
+
+    // Rejects the heartbeats with an odd time stamp, and records every attempt.
+    struct PickyConnection {
+        attempts: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Connection for PickyConnection {
+        fn deliver<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                let Message::Heartbeat(msg) = msg else { unreachable!() };
+                self.attempts.lock().unwrap().push(msg.time_stamp);
+                if msg.time_stamp % 2 == 1 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "rejected"));
+                }
+                Ok(())
+            })
+        }
+    }
+
+    // A peer that is down while `up` is false, and records the time of every attempt.
+    struct FlakyConnection {
+        up: Arc<AtomicBool>,
+        attempts: Arc<Mutex<Vec<(u64, Instant)>>>,
+    }
+
+    impl Connection for FlakyConnection {
+        fn deliver<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                let Message::Heartbeat(msg) = msg else { unreachable!() };
+                self.attempts.lock().unwrap().push((msg.time_stamp, Instant::now()));
+                if !self.up.load(Ordering::Relaxed) {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down"));
+                }
+                Ok(())
+            })
+        }
+    }
+
+    struct FlakyPeer {
+        peers: PeerManager,
+        up: Arc<AtomicBool>,
+        attempts: Arc<Mutex<Vec<(u64, Instant)>>>,
+    }
+
+    impl FlakyPeer {
+        fn start() -> Self {
+            let up = Arc::new(AtomicBool::new(false));
+            let attempts = Arc::new(Mutex::new(Vec::new()));
+            let peers = PeerManager::new([PEER]);
+            let connection = || FlakyConnection { up: up.clone(), attempts: attempts.clone() };
+            peers.start(|_| connection, Arc::new(|_| false));
+            Self { peers, up, attempts }
+        }
+
+        fn attempts(&self, time_stamp: u64) -> Vec<Instant> {
+            self.attempts.lock().unwrap().iter().filter(|(sent, _)| *sent == time_stamp).map(|(_, at)| *at).collect()
+        }
+    }
+
+    const PEER: Endpoint = Endpoint::Replica(1);
+
+    fn heartbeat(time_stamp: u64) -> Message {
+        Message::Heartbeat(HeartbeatMsg { node_id: 0, view_id: 0, time_stamp, signature: String::new() })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmissions_back_off_exponentially() {
+        let peer = FlakyPeer::start();
+        peer.peers.enqueue(PEER, heartbeat(1));
+        tokio::time::sleep(Duration::from_millis(60 + 120 + 240 + 480 + 960)).await;
+        let attempts = peer.attempts(1);
+        let delays: Vec<Duration> = attempts.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert_eq!(delays[..4], [50, 100, 200, 400].map(Duration::from_millis));
+        // the peer is back, and acknowledges the next retransmission
+        peer.up.store(true, Ordering::Relaxed);
+        tokio::time::sleep(MAX_BACKOFF).await;
+        assert_eq!(peer.peers.queued(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_peer_that_is_down_holds_back_messages_for_a_bounded_time() {
+        let peer = FlakyPeer::start();
+        let start = Instant::now();
+        for time_stamp in 1..=3 {
+            peer.peers.enqueue(PEER, heartbeat(time_stamp));
+        }
+        tokio::time::sleep(MAX_DELIVERY_TIME + MAX_BACKOFF).await;
+        // the first message is retransmitted until its time is up, the two behind it have waited as long and are dropped unsent
+        assert_eq!(peer.peers.queued(), 0);
+        assert!(peer.attempts(1).iter().all(|at| *at < start + MAX_DELIVERY_TIME));
+        assert!(peer.attempts(2).is_empty() && peer.attempts(3).is_empty());
+
+        // once the peer is back, new messages reach it at once
+        peer.up.store(true, Ordering::Relaxed);
+        peer.peers.enqueue(PEER, heartbeat(4));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(peer.attempts(4).len(), 1);
+        assert_eq!(peer.peers.queued(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_beyond_the_queue_capacity_are_dropped() {
+        let peer = FlakyPeer::start();
+        peer.up.store(true, Ordering::Relaxed);
+        // the sender task only runs once the test yields, the queue is full by then
+        for time_stamp in 0..OUTBOUND_QUEUE_CAPACITY as u64 + 10 {
+            peer.peers.enqueue(PEER, heartbeat(time_stamp));
+        }
+        assert_eq!(peer.peers.queued(), OUTBOUND_QUEUE_CAPACITY);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(peer.peers.queued(), 0);
+        let attempts = peer.attempts.lock().unwrap();
+        assert_eq!(attempts.len(), OUTBOUND_QUEUE_CAPACITY);
+        assert_eq!(attempts.last().unwrap().0, OUTBOUND_QUEUE_CAPACITY as u64 - 1);
+    }
+
+    #[test]
+    fn rejected_messages_are_not_retransmitted() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        runtime.block_on(async {
+            let peer = Endpoint::Replica(1);
+            let peers = PeerManager::new([peer]);
+            peers.start(|_| || PickyConnection { attempts: attempts.clone() }, Arc::new(|_| false));
+            peers.enqueue(peer, heartbeat(1));
+            peers.enqueue(peer, heartbeat(2));
+            for _ in 0..100 {
+                if peers.queued() == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(peers.queued(), 0);
+        });
+        // the rejected message did not hold back the next one
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn only_rejections_are_permanent() {
+        assert!(is_permanent(&io::Error::new(io::ErrorKind::InvalidData, "incompatible protocol version")));
+        assert!(is_permanent(&io::Error::new(io::ErrorKind::InvalidInput, "frame too large")));
+        assert!(!is_permanent(&io::Error::new(io::ErrorKind::TimedOut, "no acknowledgement")));
+        assert!(!is_permanent(&io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")));
+    }
+}
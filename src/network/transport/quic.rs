@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Duration;
+use futures::future::BoxFuture;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::Mutex;
+use crate::consensus::message::Message;
+use crate::network::tls::TlsConfig;
+use crate::network::transport::peers::{Connection, PeerManager, PeerTransport, DELIVERY_TIMEOUT};
+use crate::network::transport::tcp::{receive, Framing};
+use crate::network::transport::{own_address, peers_of, ClientAddresses, Endpoint, Inbound, PeerAddress, Tasks};
+
+const ALPN: &[u8] = b"pbft";
+// Keeps idle connections open, QUIC closes them after 30 seconds without packets
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+// One lane per message type, see `lane`
+const LANES: usize = 9;
+
+// QUIC with mutual TLS, one connection per peer and one stream per message type on it.
+// Streams are independent, so a lost packet of a commit only delays the commits behind it,
+// not the prepares and pre-prepares of other sequences as it would on a single TCP connection.
+// Messages are framed and acknowledged as with the tcp transport.
+// Certificates come from the `tls_dir` of the cluster, or are generated when the whole cluster runs in one process.
+pub(crate) struct QuicTransport {
+    endpoint: Endpoint,
+    address: String, // url:port this node listens on (UDP)
+    replicas: HashMap<u32, String>, // Node.id -> url:port
+    clients: ClientAddresses, // client id -> url:port
+    tls: TlsConfig,
+    peers: PeerManager,
+    inbound_tx: UnboundedSender<Message>,
+    bound: Arc<SyncMutex<Option<quinn::Endpoint>>>, // while started, taken on stop to release the socket
+    compress_above: Option<usize>, // see `Framing`, negotiated on every stream
+    tasks: Tasks, // accepting connections and receiving on their streams
+}
+
+impl QuicTransport {
+    pub(crate) fn new(
+        endpoint: Endpoint,
+        replicas: HashMap<u32, String>,
+        clients: HashMap<u32, String>,
+        tls: TlsConfig,
+        compress_above: Option<usize>,
+    ) -> io::Result<(Self, Inbound)> {
+        let address = own_address(endpoint, &replicas, &clients)?;
+        let (inbound_tx, inbound) = unbounded_channel();
+        let transport = Self {
+            endpoint,
+            address,
+            peers: PeerManager::with_lanes(peers_of(endpoint, &replicas, &clients), LANES, lane),
+            replicas,
+            clients: ClientAddresses::new(clients),
+            tls,
+            inbound_tx,
+            bound: Arc::new(SyncMutex::new(None)),
+            compress_above,
+            tasks: Tasks::default(),
+        };
+        Ok((transport, inbound))
+    }
+
+    // QUIC endpoint of this node, accepting connections from the replicas and the client.
+    fn bind(&self) -> io::Result<quinn::Endpoint> {
+        let identity = TlsConfig::identity(self.endpoint);
+        let allowed_peers = self.replicas.keys().map(|id| TlsConfig::node_identity(*id))
+            .chain([TlsConfig::client_identity()])
+            .collect();
+        let mut server_crypto = self.tls.server_config(&identity, allowed_peers)?;
+        server_crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let server_crypto = QuicServerConfig::try_from(server_crypto).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut client_crypto = self.tls.client_config(&identity)?;
+        client_crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let client_crypto = QuicClientConfig::try_from(client_crypto).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut transport_config = quinn::TransportConfig::default();
+        transport_config.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+        client_config.transport_config(Arc::new(transport_config));
+
+        let address = prefer_ipv4(self.address.to_socket_addrs()?, &self.address)?;
+        let mut endpoint = quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(server_crypto)), address)?;
+        endpoint.set_default_client_config(client_config);
+        Ok(endpoint)
+    }
+}
+
+impl PeerTransport for QuicTransport {
+    type Connection = QuicStream;
+
+    fn peers(&self) -> &PeerManager {
+        &self.peers
+    }
+
+    fn client_addresses(&self) -> &ClientAddresses {
+        &self.clients
+    }
+
+    // The lanes share the connection to the peer, it is only resolved and made on the first message.
+    fn connect(&self, peer: Endpoint) -> impl Fn() -> Self::Connection + '_ {
+        let quic_peer = Arc::new(QuicPeer {
+            endpoint: self.bound.clone(),
+            address: self.clients.peer_address(&self.replicas, peer),
+            server_name: TlsConfig::identity(peer),
+            connection: Mutex::new(None),
+        });
+        move || QuicStream {
+            peer: quic_peer.clone(),
+            stream: None,
+            framing: Framing::new(self.compress_above),
+        }
+    }
+
+    fn listen(&self) -> io::Result<()> {
+        let endpoint = self.bind()?;
+        *self.bound.lock().unwrap() = Some(endpoint.clone());
+        let inbound_tx = self.inbound_tx.clone();
+        let tasks = self.tasks.clone();
+        self.tasks.spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                tasks.spawn(accept(incoming, inbound_tx.clone(), tasks.clone()));
+            }
+        });
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.tasks.abort();
+        if let Some(endpoint) = self.bound.lock().unwrap().take() {
+            endpoint.close(0u32.into(), b"shutdown");
+        }
+    }
+}
+
+fn lane(msg: &Message) -> usize {
+    match msg {
+        Message::Request(_) => 0,
+        Message::PrePrepare(_) => 1,
+        Message::Prepare(_) => 2,
+        Message::Commit(_) => 3,
+        Message::Reply(_) => 4,
+        Message::Busy(_) => 5,
+        Message::NewKey(_) => 6,
+        Message::StateRequest(_) | Message::State(_) => 7,
+        Message::Heartbeat(_) => 8,
+    }
+}
+
+// Picks one of the addresses `address` resolves to, preferring IPv4 so that "localhost" resolves the same way on every node.
+fn prefer_ipv4(addresses: impl Iterator<Item = SocketAddr>, address: &str) -> io::Result<SocketAddr> {
+    let addresses: Vec<SocketAddr> = addresses.collect();
+    addresses.iter().find(|address| address.is_ipv4()).or(addresses.first()).copied()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} does not resolve to an address", address)))
+}
+
+// Receives the streams of an incoming connection, each of them carries the frames of one message type.
+async fn accept(incoming: quinn::Incoming, inbound_tx: UnboundedSender<Message>, tasks: Tasks) {
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!(" -- Error accepting QUIC connection: {}", e);
+            return;
+        }
+    };
+    while let Ok((send, recv)) = connection.accept_bi().await {
+        tasks.spawn(receive(recv, send, inbound_tx.clone()));
+    }
+}
+
+// The connection to a peer, shared by the streams of all lanes.
+struct QuicPeer {
+    endpoint: Arc<SyncMutex<Option<quinn::Endpoint>>>, // of this node, while started
+    address: PeerAddress, // url:port of the peer
+    server_name: String, // identity the certificate of the peer must be issued for
+    connection: Mutex<Option<(String, quinn::Connection)>>, // and the address it is connected to
+}
+
+impl QuicPeer {
+    // Connects on first use, and again once the connection has closed or the peer has moved.
+    async fn connection(&self) -> io::Result<quinn::Connection> {
+        let address = self.address.current()?;
+        let mut connection = self.connection.lock().await;
+        let open = connection.as_ref()
+            .filter(|(connected_to, connection)| *connected_to == address && connection.close_reason().is_none());
+        if let Some((_, connection)) = open {
+            return Ok(connection.clone());
+        }
+        let socket_address = prefer_ipv4(tokio::net::lookup_host(&address).await?, &address)?;
+        let endpoint = self.endpoint.lock().unwrap().clone().ok_or_else(|| io::Error::other("the transport is not running"))?;
+        let connecting = endpoint.connect(socket_address, &self.server_name).map_err(io::Error::other)?;
+        let connected = connecting.await?;
+        *connection = Some((address, connected.clone()));
+        Ok(connected)
+    }
+}
+
+// One lane to a peer: a stream opened on the first message, and again on the next one after an error
+// or once the connection to the peer has changed.
+pub(crate) struct QuicStream {
+    peer: Arc<QuicPeer>,
+    stream: Option<(usize, quinn::SendStream, quinn::RecvStream)>, // and the `stable_id` of its connection
+    framing: Framing,
+}
+
+impl QuicStream {
+    async fn write_message(&mut self, msg: &Message) -> io::Result<()> {
+        let connection = self.peer.connection().await?;
+        if self.stream.as_ref().is_some_and(|(opened_on, _, _)| *opened_on != connection.stable_id()) {
+            self.stream = None;
+        }
+        if self.stream.is_none() {
+            let (mut send, mut recv) = connection.open_bi().await?;
+            self.framing.negotiate(&mut recv, &mut send).await?;
+            self.stream = Some((connection.stable_id(), send, recv));
+        }
+        let (_, send, recv) = self.stream.as_mut().unwrap();
+        self.framing.write_message(recv, send, msg).await
+    }
+}
+
+impl Connection for QuicStream {
+    fn deliver<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let result = tokio::time::timeout(DELIVERY_TIMEOUT, self.write_message(msg)).await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "no acknowledgement")));
+            if result.is_err() {
+                self.stream = None; // reopen on the next message
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::message::{HeartbeatMsg, RequestMsg};
+    use crate::network::transport::Transport;
+
+    // Two replicas on loopback ports, with certificates of a CA generated for the test.
+    fn replicas() -> (HashMap<u32, String>, TlsConfig) {
+        let replicas = (0..2)
+            .map(|id| {
+                let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                (id, socket.local_addr().unwrap().to_string())
+            })
+            .collect();
+        let tls = TlsConfig::generate([0, 1].map(TlsConfig::node_identity).into_iter().chain([TlsConfig::client_identity()])).unwrap();
+        (replicas, tls)
+    }
+
+    fn replica(id: u32, replicas: &HashMap<u32, String>, tls: &TlsConfig) -> (QuicTransport, Inbound) {
+        let (transport, inbound) = QuicTransport::new(Endpoint::Replica(id), replicas.clone(), HashMap::new(), tls.clone(), Some(1024)).unwrap();
+        transport.start(Arc::new(|_| false)).unwrap();
+        (transport, inbound)
+    }
+
+    // `replica`, once the endpoint of its previous run has released the port, which happens in the background.
+    async fn restart(id: u32, replicas: &HashMap<u32, String>, tls: &TlsConfig) -> (QuicTransport, Inbound) {
+        for _ in 0..100 {
+            let (transport, inbound) = QuicTransport::new(Endpoint::Replica(id), replicas.clone(), HashMap::new(), tls.clone(), Some(1024)).unwrap();
+            if transport.start(Arc::new(|_| false)).is_ok() {
+                return (transport, inbound);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("the port of replica {} was not released", id);
+    }
+
+    fn request(operation: String) -> Message {
+        Message::Request(RequestMsg { operation, time_stamp: 1, client_id: 0, sequence_id: 0, digest: String::new(), reply_address: None, client_key: None, client_signature: None })
+    }
+
+    fn heartbeat(time_stamp: u64) -> Message {
+        Message::Heartbeat(HeartbeatMsg { node_id: 0, view_id: 9999, time_stamp, signature: String::new() })
+    }
+
+    async fn receive(inbound: &mut Inbound) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), inbound.recv()).await
+            .expect("no message within 5 seconds")
+            .expect("the transport has stopped")
+    }
+
+    #[tokio::test]
+    async fn messages_arrive_on_their_lanes() {
+        let (replicas, tls) = replicas();
+        let (sender, _) = replica(0, &replicas, &tls);
+        let (receiver, mut inbound) = replica(1, &replicas, &tls);
+
+        // larger than the compression threshold, and than a QUIC packet
+        let operation = "x".repeat(1024 * 1024);
+        sender.send(Endpoint::Replica(1), request(operation.clone()));
+        sender.send(Endpoint::Replica(1), heartbeat(1));
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            received.push(receive(&mut inbound).await);
+        }
+        // the lanes are independent, so the heartbeat may overtake the request
+        assert!(received.iter().any(|msg| matches!(msg, Message::Request(msg) if msg.operation == operation)));
+        assert!(received.iter().any(|msg| matches!(msg, Message::Heartbeat(msg) if msg.time_stamp == 1)));
+        sender.stop();
+        receiver.stop();
+    }
+
+    #[tokio::test]
+    async fn a_restarted_peer_is_connected_to_again() {
+        let (replicas, tls) = replicas();
+        let (sender, _) = replica(0, &replicas, &tls);
+        let (receiver, mut inbound) = replica(1, &replicas, &tls);
+        sender.send(Endpoint::Replica(1), heartbeat(1));
+        assert!(matches!(receive(&mut inbound).await, Message::Heartbeat(msg) if msg.time_stamp == 1));
+
+        // the connection the sender had is closed with the endpoint of the old receiver, which releases its port
+        receiver.stop();
+        let (receiver, mut inbound) = restart(1, &replicas, &tls).await;
+        sender.send(Endpoint::Replica(1), heartbeat(2));
+        assert!(matches!(receive(&mut inbound).await, Message::Heartbeat(msg) if msg.time_stamp == 2));
+        sender.stop();
+        receiver.stop();
+    }
+
+    #[test]
+    fn ipv4_addresses_are_preferred() {
+        let addresses: Vec<SocketAddr> = vec!["[::1]:8000".parse().unwrap(), "127.0.0.1:8000".parse().unwrap()];
+        assert_eq!(prefer_ipv4(addresses.into_iter(), "localhost:8000").unwrap(), "127.0.0.1:8000".parse().unwrap());
+        assert!(prefer_ipv4(std::iter::empty(), "nowhere:8000").is_err());
+    }
+}
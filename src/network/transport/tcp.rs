@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::io;
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::consensus::message::Message;
+use crate::network::transport::peers::{Connection, PeerManager, PeerTransport, DELIVERY_TIMEOUT};
+use crate::network::transport::{own_address, peers_of, ClientAddresses, Endpoint, Inbound, PeerAddress, Tasks, Versions};
+
+// Larger frames are rejected, a corrupt length prefix must not make the node allocate gigabytes.
+// The other transports accept messages of the same size.
+pub(crate) const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+// Sent back by the receiver for every frame it has read
+const ACK: u8 = 1;
+// Sent by a connecting node before its first frame, followed by its protocol version, see `Framing::negotiate`.
+// It is larger than `MAX_FRAME_LEN`, so it cannot be mistaken for a length.
+const HELLO: u32 = 0x5042_4656; // "PBFV"
+// Flag in the answer to `HELLO` of a receiver accepting frames compressed with zstd
+const ACCEPTS_ZSTD: u8 = 1;
+// Set in the length of a frame whose body is compressed with zstd
+const ZSTD_FLAG: u32 = 1 << 31;
+pub(crate) const ZSTD_LEVEL: i32 = 3;
+
+// Raw TCP with length-prefixed frames: a u32 big-endian length followed by the bincode encoding of the `Message`.
+// The receiver acknowledges every frame with a single `ACK` byte on the same connection.
+// Every new connection starts with a handshake, in which both nodes announce their protocol version
+// and the receiver tells whether it accepts compressed frames (see `Framing`).
+// Nodes with a compression threshold then compress the larger frames with zstd.
+// Every node listens on its own address and opens one outgoing connection per peer,
+// so for small operations a message costs a few bytes of framing instead of HTTP headers and JSON.
+pub(crate) struct TcpTransport {
+    address: String, // url:port this node listens on
+    replicas: HashMap<u32, String>, // Node.id -> url:port
+    clients: ClientAddresses, // client id -> url:port
+    peers: PeerManager,
+    inbound_tx: UnboundedSender<Message>,
+    tasks: Tasks, // the listener and the connections it accepted
+    compress_above: Option<usize>, // frames larger than this many bytes are compressed, if the receiver accepts it
+}
+
+impl TcpTransport {
+    pub(crate) fn new(
+        endpoint: Endpoint,
+        replicas: HashMap<u32, String>,
+        clients: HashMap<u32, String>,
+        compress_above: Option<usize>,
+    ) -> io::Result<(Self, Inbound)> {
+        let address = own_address(endpoint, &replicas, &clients)?;
+        let (inbound_tx, inbound) = unbounded_channel();
+        let transport = Self {
+            address,
+            peers: PeerManager::new(peers_of(endpoint, &replicas, &clients)),
+            replicas,
+            clients: ClientAddresses::new(clients),
+            inbound_tx,
+            tasks: Tasks::default(),
+            compress_above,
+        };
+        Ok((transport, inbound))
+    }
+}
+
+impl PeerTransport for TcpTransport {
+    type Connection = StreamConnection<TcpStream>;
+
+    fn peers(&self) -> &PeerManager {
+        &self.peers
+    }
+
+    fn client_addresses(&self) -> &ClientAddresses {
+        &self.clients
+    }
+
+    fn connect(&self, peer: Endpoint) -> impl Fn() -> Self::Connection + '_ {
+        move || {
+            StreamConnection::new(self.clients.peer_address(&self.replicas, peer), self.compress_above, |address| {
+                Box::pin(async move {
+                    let stream = TcpStream::connect(&address).await?;
+                    stream.set_nodelay(true)?;
+                    Ok(stream)
+                })
+            })
+        }
+    }
+
+    fn listen(&self) -> io::Result<()> {
+        let listener = std::net::TcpListener::bind(&self.address)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let inbound_tx = self.inbound_tx.clone();
+        let tasks = self.tasks.clone();
+        self.tasks.spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let (reader, writer) = stream.into_split();
+                        tasks.spawn(receive(reader, writer, inbound_tx.clone()));
+                    }
+                    Err(e) => eprintln!(" -- Error accepting connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.tasks.abort();
+    }
+}
+
+// Reads the frames of an incoming connection (or QUIC stream) until the peer closes it, acknowledging each of them.
+// The peer must have announced a compatible protocol version before its first frame.
+pub(crate) async fn receive(
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+    inbound_tx: UnboundedSender<Message>,
+) {
+    receive_as(Versions::CURRENT, reader, writer, inbound_tx).await
+}
+
+// `receive` on a node of protocol `versions`
+async fn receive_as(
+    versions: Versions,
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    inbound_tx: UnboundedSender<Message>,
+) {
+    let mut has_announced = false; // whether the peer has announced versions compatible with this node
+    loop {
+        let answer = match read_frame(&mut reader).await {
+            Ok(Some(Incoming::Hello(peer))) => {
+                let negotiated = versions.negotiate(peer);
+                // answered either way, so that the peer can report the versions too
+                let answer = answer_hello(&mut writer, versions).await;
+                match negotiated {
+                    Ok(_) => has_announced = true,
+                    Err(e) => {
+                        eprintln!(" -- Rejected connection: {}", e);
+                        return;
+                    }
+                }
+                answer
+            }
+            Ok(Some(Incoming::Frame(frame))) => {
+                if !has_announced {
+                    eprintln!(" -- Rejected connection: the peer did not announce its protocol version, it is older than version 1");
+                    return;
+                }
+                match decode_frame(&frame) {
+                    Ok(msg) => {
+                        let _ = inbound_tx.send(msg); // the node is shutting down if nobody is receiving
+                        writer.write_u8(ACK).await
+                    }
+                    Err(e) => {
+                        eprintln!(" -- Error decoding frame: {}", e);
+                        return;
+                    }
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!(" -- Error reading frame: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = answer {
+            eprintln!(" -- Error acknowledging frame: {}", e);
+            return;
+        }
+    }
+}
+
+async fn answer_hello(writer: &mut (impl AsyncWrite + Unpin), versions: Versions) -> io::Result<()> {
+    writer.write_u16(versions.version).await?;
+    writer.write_u16(versions.min_version).await?;
+    writer.write_u8(ACCEPTS_ZSTD).await
+}
+
+enum Incoming {
+    Hello(Versions),
+    Frame(Vec<u8>), // decompressed
+}
+
+// Returns None once the peer has closed the connection.
+async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Incoming>> {
+    let len = match stream.read_u32().await {
+        Ok(HELLO) => {
+            let version = stream.read_u16().await?;
+            let min_version = stream.read_u16().await?;
+            return Ok(Some(Incoming::Hello(Versions { version, min_version })));
+        }
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let is_compressed = len & ZSTD_FLAG != 0;
+    let len = len & !ZSTD_FLAG;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+    }
+    let mut frame = vec![0; len as usize];
+    stream.read_exact(&mut frame).await?;
+    if is_compressed {
+        // bounded like uncompressed frames, a small frame must not decompress to gigabytes either
+        frame = zstd::bulk::decompress(&frame, MAX_FRAME_LEN as usize)?;
+    }
+    Ok(Some(Incoming::Frame(frame)))
+}
+
+// Every compatible version so far encodes messages the same way, so frames do not depend on the negotiated version.
+// A version that changes the encoding of a message passes the negotiated version (the older of the versions of
+// both nodes) in here, keeps encoding the message the old way for older peers, and decodes both.
+fn encode_frame(msg: &Message) -> io::Result<Vec<u8>> {
+    bincode::serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode_frame(frame: &[u8]) -> io::Result<Message> {
+    bincode::deserialize(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Sending side of the frames of one connection (or QUIC stream).
+// A new connection starts with the handshake:
+//   -> HELLO: u32 | version: u16 | min_version: u16
+//   <- version: u16 | min_version: u16 | flags: u8 (`ACCEPTS_ZSTD`)
+// after which either side drops the connection if the versions are not compatible (see `Versions::negotiate`),
+// and otherwise sends its frames (see `encode_frame`).
+// With a compression threshold, frames larger than the threshold are compressed if the receiver accepts them.
+pub(crate) struct Framing {
+    versions: Versions, // of this node
+    compress_above: Option<usize>,
+    compress: bool, // negotiated on the current connection
+}
+
+impl Framing {
+    pub(crate) fn new(compress_above: Option<usize>) -> Self {
+        Self { versions: Versions::CURRENT, compress_above, compress: false }
+    }
+
+    // Called on every new connection, before its first frame.
+    pub(crate) async fn negotiate(
+        &mut self,
+        reader: &mut (impl AsyncRead + Unpin),
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        self.compress = false;
+        writer.write_u32(HELLO).await?;
+        writer.write_u16(self.versions.version).await?;
+        writer.write_u16(self.versions.min_version).await?;
+        let version = match reader.read_u16().await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                // a node without versions takes `HELLO` for the length of a frame that is too large
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "the peer closed the connection on the handshake, it may be older than protocol version 1"));
+            }
+            version => version?,
+        };
+        let min_version = reader.read_u16().await?;
+        let flags = reader.read_u8().await?;
+        self.versions.negotiate(Versions { version, min_version })?;
+        self.compress = self.compress_above.is_some() && flags & ACCEPTS_ZSTD != 0;
+        Ok(())
+    }
+
+    // Writes one message and waits for the receiver to acknowledge it.
+    pub(crate) async fn write_message(
+        &self,
+        reader: &mut (impl AsyncRead + Unpin),
+        writer: &mut (impl AsyncWrite + Unpin),
+        msg: &Message,
+    ) -> io::Result<()> {
+        let frame = &encode_frame(msg)?[..];
+        let compressed = match self.compress_above {
+            Some(threshold) if self.compress && frame.len() > threshold => Some(zstd::bulk::compress(frame, ZSTD_LEVEL)?),
+            _ => None,
+        };
+        let compressed = compressed.filter(|compressed| compressed.len() < frame.len());
+        // the receiver would drop the connection, and again on every retransmission
+        let len = compressed.as_ref().map_or(frame.len(), |compressed| compressed.len());
+        if len > MAX_FRAME_LEN as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("frame of {} bytes is too large", len)));
+        }
+        match compressed {
+            Some(compressed) => {
+                writer.write_u32(compressed.len() as u32 | ZSTD_FLAG).await?;
+                writer.write_all(&compressed).await?;
+            }
+            None => {
+                writer.write_u32(frame.len() as u32).await?;
+                writer.write_all(frame).await?;
+            }
+        }
+        match reader.read_u8().await? {
+            ACK => Ok(()),
+            byte => Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected an acknowledgement, got {}", byte))),
+        }
+    }
+}
+
+// Connects on the first message, and again on the next one after an error or once the peer has moved.
+// Shared by `tcp` and `unix`, which only differ in the streams that `connect` opens.
+pub(crate) struct StreamConnection<S> {
+    address: PeerAddress, // of the peer
+    stream: Option<(String, S)>, // and the address it is connected to
+    framing: Framing,
+    connect: fn(String) -> BoxFuture<'static, io::Result<S>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> StreamConnection<S> {
+    pub(crate) fn new(
+        address: PeerAddress,
+        compress_above: Option<usize>,
+        connect: fn(String) -> BoxFuture<'static, io::Result<S>>,
+    ) -> Self {
+        Self { address, stream: None, framing: Framing::new(compress_above), connect }
+    }
+
+    async fn write_message(&mut self, msg: &Message) -> io::Result<()> {
+        let address = self.address.current()?;
+        if self.stream.as_ref().is_some_and(|(connected_to, _)| *connected_to != address) {
+            self.stream = None;
+        }
+        if self.stream.is_none() {
+            let mut stream = (self.connect)(address.clone()).await?;
+            let (mut reader, mut writer) = tokio::io::split(&mut stream);
+            self.framing.negotiate(&mut reader, &mut writer).await?;
+            self.stream = Some((address, stream));
+        }
+        let (_, stream) = self.stream.as_mut().unwrap();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        self.framing.write_message(&mut reader, &mut writer, msg).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection for StreamConnection<S> {
+    fn deliver<'a>(&'a mut self, msg: &'a Message) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let result = tokio::time::timeout(DELIVERY_TIMEOUT, self.write_message(msg)).await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "no acknowledgement")));
+            if result.is_err() {
+                self.stream = None; // reconnect on the next message
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+    use super::*;
+    use crate::consensus::message::{HeartbeatMsg, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+    fn heartbeat() -> Message {
+        Message::Heartbeat(HeartbeatMsg { node_id: 1, view_id: 9999, time_stamp: 1, signature: String::new() })
+    }
+
+    #[tokio::test]
+    async fn nodes_of_adjacent_versions_talk_to_each_other() {
+        // a newer node, which still accepts the current version, receives from a current one
+        let newer = Versions { version: PROTOCOL_VERSION + 1, min_version: PROTOCOL_VERSION };
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (inbound_tx, mut inbound) = unbounded_channel();
+        tokio::spawn(receive_as(newer, server_reader, server_writer, inbound_tx));
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let mut framing = Framing::new(None);
+        framing.negotiate(&mut reader, &mut writer).await.unwrap();
+        framing.write_message(&mut reader, &mut writer, &heartbeat()).await.unwrap();
+        assert!(matches!(inbound.recv().await, Some(Message::Heartbeat(msg)) if msg.node_id == 1));
+
+        // and the newer node sending to the current one
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (inbound_tx, mut inbound) = unbounded_channel();
+        tokio::spawn(receive(server_reader, server_writer, inbound_tx));
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let mut framing = Framing { versions: newer, ..Framing::new(None) };
+        framing.negotiate(&mut reader, &mut writer).await.unwrap();
+        framing.write_message(&mut reader, &mut writer, &heartbeat()).await.unwrap();
+        assert!(matches!(inbound.recv().await, Some(Message::Heartbeat(msg)) if msg.node_id == 1));
+    }
+
+    #[tokio::test]
+    async fn the_handshake_announces_the_versions_and_compression() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (inbound_tx, _inbound) = unbounded_channel();
+        tokio::spawn(receive(server_reader, server_writer, inbound_tx));
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        writer.write_u32(HELLO).await.unwrap();
+        writer.write_u16(PROTOCOL_VERSION).await.unwrap();
+        writer.write_u16(MIN_PROTOCOL_VERSION).await.unwrap();
+        assert_eq!(reader.read_u16().await.unwrap(), PROTOCOL_VERSION);
+        assert_eq!(reader.read_u16().await.unwrap(), MIN_PROTOCOL_VERSION);
+        assert_eq!(reader.read_u8().await.unwrap(), ACCEPTS_ZSTD);
+    }
+
+    #[tokio::test]
+    async fn frames_before_the_handshake_are_rejected() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (inbound_tx, mut inbound) = unbounded_channel();
+        tokio::spawn(receive(server_reader, server_writer, inbound_tx));
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let frame = encode_frame(&heartbeat()).unwrap();
+        writer.write_u32(frame.len() as u32).await.unwrap();
+        writer.write_all(&frame).await.unwrap();
+        // the receiver drops the connection without acknowledging the frame
+        assert_eq!(reader.read_u8().await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(inbound.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn frames_longer_than_the_limit_are_rejected() {
+        for len in [MAX_FRAME_LEN + 1, (MAX_FRAME_LEN + 1) | ZSTD_FLAG] {
+            let e = read_frame(&mut &len.to_be_bytes()[..]).await.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+        // the largest frame is read in full
+        let mut stream = MAX_FRAME_LEN.to_be_bytes().to_vec();
+        stream.resize(4 + MAX_FRAME_LEN as usize, 0);
+        assert!(matches!(read_frame(&mut &stream[..]).await, Ok(Some(Incoming::Frame(frame))) if frame.len() == MAX_FRAME_LEN as usize));
+    }
+
+    #[tokio::test]
+    async fn frames_above_the_threshold_are_compressed() {
+        let large = Message::Heartbeat(HeartbeatMsg { node_id: 1, view_id: 9999, time_stamp: 1, signature: "ab".repeat(4096) });
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (mut server_reader, mut server_writer) = tokio::io::split(server);
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let mut framing = Framing::new(Some(1024));
+        let receiver = tokio::spawn(async move {
+            assert!(matches!(read_frame(&mut server_reader).await, Ok(Some(Incoming::Hello(_)))));
+            answer_hello(&mut server_writer, Versions::CURRENT).await.unwrap();
+            let mut lens = Vec::new();
+            for _ in 0..2 {
+                let len = server_reader.read_u32().await.unwrap();
+                let mut body = vec![0; (len & !ZSTD_FLAG) as usize];
+                server_reader.read_exact(&mut body).await.unwrap();
+                if len & ZSTD_FLAG != 0 {
+                    body = zstd::bulk::decompress(&body, MAX_FRAME_LEN as usize).unwrap();
+                }
+                assert!(decode_frame(&body).is_ok());
+                server_writer.write_u8(ACK).await.unwrap();
+                lens.push(len);
+            }
+            lens
+        });
+        framing.negotiate(&mut reader, &mut writer).await.unwrap();
+        framing.write_message(&mut reader, &mut writer, &heartbeat()).await.unwrap();
+        framing.write_message(&mut reader, &mut writer, &large).await.unwrap();
+        let lens = receiver.await.unwrap();
+        assert_eq!(lens[0] & ZSTD_FLAG, 0);
+        assert_ne!(lens[1] & ZSTD_FLAG, 0);
+        assert!(lens[1] & !ZSTD_FLAG < 1024);
+    }
+
+    #[tokio::test]
+    async fn incompatible_versions_are_rejected() {
+        let newer = Versions { version: PROTOCOL_VERSION + 2, min_version: PROTOCOL_VERSION + 1 };
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (inbound_tx, _inbound) = unbounded_channel();
+        tokio::spawn(receive_as(newer, server_reader, server_writer, inbound_tx));
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let mut framing = Framing::new(None);
+        let e = framing.negotiate(&mut reader, &mut writer).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::consensus::message::Message;
+use crate::network::transport::peers::{PeerManager, PeerTransport};
+use crate::network::transport::tcp::{receive, StreamConnection};
+use crate::network::transport::{own_address, peers_of, ClientAddresses, Endpoint, Inbound, Tasks};
+
+// The frames of the tcp transport over Unix domain sockets, for clusters on a single host.
+// Addresses are socket paths instead of ports, so clusters with their own socket directories
+// can run side by side without port conflicts.
+pub(crate) struct UnixTransport {
+    path: PathBuf, // socket this node listens on
+    replicas: HashMap<u32, String>, // Node.id -> socket path
+    clients: ClientAddresses, // client id -> socket path
+    peers: PeerManager,
+    inbound_tx: UnboundedSender<Message>,
+    tasks: Tasks, // the listener and the connections it accepted
+    compress_above: Option<usize>, // see `Framing`
+}
+
+impl UnixTransport {
+    pub(crate) fn new(
+        endpoint: Endpoint,
+        replicas: HashMap<u32, String>,
+        clients: HashMap<u32, String>,
+        compress_above: Option<usize>,
+    ) -> io::Result<(Self, Inbound)> {
+        let path = own_address(endpoint, &replicas, &clients)?.into();
+        let (inbound_tx, inbound) = unbounded_channel();
+        let transport = Self {
+            path,
+            peers: PeerManager::new(peers_of(endpoint, &replicas, &clients)),
+            replicas,
+            clients: ClientAddresses::new(clients),
+            inbound_tx,
+            tasks: Tasks::default(),
+            compress_above,
+        };
+        Ok((transport, inbound))
+    }
+}
+
+impl PeerTransport for UnixTransport {
+    type Connection = StreamConnection<UnixStream>;
+
+    fn peers(&self) -> &PeerManager {
+        &self.peers
+    }
+
+    fn client_addresses(&self) -> &ClientAddresses {
+        &self.clients
+    }
+
+    fn connect(&self, peer: Endpoint) -> impl Fn() -> Self::Connection + '_ {
+        move || {
+            StreamConnection::new(self.clients.peer_address(&self.replicas, peer), self.compress_above, |path| {
+                Box::pin(async move { UnixStream::connect(&path).await })
+            })
+        }
+    }
+
+    fn listen(&self) -> io::Result<()> {
+        prepare_socket(&self.path)?;
+        let listener = UnixListener::bind(&self.path)?;
+        let inbound_tx = self.inbound_tx.clone();
+        let tasks = self.tasks.clone();
+        self.tasks.spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let (reader, writer) = stream.into_split();
+                        tasks.spawn(receive(reader, writer, inbound_tx.clone()));
+                    }
+                    Err(e) => eprintln!(" -- Error accepting connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.tasks.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Makes room for a socket at `path`, removing the socket a previous run may have left behind.
+// Only a socket nobody listens on is removed: any other file is left alone, and a live socket
+// means that another node is using the path, so this one fails to start with `AddrInUse`.
+pub(crate) fn prepare_socket(path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let metadata = match std::fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        metadata => metadata?,
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a socket, refusing to replace it", path.display())));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("another process listens on {}", path.display())));
+    }
+    std::fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pbft-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let dir = dir("stale-socket");
+        let path = dir.join("node0.sock");
+        drop(UnixListener::bind(&path).unwrap()); // the file stays behind, like after a crash
+        prepare_socket(&path).unwrap();
+        assert!(!path.exists());
+        UnixListener::bind(&path).unwrap();
+        // nothing to replace in a new directory
+        prepare_socket(&dir.join("sockets").join("node1.sock")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_delete_a_regular_file() {
+        let dir = dir("regular-file");
+        let path = dir.join("node0.sock");
+        std::fs::write(&path, "not a socket").unwrap();
+        assert_eq!(prepare_socket(&path).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_take_over_a_live_socket() {
+        let dir = dir("live-socket");
+        let path = dir.join("node0.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+        assert_eq!(prepare_socket(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,58 @@
-use crate::consensus::message::{RequestMsg, PrePrepareMsg};
+use std::collections::HashSet;
+use crate::consensus::crypto::{PublicKey, PublicKeyTable};
+use crate::consensus::message::{CommitCertificate, CommittedEntry, MsgType, NewKeyMsg, RequestMsg, PrePrepareMsg, VoteMsg};
 pub fn compute_digest(request_msg: &RequestMsg) -> String {
     use sha2::{Sha256, Digest};
-    let serialized_request = serde_json::to_string(request_msg).unwrap();
     let mut hasher = Sha256::new();
-    hasher.update(serialized_request);
+    hasher.update(request_msg.canonical_bytes());
     let result = hasher.finalize();
     hex::encode(result)
 }
 
+// Digest of a committed log: SHA-256 over the (v, n, d) of every entry, sorted by (v, n).
+// Replicas log pipelined instances in the order they commit, which differs between replicas,
+// so the digest must not depend on it.
+pub fn compute_state_digest(log: &[CommittedEntry]) -> String {
+    use sha2::{Sha256, Digest};
+    let mut hasher = Sha256::new();
+    let mut entries: Vec<&CommittedEntry> = log.iter().collect();
+    entries.sort_by_key(|entry| (entry.view_id, entry.sequence_id));
+    for entry in entries {
+        hasher.update(entry.view_id.to_be_bytes());
+        hasher.update(entry.sequence_id.to_be_bytes());
+        hasher.update((entry.digest.len() as u32).to_be_bytes());
+        hasher.update(entry.digest.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+// Installs the key announced by a recovering replica, if it is signed with its recovery key
+// and its epoch is a recovery slot of the replica (see `PublicKeyTable::refresh`).
+// A BLS key also needs a valid proof of possession, before it can take part in aggregate signatures.
+pub fn apply_new_key(public_keys: &mut PublicKeyTable, new_key_msg: &NewKeyMsg) -> bool {
+    if !public_keys.verify_recovery(new_key_msg.node_id, &new_key_msg.signing_bytes(), &new_key_msg.signature) {
+        return false;
+    }
+    match PublicKey::from_hex(&new_key_msg.public_key) {
+        Some(public_key) if public_key.verify_possession(&new_key_msg.proof_of_possession) => {
+            public_keys.refresh(new_key_msg.node_id, new_key_msg.epoch, public_key)
+        }
+        _ => false,
+    }
+}
+
+// The key a client that is not in the configuration has signed its request with, if the signature is valid.
+// Only such a request may register or move the reply address of the client, see `ClientAddresses`.
+pub fn verify_client_signature(request_msg: &RequestMsg) -> Option<&str> {
+    let (Some(client_key), Some(signature)) = (&request_msg.client_key, &request_msg.client_signature) else {
+        return None;
+    };
+    match PublicKey::from_hex(client_key) {
+        Some(public_key @ PublicKey::Ed25519(_)) if public_key.verify(&request_msg.canonical_bytes(), signature) => Some(client_key),
+        _ => None,
+    }
+}
+
 pub fn verify_msg(msg: &PrePrepareMsg, req_view_id: u32, req_digest: String) -> bool {
     let correct_digest = req_digest == msg.digest;
     let correct_view_id = req_view_id == msg.view_id;
@@ -17,14 +62,225 @@ pub fn verify_msg(msg: &PrePrepareMsg, req_view_id: u32, req_digest: String) ->
     correct_digest && correct_view_id
 }
 
-pub fn generate_fake_request_msg() -> RequestMsg {
-    let mut request_msg = RequestMsg {
-        operation: "fake".to_string(),
-        time_stamp: 0,
-        client_id: 0,
-        sequence_id: 0,
-        digest: "".to_string(),
-    };
-    request_msg.digest = compute_digest(&request_msg);
-    request_msg
-}
\ No newline at end of file
+// Client side check of a commit certificate attached to a reply.
+// The certificate is valid if it holds correctly signed commit votes of at least 2f+1 distinct replicas
+// for the same (v, n, d), and 'd' is the digest of the request the client has sent.
+pub fn verify_commit_certificate(certificate: &CommitCertificate, request_digest: &str, public_keys: &PublicKeyTable, f: usize) -> bool {
+    if certificate.digest != request_digest {
+        return false;
+    }
+    if let Some(aggregate) = &certificate.aggregate {
+        let signers = aggregate.signers.node_ids();
+        let commit = VoteMsg {
+            view_id: certificate.view_id,
+            sequence_id: certificate.sequence_id,
+            digest: certificate.digest.clone(),
+            node_id: 0, // not covered by the signature
+            msg_type: MsgType::CommitMsg,
+            signature: String::new(),
+        };
+        return signers.len() > 2 * f && public_keys.verify_aggregate(&signers, &commit.signing_bytes(), &aggregate.signature);
+    }
+    let mut signers = HashSet::new();
+    for commit in certificate.commits.iter() {
+        let matches_certificate = commit.msg_type == MsgType::CommitMsg
+            && commit.view_id == certificate.view_id
+            && commit.sequence_id == certificate.sequence_id
+            && commit.digest == certificate.digest;
+        if !matches_certificate || !public_keys.verify(commit.node_id, &commit.signing_bytes(), &commit.signature) {
+            return false;
+        }
+        signers.insert(commit.node_id);
+    }
+    signers.len() > 2 * f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::crypto::{aggregate_signatures, AuthScheme, KeyPair};
+    use crate::consensus::message::AggregateSignature;
+    use crate::network::recovery::RecoverySchedule;
+    use std::time::Duration;
+
+    const F: usize = 1;
+    const DIGEST: &str = "d";
+
+    fn keys(scheme: AuthScheme) -> (Vec<KeyPair>, PublicKeyTable) {
+        let key_pairs: Vec<KeyPair> = (0..3 * F + 1).map(|_| KeyPair::generate(scheme)).collect();
+        let mut public_keys = PublicKeyTable::default();
+        for (node_id, key_pair) in key_pairs.iter().enumerate() {
+            public_keys.insert(node_id as u32, key_pair.public_key());
+        }
+        (key_pairs, public_keys)
+    }
+
+    fn commit(key_pairs: &[KeyPair], node_id: u32) -> VoteMsg {
+        let mut commit = VoteMsg {
+            view_id: 0,
+            sequence_id: 1,
+            digest: DIGEST.to_string(),
+            node_id,
+            msg_type: MsgType::CommitMsg,
+            signature: String::new(),
+        };
+        commit.signature = key_pairs[node_id as usize].sign(&commit.signing_bytes());
+        commit
+    }
+
+    fn certificate(commits: Vec<VoteMsg>) -> CommitCertificate {
+        CommitCertificate { view_id: 0, sequence_id: 1, digest: DIGEST.to_string(), commits, aggregate: None }
+    }
+
+    fn aggregate_certificate(key_pairs: &[KeyPair], signers: Vec<u32>) -> CommitCertificate {
+        let commits: Vec<VoteMsg> = signers.iter().map(|node_id| commit(key_pairs, *node_id)).collect();
+        let signatures: Vec<&str> = commits.iter().map(|commit| commit.signature.as_str()).collect();
+        let signature = aggregate_signatures(&signatures).unwrap();
+        let signers = signers.into_iter().collect();
+        CommitCertificate { aggregate: Some(AggregateSignature { signers, signature }), ..certificate(vec![]) }
+    }
+
+    fn new_key_msg(signer: &KeyPair, node_id: u32, epoch: u64, new_key: &KeyPair) -> NewKeyMsg {
+        let mut new_key_msg = NewKeyMsg {
+            node_id,
+            epoch,
+            public_key: new_key.public_key().to_hex(),
+            signature: String::new(),
+            proof_of_possession: new_key.prove_possession(),
+        };
+        new_key_msg.signature = signer.sign(&new_key_msg.signing_bytes());
+        new_key_msg
+    }
+
+    #[test]
+    fn new_key_needs_the_recovery_key_and_a_started_slot() {
+        let (key_pairs, mut public_keys) = keys(AuthScheme::Ed25519);
+        let recovery_key = KeyPair::generate(AuthScheme::Ed25519);
+        public_keys.insert_recovery_key(1, recovery_key.public_key());
+        let schedule = RecoverySchedule { n: 4, period: Duration::from_secs(60) };
+        public_keys.set_recovery_schedule(schedule);
+        let (_, next) = schedule.next_recovery(1);
+        let epoch = next - 4; // the last slot of replica 1, groups of f = 1
+        let new_key = KeyPair::generate(AuthScheme::Ed25519);
+        // the current key of a replica cannot announce the next one, it may have leaked
+        assert!(!apply_new_key(&mut public_keys, &new_key_msg(&key_pairs[1], 1, epoch, &new_key)));
+        // nor can the recovery key announce a key for a slot that has not started, or that belongs to another group
+        assert!(!apply_new_key(&mut public_keys, &new_key_msg(&recovery_key, 1, next + 4, &new_key)));
+        assert!(!apply_new_key(&mut public_keys, &new_key_msg(&recovery_key, 1, epoch + 1, &new_key)));
+        assert!(apply_new_key(&mut public_keys, &new_key_msg(&recovery_key, 1, epoch, &new_key)));
+        // replayed
+        assert!(!apply_new_key(&mut public_keys, &new_key_msg(&recovery_key, 1, epoch, &new_key)));
+    }
+
+    #[test]
+    fn new_bls_key_needs_a_proof_of_possession() {
+        let (_, mut public_keys) = keys(AuthScheme::Bls);
+        let recovery_key = KeyPair::generate(AuthScheme::Ed25519);
+        public_keys.insert_recovery_key(1, recovery_key.public_key());
+        let schedule = RecoverySchedule { n: 4, period: Duration::from_secs(60) };
+        public_keys.set_recovery_schedule(schedule);
+        let epoch = schedule.next_recovery(1).1 - 4;
+        let new_key = KeyPair::generate(AuthScheme::Bls);
+        let mut wrong_proof = new_key_msg(&recovery_key, 1, epoch, &new_key);
+        wrong_proof.proof_of_possession = KeyPair::generate(AuthScheme::Bls).prove_possession();
+        assert!(!apply_new_key(&mut public_keys, &wrong_proof));
+        assert!(apply_new_key(&mut public_keys, &new_key_msg(&recovery_key, 1, epoch, &new_key)));
+    }
+
+    #[test]
+    fn state_digest_does_not_depend_on_the_commit_order() {
+        let entry = |sequence_id| CommittedEntry { view_id: 0, sequence_id, digest: format!("d{}", sequence_id) };
+        let log = vec![entry(1), entry(2), entry(3)];
+        assert_eq!(compute_state_digest(&log), compute_state_digest(&[entry(2), entry(1), entry(3)]));
+        assert_ne!(compute_state_digest(&log), compute_state_digest(&[entry(1), entry(2)]));
+    }
+
+    #[test]
+    fn accepts_2f_plus_1_commits() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Ed25519);
+        let certificate = certificate((0..3).map(|node_id| commit(&key_pairs, node_id)).collect());
+        assert!(verify_commit_certificate(&certificate, DIGEST, &public_keys, F));
+    }
+
+    #[test]
+    fn rejects_wrong_digest() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Ed25519);
+        let certificate = certificate((0..3).map(|node_id| commit(&key_pairs, node_id)).collect());
+        assert!(!verify_commit_certificate(&certificate, "other", &public_keys, F));
+    }
+
+    #[test]
+    fn rejects_duplicate_signers() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Ed25519);
+        let certificate = certificate(vec![commit(&key_pairs, 0), commit(&key_pairs, 1), commit(&key_pairs, 1)]);
+        assert!(!verify_commit_certificate(&certificate, DIGEST, &public_keys, F));
+    }
+
+    #[test]
+    fn rejects_too_few_signers() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Ed25519);
+        let certificate = certificate(vec![commit(&key_pairs, 0), commit(&key_pairs, 1)]);
+        assert!(!verify_commit_certificate(&certificate, DIGEST, &public_keys, F));
+    }
+
+    #[test]
+    fn rejects_forged_signature() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Ed25519);
+        let mut forged = commit(&key_pairs, 2);
+        forged.signature = key_pairs[0].sign(&forged.signing_bytes());
+        let certificate = certificate(vec![commit(&key_pairs, 0), commit(&key_pairs, 1), forged]);
+        assert!(!verify_commit_certificate(&certificate, DIGEST, &public_keys, F));
+    }
+
+    #[test]
+    fn accepts_aggregate() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Bls);
+        let certificate = aggregate_certificate(&key_pairs, vec![0, 1, 3]);
+        assert!(verify_commit_certificate(&certificate, DIGEST, &public_keys, F));
+    }
+
+    #[test]
+    fn rejects_aggregate_with_duplicate_or_too_few_signers() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Bls);
+        // a node is in the bitmap once, whatever it has signed: 2 signers for 3 signatures
+        assert!(!verify_commit_certificate(&aggregate_certificate(&key_pairs, vec![0, 1, 1]), DIGEST, &public_keys, F));
+        assert!(!verify_commit_certificate(&aggregate_certificate(&key_pairs, vec![0, 1]), DIGEST, &public_keys, F));
+    }
+
+    #[test]
+    fn rejects_bad_aggregate() {
+        let (key_pairs, public_keys) = keys(AuthScheme::Bls);
+        // the signature of 0, 1 and 2 claimed for 0, 1 and 3
+        let mut certificate = aggregate_certificate(&key_pairs, vec![0, 1, 2]);
+        certificate.aggregate.as_mut().unwrap().signers = [0, 1, 3].into_iter().collect();
+        assert!(!verify_commit_certificate(&certificate, DIGEST, &public_keys, F));
+        certificate.aggregate.as_mut().unwrap().signature = "not hex".to_string();
+        assert!(!verify_commit_certificate(&certificate, DIGEST, &public_keys, F));
+    }
+
+    #[test]
+    fn reply_addresses_are_only_taken_from_requests_the_client_has_signed() {
+        let key_pair = KeyPair::generate(AuthScheme::Ed25519);
+        let mut request_msg = RequestMsg {
+            operation: "put x 1".to_string(),
+            time_stamp: 1,
+            client_id: 7,
+            sequence_id: 0,
+            digest: String::new(),
+            reply_address: Some("127.0.0.1:9107".to_string()),
+            client_key: Some(key_pair.public_key().to_hex()),
+            client_signature: None,
+        };
+        assert_eq!(verify_client_signature(&request_msg), None);
+        request_msg.client_signature = Some(key_pair.sign(&request_msg.canonical_bytes()));
+        assert_eq!(verify_client_signature(&request_msg), Some(key_pair.public_key().to_hex().as_str()));
+        // e.g. a faulty primary redirecting the replies
+        request_msg.reply_address = Some("127.0.0.1:6666".to_string());
+        assert_eq!(verify_client_signature(&request_msg), None);
+        // only ed25519 keys
+        let bls_key_pair = KeyPair::generate(AuthScheme::Bls);
+        request_msg.client_key = Some(bls_key_pair.public_key().to_hex());
+        request_msg.client_signature = Some(bls_key_pair.sign(&request_msg.canonical_bytes()));
+        assert_eq!(verify_client_signature(&request_msg), None);
+    }
+}